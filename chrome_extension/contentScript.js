@@ -5,6 +5,8 @@ const SERVER_IP = '';
 const CACHE_NUM = 5;
 const DEBUG = false;
 const MAX_NUM_CONNECTION_ATTEMPTS = 3;
+const POLL_INTERVAL_MS = 1000;
+const MAX_NUM_POLLS = 120;
 
 //Global Vars
 let cache = {};
@@ -18,6 +20,27 @@ let section_monitor = document.getElementsByClassName('post-images')[0]; //When
 let toast = document.createElement("div");
 
 //Functions
+//Returns the random id this browser uses to send feedback, creating it on first use. It identifies the user's corrections and nothing else.
+function get_user_id() {
+    let user_id = localStorage.getItem('political_post_blocker_user');
+    if (!user_id) {
+        user_id = Array.from(crypto.getRandomValues(new Uint8Array(16)), b => b.toString(16).padStart(2, '0')).join('');
+        localStorage.setItem('political_post_blocker_user', user_id);
+    }
+    return user_id;
+}
+
+//Returns the api key set with localStorage.setItem('political_post_blocker_api_key', ...), if any. It selects the user's filter profile on the server, and is required to send feedback.
+function get_api_key() {
+    return localStorage.getItem('political_post_blocker_api_key');
+}
+
+//Returns the headers sent with post checks and feedback.
+function profile_headers() {
+    let api_key = get_api_key();
+    return api_key ? { 'X-Api-Key': api_key } : {};
+}
+
 //Collects the current id of the page.
 let get_id = () => document.getElementsByClassName("selected base list")[0].getAttribute("href").split("/").pop(); //This can be undefined for some reason?
 
@@ -67,12 +90,32 @@ function show_toast(message, type) {
     }
 
     x.innerText = message;
+    x.onclick = null;
     x.className = "show";
     setTimeout(function(){ x.className = x.className.replace("show", ""); }, 3000);
 }
 
-//Makes a request to the server for 
-async function request_post(post_id) {
+//Waits for the given number of milliseconds.
+let sleep = (ms) => new Promise(resolve => setTimeout(resolve, ms));
+
+//Polls a queued job on the server until it has finished, then returns the processed post.
+async function poll_job(job_id) {
+    for (let i = 0; i < MAX_NUM_POLLS; i++) {
+        await sleep(POLL_INTERVAL_MS);
+        let response = await axios({
+            method: 'get',
+            url: `${SERVER_IP}/check_post_priority/${job_id}?user=${get_user_id()}`,
+            headers: profile_headers()
+        });
+        if (response.status === 202) continue;
+        if (response.status !== 200) throw new Error('Error! Server returned non-200 status.');
+        return response.data;
+    }
+    throw new Error(`Error! Job ${job_id} did not finish in time.`);
+}
+
+//Sends a post to the server with the given priority ('visible', 'prefetch' or 'background').
+async function send_post(post_id, priority) {
     return await axios({
         method: 'post',
         url: `${SERVER_IP}/check_post_priority`,
@@ -80,15 +123,65 @@ async function request_post(post_id) {
             id: post_id,
             images: [],
             post_url: `https://imgur.com/gallery/${post_id}`,
-            datetime: Date.now().toString()
+            datetime: Date.now().toString(),
+            priority: priority,
+            user: get_user_id()
         }),
         headers: {
-            'Content-Type': 'application/json'
+            'Content-Type': 'application/json',
+            ...profile_headers()
         }
-    }).then(response => {
-        if (response.status !== 200) throw new Error('Error! Server returned non-200 status.');
-        return response.data;
-    })
+    });
+}
+
+//Tells the server a post (or one of its images) was judged wrongly. The label is 'safe' or 'unsafe'.
+async function send_feedback(post_id, label, image_id) {
+    return await axios({
+        method: 'post',
+        url: `${SERVER_IP}/feedback`,
+        data: JSON.stringify({
+            post_id: post_id,
+            image_id: image_id,
+            user: get_user_id(),
+            label: label
+        }),
+        headers: {
+            'Content-Type': 'application/json',
+            ...profile_headers()
+        }
+    });
+}
+
+//Makes a request to the server for a post, waiting for it to be processed if it was queued.
+async function request_post(post_id, priority) {
+    let response = await send_post(post_id, priority);
+    if (response.status !== 200 && response.status !== 202) throw new Error('Error! Server returned non-200 status.');
+    let job = (response.status === 202) ? await poll_job(response.data.job_id) : response.data;
+    if (job.status !== 'done') throw new Error(`Error! Server failed to process post: ${job.error}`);
+    return job.post;
+}
+
+//Describes where a reason was found, such as 'title' or 'image 3 OCR'.
+function describe_source(source, image_index) {
+    switch (source) {
+        case 'title': return 'title';
+        case 'description': return 'description';
+        case 'image_description': return `image ${image_index + 1} description`;
+        case 'ocr_text': return `image ${image_index + 1} OCR`;
+        case 'post_id': return 'denylisted post id';
+        case 'account': return 'denylisted account';
+        case 'tag': return 'denylisted tags';
+        default: return source;
+    }
+}
+
+//Explains why the server hid a post, using the first reason it reported.
+function describe_reasons(post) {
+    let reasons = (post.reasons || []).map(reason => [reason, undefined]);
+    (post.images || []).forEach((image, i) => (image.reasons || []).forEach(reason => reasons.push([reason, i])));
+    if (reasons.length === 0) return 'Skipped Post!';
+    let [reason, image_index] = reasons[0];
+    return `Skipped Post! Hidden because: '${reason.term}' in ${describe_source(reason.source, image_index)}`;
 }
 
 //Scans ahead the next 5 images and buffers them in the cache as the user scrolls through.
@@ -103,7 +196,7 @@ async function get_ahead() {
 
     for(let i = 1; i <= CACHE_NUM; i++) {
         let id = gallery[current_index + i].getAttribute("href").split("/").pop();
-        if (!cache.hasOwnProperty(id)) cache[id] = create_custom_promise(request_post(id));
+        if (!cache.hasOwnProperty(id)) cache[id] = create_custom_promise(request_post(id, 'prefetch'));
     }
 }
 
@@ -113,13 +206,28 @@ async function scan_page_core(retry_num) {
         check_images(); //Hide any relevant images.
         //Get current element from upcoming Posts
         let id = get_id();
-        let post_data = (cache.hasOwnProperty(id)) ? cache[id] : create_custom_promise(request_post(id));
+        let post_data = (cache.hasOwnProperty(id)) ? cache[id] : create_custom_promise(request_post(id, 'visible'));
         if (!cache.hasOwnProperty(id)) cache[id] = post_data;
+        //A prefetched post which is still queued is now being viewed, so ask the server to move it up the queue.
+        else if (post_data.is_pending()) send_post(id, 'visible').catch(() => {});
 
         await post_data.then(result => {
             if (result.unrecoverable) {
                 trigger_next();
-                show_toast("Skipped Post!");
+                //Without an api key the server turns feedback away, so only offer it when one is set.
+                if (!get_api_key()) {
+                    show_toast(describe_reasons(result));
+                    toast.onclick = null;
+                    return;
+                }
+                show_toast(`${describe_reasons(result)} (click if this was wrong)`);
+                //Report the skipped post as safe, so it is shown the next time.
+                toast.onclick = () => {
+                    delete cache[id];
+                    send_feedback(id, 'safe')
+                        .then(() => show_toast('Thanks! This post will not be skipped again.'))
+                        .catch(() => show_toast('Failed to send feedback', 'error'));
+                };
             }
         }).catch(err => {
             if (DEBUG) show_toast("Error contacting server", 'error');
@@ -0,0 +1,142 @@
+//!This benchmark checks the filter against a large generated rule set and long generated OCR text, so the cost of growing the word list can be measured.
+//!Run it with `cargo bench --bench filter -- --help` to see its options.
+
+//Imports
+use std::time::Instant;
+use anyhow::Result;
+use structopt::StructOpt;
+use scraper_app::filter::Filter;
+use scraper_app::filter_rules::RuleSet;
+
+///The options of a benchmark run.
+#[derive(StructOpt)]
+struct Options {
+    ///The number of generated rules.
+    #[structopt(long, default_value = "10000")]
+    rules: usize,
+    ///The number of words of generated text.
+    #[structopt(long, default_value = "100000")]
+    words: usize,
+    ///How many times the text is checked.
+    #[structopt(long, default_value = "10")]
+    iterations: usize,
+    ///Also time the word by word matcher, once.
+    #[structopt(long)]
+    baseline: bool,
+    ///Passed by `cargo bench`, and ignored.
+    #[structopt(long = "bench", hidden = true)]
+    _bench: bool,
+}
+
+///Syllables used to build made up words, so the generated rules and text share a vocabulary without needing a dictionary.
+const SYLLABLES: &[&str] = &["ka", "lo", "mi", "ne", "ru", "ta", "vo", "shi", "pre", "dent", "tion", "el", "ect", "gor", "an", "is", "ber", "qua", "mon", "sty"];
+///How many rules go in each generated category.
+const RULES_PER_CATEGORY: usize = 500;
+///One in this many words of the generated text is taken from the rules.
+const MATCH_EVERY: usize = 250;
+///The options of successive generated rules. Like the shipped rule file, most terms use `~leet ~unicode`, and a few use other options.
+const OPTION_MIX: &[&str] = &["~leet ~unicode", "~leet ~unicode", "", "~leet ~unicode", "~leet ~unicode ~stem", "~stem", "~leet ~unicode", "~leet ~unicode ~spaced", "", "~unicode"];
+///Letters written as digits when a term is inserted into the text in leetspeak.
+const LEET: &[(char, char)] = &[('a', '4'), ('e', '3'), ('o', '0'), ('i', '1')];
+
+///A small xorshift generator, so runs are repeatable without pulling in a random number crate.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    ///Makes up a word of the given number of syllables.
+    fn word(&mut self, syllables: usize) -> String {
+        (0..syllables).map(|_| SYLLABLES[self.below(SYLLABLES.len())]).collect()
+    }
+}
+
+///Generates a rule file with the given number of terms. One in five terms is a two word phrase, and each takes the next options of `OPTION_MIX`.
+///Rule words are four syllables long and the rest of the text is shorter, so the text only matches where a term was inserted.
+fn generate_rules(rng: &mut Rng, count: usize) -> (String, Vec<String>) {
+    let mut contents = String::new();
+    let mut terms = vec![];
+    for i in 0..count {
+        if i % RULES_PER_CATEGORY == 0 {
+            contents.push_str(&format!("[generated_{} threshold=2]\n", i / RULES_PER_CATEGORY));
+        }
+        let term = if i % 5 == 0 { format!("{} {}", rng.word(4), rng.word(4)) } else { rng.word(4) };
+        contents.push_str(&format!("{} {}\n", term, OPTION_MIX[i % OPTION_MIX.len()]));
+        terms.push(term);
+    }
+    (contents, terms)
+}
+
+///Obfuscates an inserted term the way posts dodge filters: nearly half are written in leetspeak, and one in twenty has a letter masked with `*`.
+fn obfuscate(rng: &mut Rng, term: &str) -> String {
+    match rng.below(20) {
+        //Terms are at least eight letters long, so the second letter is always inside a word.
+        0 => term.chars().enumerate().map(|(i, c)| if i == 1 { '*' } else { c }).collect(),
+        1..=9 => term.chars().map(|c| LEET.iter().find(|(letter, _)| *letter == c).map_or(c, |(_, digit)| *digit)).collect(),
+        _ => term.to_owned(),
+    }
+}
+
+///Generates OCR-like text: made up words with punctuation and line breaks, and an occasional rule term, which is often obfuscated.
+fn generate_text(rng: &mut Rng, terms: &[String], words: usize) -> String {
+    let mut text = String::new();
+    for i in 1..=words {
+        if rng.below(MATCH_EVERY) == 0 {
+            let term = &terms[rng.below(terms.len())];
+            text.push_str(&obfuscate(rng, term));
+        } else {
+            let syllables = 1 + rng.below(3);
+            text.push_str(&rng.word(syllables));
+        }
+        text.push_str(match (i % 12, i % 40) {
+            (_, 0) => ".\n",
+            (0, _) => ", ",
+            _ => " ",
+        });
+    }
+    text
+}
+
+///Times `iterations` checks of the text, printing the throughput.
+fn time_checks(name: &str, filter: &Filter, text: &str, iterations: usize) {
+    let mut matches = 0;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        matches = filter.check(text).matches.len();
+    }
+    let elapsed = start.elapsed().as_secs_f64() / iterations as f64;
+    println!(
+        "{}: {:.2}ms per check, {:.1} MB/s, {} matches",
+        name,
+        elapsed * 1000.0,
+        text.len() as f64 / elapsed / 1_000_000.0,
+        matches,
+    );
+}
+
+///Runs the benchmark. The word by word baseline is only run when asked for, as it is very slow with large rule sets.
+fn main() -> Result<()> {
+    let Options { rules, words, iterations, baseline, .. } = Options::from_args();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let (contents, terms) = generate_rules(&mut rng, rules.max(1));
+    let text = generate_text(&mut rng, &terms, words);
+    println!("Benchmarking {} rules against {} words ({} bytes) of text, {} iterations.", terms.len(), words, text.len(), iterations);
+
+    let rule_set = RuleSet::parse(&contents)?;
+    let start = Instant::now();
+    let filter = Filter::compile(rule_set.clone());
+    println!("Compiled the rules in {:.2}ms.", start.elapsed().as_secs_f64() * 1000.0);
+    time_checks("automaton", &filter, &text, iterations.max(1));
+
+    if baseline {
+        time_checks("word by word", &Filter::uncompiled(rule_set), &text, 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//!This module holds the allowlist and denylist. A post with a listed id, from a listed account or with a listed tag skips the filter entirely, and is always shown or always hidden.
+
+//Imports
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use anyhow::{Context, Result, bail};
+use crate::mongo_db_interface::{Post, Reason, ReasonSource};
+
+///The category named in the reasons of a denied post.
+const DENYLIST_CATEGORY: &str = "denylist";
+
+///What an entry is matched against.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    ///An imgur tag. Tags are only known for posts the imgur api returns them for.
+    Tag,
+    ///The account which uploaded the post.
+    Account,
+    ///A single post id.
+    Post,
+}
+
+///Whether matching posts are always shown or always hidden.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListAction {
+    Allow,
+    Deny,
+}
+
+///A single allowlist or denylist entry.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ListEntry {
+    pub kind: EntryKind,
+    ///Accounts and tags are compared case-insensitively, post ids exactly.
+    pub value: String,
+    pub action: ListAction,
+    ///Why the entry was added, for whoever reads the list next.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+///The layout of the list file.
+#[derive(Deserialize, Serialize, Default)]
+struct ListFile {
+    entries: Vec<ListEntry>,
+}
+
+///The lists shared between the workers and the admin endpoints. Every edit is written straight back to the list file.
+#[derive(Clone)]
+pub struct AccessLists {
+    entries: Arc<RwLock<Vec<ListEntry>>>,
+    ///Held for the whole of an edit, so edits are saved one at a time and in order without blocking readers.
+    editing: Arc<Mutex<()>>,
+    path: PathBuf,
+}
+
+///Normalises a value for comparison. Imgur post ids are case-sensitive, accounts and tags are not.
+fn normalise(kind: EntryKind, value: &str) -> String {
+    match kind {
+        EntryKind::Post => value.trim().to_owned(),
+        EntryKind::Account | EntryKind::Tag => value.trim().to_lowercase(),
+    }
+}
+
+impl ListEntry {
+    ///The reason recorded on a post hidden by this entry.
+    pub fn reason(&self) -> Reason {
+        let source = match self.kind {
+            EntryKind::Post => ReasonSource::PostId,
+            EntryKind::Account => ReasonSource::Account,
+            EntryKind::Tag => ReasonSource::Tag,
+        };
+        Reason {
+            source,
+            category: DENYLIST_CATEGORY.to_owned(),
+            term: self.value.clone(),
+            start: 0,
+            end: self.value.chars().count(),
+        }
+    }
+}
+
+impl AccessLists {
+    ///Loads the lists from a file. A missing file is treated as empty lists, it is created on the first edit.
+    pub fn load(path: &Path) -> Result<AccessLists> {
+        let file: ListFile = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid access list file {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ListFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read access list file {}", path.display())),
+        };
+        let entries = file.entries.into_iter()
+            .map(|entry| ListEntry { value: normalise(entry.kind, &entry.value), ..entry })
+            .collect();
+        Ok(AccessLists { entries: Arc::new(RwLock::new(entries)), editing: Arc::new(Mutex::new(())), path: path.to_path_buf() })
+    }
+    ///Returns every entry.
+    pub fn entries(&self) -> Vec<ListEntry> {
+        self.entries.read().unwrap().clone()
+    }
+    ///Finds the entry deciding a post, if any.
+    ///The most specific entry wins: a post id over an account, and an account over a tag. Between tags of the same post, deny wins.
+    pub fn decide(&self, post_id: &str, account: Option<&str>, tags: &[String]) -> Option<ListEntry> {
+        let account = account.map(|account| normalise(EntryKind::Account, account));
+        let tags: Vec<String> = tags.iter().map(|tag| normalise(EntryKind::Tag, tag)).collect();
+        let entries = self.entries.read().unwrap();
+        entries.iter()
+            .filter(|entry| match entry.kind {
+                EntryKind::Post => entry.value == post_id,
+                EntryKind::Account => account.as_deref() == Some(entry.value.as_str()),
+                EntryKind::Tag => tags.contains(&entry.value),
+            })
+            .max_by_key(|entry| (entry.kind, entry.action == ListAction::Deny))
+            .cloned()
+    }
+    ///Returns true if the list decision stored with a post still matches the current lists.
+    pub fn is_current(&self, post: &Post) -> bool {
+        self.decide(&post.id, post.account.as_deref(), &post.tags).map(|entry| entry.action) == post.listed
+    }
+    ///Adds an entry, replacing any entry for the same value, and saves the lists.
+    pub async fn add(&self, entry: ListEntry) -> Result<()> {
+        let entry = ListEntry { value: normalise(entry.kind, &entry.value), ..entry };
+        if entry.value.is_empty() {
+            bail!("value must not be empty");
+        }
+        let _editing = self.editing.lock().await;
+        let mut updated = self.entries();
+        updated.retain(|e| !(e.kind == entry.kind && e.value == entry.value));
+        updated.push(entry);
+        self.save(updated).await
+    }
+    ///Removes the entry for a value, returning whether it existed, and saves the lists.
+    pub async fn remove(&self, kind: EntryKind, value: &str) -> Result<bool> {
+        let value = normalise(kind, value);
+        let _editing = self.editing.lock().await;
+        let mut updated = self.entries();
+        let count = updated.len();
+        updated.retain(|e| !(e.kind == kind && e.value == value));
+        if updated.len() == count {
+            return Ok(false);
+        }
+        self.save(updated).await?;
+        Ok(true)
+    }
+    ///Writes the lists to a temporary file on a blocking thread and renames it over the list file, so a crash never leaves a half written file. The lists in memory are only replaced once the file is saved.
+    async fn save(&self, entries: Vec<ListEntry>) -> Result<()> {
+        let contents = serde_json::to_string_pretty(&ListFile { entries: entries.clone() })?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let temp = path.with_extension("tmp");
+            fs::write(&temp, contents)
+                .with_context(|| format!("Failed to write access list file {}", temp.display()))?;
+            fs::rename(&temp, &path)
+                .with_context(|| format!("Failed to replace access list file {}", path.display()))?;
+            Ok(())
+        }).await??;
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Returns a list file path no other test uses, removing anything left over from an earlier run.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("access_lists_{}_{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn entry(kind: EntryKind, value: &str, action: ListAction) -> ListEntry {
+        ListEntry { kind, value: value.to_owned(), action, note: None }
+    }
+
+    async fn lists(name: &str, entries: Vec<ListEntry>) -> AccessLists {
+        let lists = AccessLists::load(&temp_path(name)).unwrap();
+        for entry in entries {
+            lists.add(entry).await.unwrap();
+        }
+        lists
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn the_most_specific_entry_decides() {
+        let lists = lists("specific", vec![
+            entry(EntryKind::Tag, "politics", ListAction::Deny),
+            entry(EntryKind::Account, "Trusted", ListAction::Allow),
+            entry(EntryKind::Post, "abc123", ListAction::Deny),
+        ]).await;
+        let decide = |post_id: &str, account: Option<&str>| lists.decide(post_id, account, &tags(&["Politics"])).map(|entry| entry.action);
+        assert_eq!(decide("other", None), Some(ListAction::Deny));
+        //An allowed account overrides a denied tag, and a denied post id overrides both.
+        assert_eq!(decide("other", Some("trusted")), Some(ListAction::Allow));
+        assert_eq!(decide("abc123", Some("trusted")), Some(ListAction::Deny));
+        assert_eq!(lists.decide("other", Some("someone"), &tags(&["cats"])), None);
+    }
+
+    #[tokio::test]
+    async fn deny_wins_between_tags() {
+        let lists = lists("tags", vec![
+            entry(EntryKind::Tag, "cats", ListAction::Allow),
+            entry(EntryKind::Tag, "election", ListAction::Deny),
+        ]).await;
+        let decided = lists.decide("post", None, &tags(&["cats", "election"])).unwrap();
+        assert_eq!(decided.action, ListAction::Deny);
+        assert_eq!(decided.reason().term, "election");
+        assert_eq!(lists.decide("post", None, &tags(&["cats"])).unwrap().action, ListAction::Allow);
+    }
+
+    #[tokio::test]
+    async fn post_ids_are_case_sensitive() {
+        let lists = lists("case", vec![entry(EntryKind::Post, "AbC", ListAction::Deny)]).await;
+        assert!(lists.decide("AbC", None, &[]).is_some());
+        assert!(lists.decide("abc", None, &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn edits_are_saved_and_replace_the_same_value() {
+        let path = temp_path("saved");
+        let lists = AccessLists::load(&path).unwrap();
+        lists.add(entry(EntryKind::Account, "Someone", ListAction::Allow)).await.unwrap();
+        lists.add(entry(EntryKind::Account, "someone ", ListAction::Deny)).await.unwrap();
+        lists.add(entry(EntryKind::Tag, "cats", ListAction::Allow)).await.unwrap();
+        assert!(lists.add(entry(EntryKind::Tag, " ", ListAction::Allow)).await.is_err());
+        assert!(lists.remove(EntryKind::Tag, "Cats").await.unwrap());
+        assert!(!lists.remove(EntryKind::Tag, "cats").await.unwrap());
+        let loaded = AccessLists::load(&path).unwrap();
+        assert_eq!(loaded.entries(), vec![entry(EntryKind::Account, "someone", ListAction::Deny)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//!This module holds text classifiers, which learn what political posts look like from labelled examples rather than relying on every term being listed in the rule file.
+
+//Imports
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+use serde::{Serialize, Deserialize};
+use anyhow::{Context, Result, bail};
+use crate::feedback::{self, Label, LabelledExample};
+use crate::filter_normalise::stem;
+use crate::filter_rules::{tokenize, version_of};
+use crate::post_store::Store;
+
+///How many posts are read from the database at a time when gathering training data.
+const BATCH_SIZE: usize = 100;
+///How many of the most telling words are reported with a classification.
+const REPORTED_TERMS: usize = 3;
+///Laplace smoothing added to every word count, so words never seen in one class don't rule it out entirely.
+const SMOOTHING: f64 = 1.0;
+
+///The result of classifying a piece of text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Classification {
+    ///The probability the text is political, from 0 to 1.
+    pub probability: f32,
+    ///The words of the text which pointed most strongly towards political, most telling first.
+    pub terms: Vec<String>,
+}
+
+///A trained model which judges text as a whole, used in place of or alongside the rule file.
+pub trait Classifier: Send + Sync {
+    ///Identifies the trained model, so posts judged by an older model can be found like posts judged by older rules.
+    fn version(&self) -> &str;
+    ///Scores a piece of text. Returns None for text with no words the model knows, as there is nothing to judge it by.
+    fn classify(&self, text: &str) -> Option<Classification>;
+}
+
+///A multinomial Naive Bayes model over the words of the text.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct NaiveBayes {
+    ///The number of training examples labelled safe and unsafe.
+    examples: [u64; 2],
+    ///The total number of words in the safe and unsafe examples.
+    totals: [u64; 2],
+    ///How often each word appeared in safe and unsafe examples.
+    words: HashMap<String, [u64; 2]>,
+    #[serde(skip)]
+    version: String,
+}
+
+///The layout of a model file. Each kind of model is tagged, so other classifiers can be added without breaking existing files.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ModelFile {
+    NaiveBayes(NaiveBayes),
+}
+
+///Splits text into the words a model is trained on: lowercased, with accents removed and plurals reduced, ignoring single characters and numbers.
+fn features(text: &str) -> Vec<String> {
+    tokenize(text).into_iter()
+        .filter(|token| token.folded.chars().count() > 1 && !token.folded.chars().all(|c| c.is_numeric()))
+        .map(|token| stem(&token.folded))
+        .collect()
+}
+
+///The index of a label in the count arrays.
+fn class(label: Label) -> usize {
+    match label {
+        Label::Safe => 0,
+        Label::Unsafe => 1,
+    }
+}
+
+///Splits an example into the texts the filter judges separately: its title, its description and the OCR text of each image.
+///A label for a single image only covers that image's text, not the title and description of its post. Empty texts are left out.
+pub fn example_texts(example: &LabelledExample) -> Vec<&str> {
+    let mut texts = vec![];
+    if example.image_id.is_none() {
+        texts.push(example.title.as_str());
+        texts.push(example.description.as_str());
+    }
+    if example.images.is_empty() {
+        texts.push(&example.ocr_text);
+    } else {
+        texts.extend(example.images.iter().map(String::as_str));
+    }
+    texts.into_iter().filter(|text| !text.trim().is_empty()).collect()
+}
+
+impl NaiveBayes {
+    ///Trains a model from labelled examples. Both labels must be present, otherwise the model could only ever give one answer.
+    ///Each text of an example is learnt as a document of its own with the example's label, as the filter classifies each text on its own too.
+    pub fn train(examples: &[LabelledExample]) -> Result<NaiveBayes> {
+        let mut model = NaiveBayes::default();
+        for example in examples {
+            let class = class(example.label);
+            for text in example_texts(example) {
+                model.examples[class] += 1;
+                for word in features(text) {
+                    model.totals[class] += 1;
+                    model.words.entry(word).or_insert([0, 0])[class] += 1;
+                }
+            }
+        }
+        if model.examples[0] == 0 || model.examples[1] == 0 {
+            bail!("Training needs both safe and unsafe text, got {} safe and {} unsafe texts", model.examples[0], model.examples[1]);
+        }
+        Ok(model)
+    }
+    ///Returns the number of training texts labelled safe and unsafe.
+    pub fn example_counts(&self) -> [u64; 2] {
+        self.examples
+    }
+    ///Returns the number of distinct words the model knows.
+    pub fn vocabulary(&self) -> usize {
+        self.words.len()
+    }
+    ///Returns the smoothed log probability of a word appearing in each class.
+    fn log_likelihoods(&self, counts: &[u64; 2]) -> [f64; 2] {
+        let vocabulary = self.words.len() as f64;
+        let likelihood = |class: usize| ((counts[class] as f64 + SMOOTHING) / (self.totals[class] as f64 + SMOOTHING * vocabulary)).ln();
+        [likelihood(0), likelihood(1)]
+    }
+    ///Writes the model to a file, replacing it through a temporary file so a running server never reads half a model.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string(&ModelFile::NaiveBayes(self.clone()))?;
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, contents)
+            .with_context(|| format!("Failed to write model file {}", temp.display()))?;
+        fs::rename(&temp, path)
+            .with_context(|| format!("Failed to replace model file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl Classifier for NaiveBayes {
+    fn version(&self) -> &str {
+        &self.version
+    }
+    fn classify(&self, text: &str) -> Option<Classification> {
+        let total: u64 = self.examples.iter().sum();
+        let mut scores = [
+            (self.examples[0] as f64 / total as f64).ln(),
+            (self.examples[1] as f64 / total as f64).ln(),
+        ];
+        let mut known = false;
+        let mut evidence: HashMap<String, f64> = HashMap::new();
+        for word in features(text) {
+            //Words never seen in training carry no evidence either way.
+            let counts = match self.words.get(&word) {
+                Some(counts) => counts,
+                None => continue,
+            };
+            known = true;
+            let [safe, unsafe_] = self.log_likelihoods(counts);
+            scores[0] += safe;
+            scores[1] += unsafe_;
+            if unsafe_ > safe {
+                evidence.insert(word, unsafe_ - safe);
+            }
+        }
+        if !known {
+            return None;
+        }
+        //Equivalent to exp(unsafe) / (exp(safe) + exp(unsafe)), without overflowing on long text.
+        let probability = 1.0 / (1.0 + (scores[0] - scores[1]).exp());
+        let mut evidence: Vec<(String, f64)> = evidence.into_iter().collect();
+        evidence.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+        Some(Classification {
+            probability: probability as f32,
+            terms: evidence.into_iter().take(REPORTED_TERMS).map(|(word, _)| word).collect(),
+        })
+    }
+}
+
+///Loads a model file, whatever kind of classifier it holds. The version of the model is a hash of the file.
+pub fn load(path: &Path) -> Result<Box<dyn Classifier>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read model file {}", path.display()))?;
+    let model: ModelFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid model file {}", path.display()))?;
+    match model {
+        ModelFile::NaiveBayes(mut model) => {
+            if model.examples.contains(&0) {
+                bail!("Model file {} was trained without both safe and unsafe examples", path.display());
+            }
+            model.version = version_of(&contents);
+            Ok(Box::new(model))
+        },
+    }
+}
+
+///Gathers training examples from every user correction and, if `include_verdicts` is set, from the stored verdict of every other post.
+///A post corrected by a user is only learnt from their corrections. Posts on the allowlist or denylist are skipped, as their verdict says nothing about their text.
+pub async fn training_set(db: &Store, include_verdicts: bool) -> Result<Vec<LabelledExample>> {
+    let mut examples = feedback::export(db).await?;
+    if !include_verdicts {
+        return Ok(examples);
+    }
+    let corrected: HashSet<String> = examples.iter()
+        .filter(|example| example.image_id.is_none())
+        .map(|example| example.post_id.clone())
+        .collect();
+    let mut after: Option<String> = None;
+    loop {
+        let posts = db.list(after.as_deref(), BATCH_SIZE).await?;
+        after = match posts.last() {
+            Some(last) => Some(last.id.clone()),
+            None => break,
+        };
+        for post in posts {
+            if post.listed.is_some() || corrected.contains(&post.id) {
+                continue;
+            }
+            if let Some(unrecoverable) = post.unrecoverable {
+                let label = if unrecoverable { Label::Unsafe } else { Label::Safe };
+                examples.push(feedback::labelled_example(&post, None, label));
+            }
+        }
+    }
+    Ok(examples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_texts_are_split_by_field() {
+        let post = LabelledExample::new(None, "the title", &["first image", "", "second image"], Label::Unsafe);
+        assert_eq!(example_texts(&post), vec!["the title", "first image", "second image"]);
+        //A single image's label says nothing about the title of its post.
+        let image = LabelledExample::new(Some("image"), "the title", &["first image"], Label::Unsafe);
+        assert_eq!(example_texts(&image), vec!["first image"]);
+    }
+
+    #[test]
+    fn each_text_is_a_training_document() {
+        let examples = vec![
+            LabelledExample::new(None, "election vote", &["senator ballot"], Label::Unsafe),
+            LabelledExample::new(None, "cute kitten", &[], Label::Safe),
+        ];
+        let model = NaiveBayes::train(&examples).unwrap();
+        assert_eq!(model.example_counts(), [1, 2]);
+        assert!(model.classify("ballot").unwrap().probability > 0.5);
+        assert!(model.classify("kitten").unwrap().probability < 0.5);
+        //Nothing is known about words never seen in training, not even that they are safe.
+        assert_eq!(model.classify("unseen words"), None);
+    }
+
+    #[test]
+    fn training_needs_both_labels() {
+        let examples = vec![LabelledExample::new(None, "election vote", &[], Label::Unsafe)];
+        assert!(NaiveBayes::train(&examples).is_err());
+    }
+}
@@ -0,0 +1,868 @@
+//!This module loads the runtime configuration of the server. Settings are layered: built-in defaults, then a TOML file, then environment variables, then command line flags.
+
+//Imports
+use std::{
+    collections::BTreeMap,
+    env,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use serde::Deserialize;
+use structopt::StructOpt;
+use anyhow::{Context, Result, anyhow, bail};
+use crate::profiles::Profile;
+use crate::filter_rules::version_of;
+use crate::scan_cache;
+
+///The config file which is read when no `--config` flag is given. It is optional, if it does not exist the defaults are used.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+///The prefix of every environment variable which overrides a config value.
+const ENV_PREFIX: &str = "SCRAPER_";
+
+///Command line flags. Every flag overrides the matching value from the config file and the environment.
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "scraper_app", about = "Filters political posts from imgur for the browser extension.")]
+pub struct CommandLine {
+    ///Path to a TOML config file.
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    ///Address the webserver binds to.
+    #[structopt(long)]
+    pub address: Option<IpAddr>,
+    ///Port the webserver listens on.
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    ///Path to the TLS certificate (PEM).
+    #[structopt(long, parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+    ///Path to the TLS private key.
+    #[structopt(long, parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+    ///Storage backend for processed posts: mongo, sqlite or memory.
+    #[structopt(long)]
+    pub database_backend: Option<StorageBackend>,
+    ///Connection string of the mongo database.
+    #[structopt(long)]
+    pub database_uri: Option<String>,
+    ///Path of the SQLite database file.
+    #[structopt(long, parse(from_os_str))]
+    pub sqlite_path: Option<PathBuf>,
+    ///Client-ID used to authenticate against the imgur api.
+    #[structopt(long)]
+    pub imgur_client_id: Option<String>,
+    ///Maximum number of images downloaded at once for a single post.
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+    ///Path to the filter word list.
+    #[structopt(long, parse(from_os_str))]
+    pub word_list: Option<PathBuf>,
+    ///Path to the allowlist and denylist file.
+    #[structopt(long, parse(from_os_str))]
+    pub access_lists: Option<PathBuf>,
+    ///Fraction of images that must be unsafe before the whole post is marked unrecoverable.
+    #[structopt(long)]
+    pub unrecoverable_threshold: Option<f32>,
+    ///Path to a trained classifier model.
+    #[structopt(long, parse(from_os_str))]
+    pub classifier_model: Option<PathBuf>,
+    ///How the classifier is combined with the rules: keywords, classifier, either or both.
+    #[structopt(long)]
+    pub classifier_mode: Option<ClassifierMode>,
+    ///Probability from which the classifier judges text political.
+    #[structopt(long)]
+    pub classifier_threshold: Option<f32>,
+    ///Directory containing the tesseract language models.
+    #[structopt(long, parse(from_os_str))]
+    pub tessdata: Option<PathBuf>,
+    ///Number of workers processing queued posts.
+    #[structopt(long)]
+    pub workers: Option<usize>,
+    ///Number of threads running OCR, each with its own tesseract engine.
+    #[structopt(long)]
+    pub ocr_workers: Option<usize>,
+    ///Tesseract language models to read text with, joined with `+` such as `eng+spa+deu`.
+    #[structopt(long)]
+    pub ocr_languages: Option<String>,
+    ///Run a maintenance command instead of the webserver.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+///Maintenance commands which run once and exit.
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    ///Re-check stored posts against the current filter rules without downloading them again.
+    Rescore {
+        ///Re-check every post, not just those judged by a different version of the rules.
+        #[structopt(long)]
+        all: bool,
+    },
+    ///Measure the filter rules and image threshold against a labelled dataset, printing precision, recall and the terms behind each mistake.
+    Evaluate {
+        ///A JSONL file of labelled examples, in the format written by export-feedback.
+        #[structopt(parse(from_os_str))]
+        dataset: PathBuf,
+        ///How many false positive and false negative terms to list.
+        #[structopt(long, default_value = "10")]
+        top: usize,
+        ///Print the report as JSON.
+        #[structopt(long)]
+        json: bool,
+    },
+    ///Train a Naive Bayes classifier from user corrections, writing it to a model file.
+    TrainClassifier {
+        ///Where to write the model.
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        ///Also learn from the verdicts the filter gave stored posts which no user corrected. The model then learns to copy the rules, mistakes included.
+        #[structopt(long)]
+        include_verdicts: bool,
+        ///Extra labelled examples to learn from, in the format written by export-feedback. Can be given more than once.
+        #[structopt(long, parse(from_os_str))]
+        dataset: Vec<PathBuf>,
+    },
+    ///Run the OCR preprocessing over an image file and save the result, to see what tesseract will be given. Every enabled stage runs, even if ocr.preprocess.enabled is off.
+    Preprocess {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        ///Where to save the result. The format is taken from the extension.
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    ///Write every user correction as labelled training data, one JSON object per line.
+    ExportFeedback {
+        ///The file to write to. Defaults to standard output.
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+///The complete configuration of the server.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub imgur: ImgurConfig,
+    pub filter: FilterConfig,
+    pub ocr: OcrConfig,
+    pub queue: QueueConfig,
+    ///Per-user filter profiles, given as `[[profiles]]` tables.
+    pub profiles: Vec<Profile>,
+}
+
+///Settings for the webserver.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    ///When both the certificate and key are set the server is served over https, otherwise plain http.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    ///Bearer token required by the admin endpoints. They are disabled when unset.
+    pub admin_token: Option<String>,
+}
+
+///Settings for the database connection.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: StorageBackend,
+    ///Connection string and database name, used by the mongo backend.
+    pub uri: String,
+    pub name: String,
+    ///Database file, used by the sqlite backend.
+    pub sqlite_path: PathBuf,
+}
+
+///The storage backends processed posts can be kept in.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongo,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mongo" | "mongodb" => Ok(StorageBackend::Mongo),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => bail!("unknown storage backend {:?}, expected mongo, sqlite or memory", s),
+        }
+    }
+}
+
+///Settings for talking to imgur.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImgurConfig {
+    pub client_id: String,
+    pub max_connections: usize,
+}
+
+///Settings for the word filter and the post verdict.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub word_list: PathBuf,
+    ///The allowlist and denylist file. It is created on the first edit.
+    pub access_lists: PathBuf,
+    pub unrecoverable_threshold: f32,
+    ///Reload the rules automatically when the rule file changes.
+    pub watch: bool,
+    ///Re-check stored posts in the background at startup and whenever the rules change.
+    pub rescore_on_change: bool,
+    ///A model trained with `train-classifier`. It is only loaded when the mode uses it.
+    pub classifier_model: Option<PathBuf>,
+    pub classifier_mode: ClassifierMode,
+    pub classifier_threshold: f32,
+    ///Rule files for text in other languages, by ISO 639-3 code such as `spa`. The rules of the language detected in a piece of text are checked on top of `word_list`.
+    pub languages: BTreeMap<String, PathBuf>,
+}
+
+///How the classifier's verdict on a piece of text is combined with the rules.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClassifierMode {
+    ///Only the rules are used.
+    Keywords,
+    ///Only the classifier is used.
+    Classifier,
+    ///Text is unsafe if either the rules or the classifier say so.
+    Either,
+    ///Text is unsafe only if the rules match and the classifier agrees, which cuts out matches of political words used in other senses.
+    Both,
+}
+
+impl FromStr for ClassifierMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "keywords" => Ok(ClassifierMode::Keywords),
+            "classifier" => Ok(ClassifierMode::Classifier),
+            "either" => Ok(ClassifierMode::Either),
+            "both" => Ok(ClassifierMode::Both),
+            _ => bail!("unknown classifier mode {:?}, expected keywords, classifier, either or both", s),
+        }
+    }
+}
+
+///Settings for the OCR scanner.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OcrConfig {
+    ///When unset tesseract falls back to its compiled in data directory.
+    pub tessdata: Option<PathBuf>,
+    ///The tesseract language models to read text with, joined with `+` such as `eng+spa+deu`. Every model must be in the tessdata directory.
+    pub languages: String,
+    ///Number of threads running OCR. Each loads its own tesseract engine once at startup.
+    pub workers: usize,
+    ///How many images can wait for a free OCR thread. Downloads wait for room in the queue once it is full.
+    pub queue_size: usize,
+    ///How long an image may take, waiting in the queue included, before it is given up on and stored unscanned, so its post is processed again later.
+    pub timeout_secs: u64,
+    ///Words tesseract is less sure of than this, from 0 to 100, are discarded before the text is filtered, as they are usually noise.
+    pub min_confidence: f32,
+    ///How many frames of an animated GIF are scanned: the first, the middle, then the largest scene changes.
+    pub gif_frames: usize,
+    ///Images are scanned from memory. Downloads larger than this many bytes are written to a file in `spool_dir` instead, so the downloaded bytes are never buffered in memory. Never spooled when unset.
+    ///Spooled images are read by tesseract from the file without preprocessing, but decoding them still holds every pixel in memory.
+    pub spool_above_bytes: Option<u64>,
+    ///Where spooled images are written. When unset the system temporary directory is used.
+    pub spool_dir: Option<PathBuf>,
+    pub preprocess: PreprocessConfig,
+    pub video: VideoConfig,
+    pub cache: CacheConfig,
+}
+
+///Settings for reusing the OCR of images seen before, found by perceptual hash. Off by default.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    ///Images whose hashes differ in at most this many of their 64 bits are compared by their detail hashes.
+    pub max_distance: u32,
+}
+
+///Settings for scanning keyframes of mp4 and gifv videos with ffmpeg. Videos are skipped unless this is enabled.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    pub enabled: bool,
+    ///The ffmpeg and ffprobe binaries, looked up on the PATH unless a path is given.
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    ///Videos larger than this are not downloaded past this many bytes, and are left unscanned.
+    pub max_bytes: u64,
+    ///Videos longer than this are left unscanned.
+    pub max_duration_secs: u64,
+    ///How many keyframes are scanned: the first, the middle, then the largest scene changes.
+    pub frames: usize,
+}
+
+///Settings for preparing images before OCR. Each stage can be switched off on its own.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreprocessConfig {
+    ///Switches every stage off at once, scanning images as downloaded.
+    pub enabled: bool,
+    ///Thresholding, inversion and deskewing work on grayscale, so they convert the image even when this is off.
+    pub grayscale: bool,
+    ///Scale images up until their shorter side is at least `min_side` pixels, by at most `max_upscale` times.
+    pub upscale: bool,
+    pub min_side: u32,
+    pub max_upscale: f32,
+    ///Binarise each pixel against the mean of the `threshold_window` pixels around it, so text on busy or uneven backgrounds survives.
+    pub threshold: bool,
+    ///A window of 0 uses an eighth of the shorter side.
+    pub threshold_window: u32,
+    ///How many percent darker than its neighbourhood a pixel must be to count as text.
+    pub threshold_offset: u8,
+    ///Invert images which are mostly dark, as tesseract reads dark text on a light background best.
+    pub invert: bool,
+    ///Straighten text rotated by up to `max_skew` degrees.
+    pub deskew: bool,
+    pub max_skew: f32,
+    ///Images with more pixels than this are never decoded by the server. They are scanned as downloaded and left out of the scan cache.
+    pub max_pixels: u64,
+}
+
+///Settings for the background job queue.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    ///Number of posts processed at the same time.
+    pub workers: usize,
+    ///How many of the workers only process posts the user is currently viewing.
+    pub reserved_visible_workers: usize,
+    ///How long the result of a finished job can be polled for.
+    pub job_retention_secs: u64,
+    ///The most jobs tracked at once, finished jobs included. Posts of every priority are rejected beyond it.
+    pub max_jobs: usize,
+    ///How many of `max_jobs` are kept for visible posts. Background and prefetch posts are rejected once only these are left.
+    pub reserved_visible_jobs: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: IpAddr::from([0, 0, 0, 0]),
+            port: 3030,
+            tls_cert: None,
+            tls_key: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: StorageBackend::Mongo,
+            uri: "mongodb://localhost:27017".to_owned(),
+            name: "imgur_scraper".to_owned(),
+            sqlite_path: PathBuf::from("imgur_scraper.sqlite"),
+        }
+    }
+}
+
+impl Default for ImgurConfig {
+    fn default() -> Self {
+        ImgurConfig {
+            client_id: String::new(),
+            max_connections: 10,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            workers: 4,
+            reserved_visible_workers: 1,
+            job_retention_secs: 300,
+            max_jobs: 10_000,
+            reserved_visible_jobs: 1_000,
+        }
+    }
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        OcrConfig {
+            tessdata: None,
+            languages: String::from("eng"),
+            workers: 2,
+            queue_size: 16,
+            timeout_secs: 30,
+            min_confidence: 50.0,
+            gif_frames: 4,
+            spool_above_bytes: None,
+            spool_dir: None,
+            preprocess: PreprocessConfig::default(),
+            video: VideoConfig::default(),
+            cache: CacheConfig::default(),
+        }
+    }
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            enabled: false,
+            ffmpeg: PathBuf::from("ffmpeg"),
+            ffprobe: PathBuf::from("ffprobe"),
+            max_bytes: 50_000_000,
+            max_duration_secs: 120,
+            frames: 6,
+        }
+    }
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        PreprocessConfig {
+            enabled: true,
+            grayscale: true,
+            upscale: true,
+            min_side: 800,
+            max_upscale: 3.0,
+            threshold: true,
+            threshold_window: 0,
+            threshold_offset: 15,
+            invert: true,
+            deskew: true,
+            max_skew: 10.0,
+            max_pixels: 50_000_000,
+        }
+    }
+}
+
+impl PreprocessConfig {
+    ///Returns true if any stage is switched on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && (self.grayscale || self.upscale || self.threshold || self.invert || self.deskew)
+    }
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            word_list: PathBuf::from("filter_word_list.txt"),
+            access_lists: PathBuf::from("access_lists.json"),
+            unrecoverable_threshold: 0.2,
+            watch: true,
+            rescore_on_change: true,
+            classifier_model: None,
+            classifier_mode: ClassifierMode::Keywords,
+            classifier_threshold: 0.5,
+            languages: BTreeMap::new(),
+        }
+    }
+}
+
+///The environment variables with the config prefix, keyed by their full name.
+type Environment = BTreeMap<String, String>;
+
+///Reads every environment variable with the config prefix from the process environment.
+fn environment() -> Result<Environment> {
+    let mut vars = Environment::new();
+    for (key, value) in env::vars_os() {
+        let key = match key.into_string() {
+            Ok(key) if key.starts_with(ENV_PREFIX) => key,
+            _ => continue,
+        };
+        let value = value.into_string()
+            .map_err(|value| anyhow!("Environment variable {} could not be read: {:?} is not valid unicode", key, value))?;
+        vars.insert(key, value);
+    }
+    Ok(vars)
+}
+
+///Reads an environment variable with the config prefix, parsing it into the requested type.
+fn env_var<T: FromStr>(vars: &Environment, name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    let key = format!("{}{}", ENV_PREFIX, name);
+    match vars.get(&key) {
+        Some(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow!("Environment variable {} has an invalid value {:?}: {}", key, value, e)),
+        None => Ok(None),
+    }
+}
+
+///Overwrites the target if a new value is present.
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+impl Config {
+    ///Loads the config from every layer and validates the result. This should be called once at startup.
+    pub fn load(args: &CommandLine) -> Result<Config> {
+        Config::layered(args, &environment()?)
+    }
+    ///Loads the config from every layer, reading environment overrides from the given variables rather than the process environment.
+    fn layered(args: &CommandLine, vars: &Environment) -> Result<Config> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+        config.apply_env(vars)?;
+        config.apply_args(args);
+        config.validate(args.command.is_none())?;
+        Ok(config)
+    }
+    ///Parses a TOML config file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+    ///Applies any `SCRAPER_*` environment variables over the current values.
+    fn apply_env(&mut self, vars: &Environment) -> Result<()> {
+        set(&mut self.server.address, env_var(vars, "SERVER_ADDRESS")?);
+        set(&mut self.server.port, env_var(vars, "SERVER_PORT")?);
+        if let Some(path) = env_var(vars, "TLS_CERT")? { self.server.tls_cert = Some(path); }
+        if let Some(path) = env_var(vars, "TLS_KEY")? { self.server.tls_key = Some(path); }
+        if let Some(token) = env_var(vars, "ADMIN_TOKEN")? { self.server.admin_token = Some(token); }
+        set(&mut self.database.backend, env_var(vars, "DATABASE_BACKEND")?);
+        set(&mut self.database.uri, env_var(vars, "DATABASE_URI")?);
+        set(&mut self.database.name, env_var(vars, "DATABASE_NAME")?);
+        set(&mut self.database.sqlite_path, env_var(vars, "SQLITE_PATH")?);
+        set(&mut self.imgur.client_id, env_var(vars, "IMGUR_CLIENT_ID")?);
+        set(&mut self.imgur.max_connections, env_var(vars, "MAX_CONNECTIONS")?);
+        set(&mut self.filter.word_list, env_var(vars, "WORD_LIST")?);
+        set(&mut self.filter.access_lists, env_var(vars, "ACCESS_LISTS")?);
+        set(&mut self.filter.unrecoverable_threshold, env_var(vars, "UNRECOVERABLE_THRESHOLD")?);
+        set(&mut self.filter.watch, env_var(vars, "WATCH_FILTER")?);
+        set(&mut self.filter.rescore_on_change, env_var(vars, "RESCORE_ON_CHANGE")?);
+        if let Some(path) = env_var(vars, "CLASSIFIER_MODEL")? { self.filter.classifier_model = Some(path); }
+        set(&mut self.filter.classifier_mode, env_var(vars, "CLASSIFIER_MODE")?);
+        set(&mut self.filter.classifier_threshold, env_var(vars, "CLASSIFIER_THRESHOLD")?);
+        if let Some(path) = env_var(vars, "TESSDATA")? { self.ocr.tessdata = Some(path); }
+        set(&mut self.ocr.languages, env_var(vars, "OCR_LANGUAGES")?);
+        set(&mut self.ocr.workers, env_var(vars, "OCR_WORKERS")?);
+        set(&mut self.ocr.queue_size, env_var(vars, "OCR_QUEUE_SIZE")?);
+        set(&mut self.ocr.timeout_secs, env_var(vars, "OCR_TIMEOUT_SECS")?);
+        set(&mut self.ocr.min_confidence, env_var(vars, "OCR_MIN_CONFIDENCE")?);
+        set(&mut self.ocr.gif_frames, env_var(vars, "OCR_GIF_FRAMES")?);
+        if let Some(bytes) = env_var(vars, "OCR_SPOOL_ABOVE_BYTES")? { self.ocr.spool_above_bytes = Some(bytes); }
+        if let Some(path) = env_var(vars, "OCR_SPOOL_DIR")? { self.ocr.spool_dir = Some(path); }
+        set(&mut self.ocr.preprocess.enabled, env_var(vars, "OCR_PREPROCESS")?);
+        set(&mut self.ocr.video.enabled, env_var(vars, "OCR_VIDEO")?);
+        set(&mut self.ocr.video.ffmpeg, env_var(vars, "FFMPEG")?);
+        set(&mut self.ocr.video.ffprobe, env_var(vars, "FFPROBE")?);
+        set(&mut self.ocr.cache.enabled, env_var(vars, "OCR_CACHE")?);
+        set(&mut self.queue.workers, env_var(vars, "WORKERS")?);
+        set(&mut self.queue.reserved_visible_workers, env_var(vars, "RESERVED_VISIBLE_WORKERS")?);
+        set(&mut self.queue.job_retention_secs, env_var(vars, "JOB_RETENTION_SECS")?);
+        set(&mut self.queue.max_jobs, env_var(vars, "MAX_JOBS")?);
+        set(&mut self.queue.reserved_visible_jobs, env_var(vars, "RESERVED_VISIBLE_JOBS")?);
+        Ok(())
+    }
+    ///Applies the command line flags over the current values.
+    fn apply_args(&mut self, args: &CommandLine) {
+        set(&mut self.server.address, args.address);
+        set(&mut self.server.port, args.port);
+        if args.tls_cert.is_some() { self.server.tls_cert = args.tls_cert.clone(); }
+        if args.tls_key.is_some() { self.server.tls_key = args.tls_key.clone(); }
+        set(&mut self.database.backend, args.database_backend);
+        set(&mut self.database.uri, args.database_uri.clone());
+        set(&mut self.database.sqlite_path, args.sqlite_path.clone());
+        set(&mut self.imgur.client_id, args.imgur_client_id.clone());
+        set(&mut self.imgur.max_connections, args.max_connections);
+        set(&mut self.filter.word_list, args.word_list.clone());
+        set(&mut self.filter.access_lists, args.access_lists.clone());
+        set(&mut self.filter.unrecoverable_threshold, args.unrecoverable_threshold);
+        if args.classifier_model.is_some() { self.filter.classifier_model = args.classifier_model.clone(); }
+        set(&mut self.filter.classifier_mode, args.classifier_mode);
+        set(&mut self.filter.classifier_threshold, args.classifier_threshold);
+        if args.tessdata.is_some() { self.ocr.tessdata = args.tessdata.clone(); }
+        set(&mut self.ocr.languages, args.ocr_languages.clone());
+        set(&mut self.ocr.workers, args.ocr_workers);
+        set(&mut self.queue.workers, args.workers);
+    }
+    ///Checks the final config for values the server cannot run with. Settings only the webserver uses are skipped for maintenance commands.
+    fn validate(&self, serving: bool) -> Result<()> {
+        if self.server.port == 0 {
+            bail!("server.port must not be 0");
+        }
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(cert), Some(key)) => {
+                if !cert.is_file() {
+                    bail!("server.tls_cert {} does not exist", cert.display());
+                }
+                if !key.is_file() {
+                    bail!("server.tls_key {} does not exist", key.display());
+                }
+            },
+            (None, None) => {},
+            _ => bail!("server.tls_cert and server.tls_key must be set together"),
+        }
+        if let Some(token) = &self.server.admin_token {
+            if token.len() < 16 {
+                bail!("server.admin_token must be at least 16 characters long");
+            }
+        }
+        match self.database.backend {
+            StorageBackend::Mongo if self.database.uri.trim().is_empty() => bail!("database.uri must not be empty"),
+            StorageBackend::Sqlite if self.database.sqlite_path.as_os_str().is_empty() => bail!("database.sqlite_path must not be empty"),
+            _ => {},
+        }
+        if serving && self.imgur.client_id.trim().is_empty() {
+            bail!("imgur.client_id must be set (config file, {}IMGUR_CLIENT_ID or --imgur-client-id)", ENV_PREFIX);
+        }
+        if self.imgur.max_connections == 0 {
+            bail!("imgur.max_connections must be at least 1");
+        }
+        if !self.filter.word_list.is_file() {
+            bail!("filter.word_list {} does not exist", self.filter.word_list.display());
+        }
+        if self.filter.access_lists.is_dir() {
+            bail!("filter.access_lists {} is a directory", self.filter.access_lists.display());
+        }
+        if !(0.0..=1.0).contains(&self.filter.unrecoverable_threshold) {
+            bail!("filter.unrecoverable_threshold must be between 0 and 1, got {}", self.filter.unrecoverable_threshold);
+        }
+        //Commands which need the model fail when loading it instead, so a first model can be trained with the mode already set.
+        if serving && self.filter.classifier_mode != ClassifierMode::Keywords {
+            match &self.filter.classifier_model {
+                Some(model) if !model.is_file() => bail!("filter.classifier_model {} does not exist", model.display()),
+                Some(_) => {},
+                None => bail!("filter.classifier_model must be set when filter.classifier_mode is not keywords"),
+            }
+        }
+        if !(0.0..=1.0).contains(&self.filter.classifier_threshold) {
+            bail!("filter.classifier_threshold must be between 0 and 1, got {}", self.filter.classifier_threshold);
+        }
+        for (code, path) in &self.filter.languages {
+            if whatlang::Lang::from_code(code).is_none() {
+                bail!("filter.languages has unknown language code {}, expected an ISO 639-3 code such as spa", code);
+            }
+            if !path.is_file() {
+                bail!("filter.languages.{} {} does not exist", code, path.display());
+            }
+        }
+        if let Some(tessdata) = &self.ocr.tessdata {
+            if !tessdata.is_dir() {
+                bail!("ocr.tessdata {} is not a directory", tessdata.display());
+            }
+            if tessdata.to_str().is_none() {
+                bail!("ocr.tessdata {} is not valid utf-8", tessdata.display());
+            }
+        }
+        let languages: Vec<&str> = self.ocr.languages.split('+').collect();
+        if languages.iter().any(|lang| lang.is_empty() || !lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+            bail!("ocr.languages must be tesseract language models joined with +, such as eng+spa, got {}", self.ocr.languages);
+        }
+        if let Some(tessdata) = &self.ocr.tessdata {
+            if let Some(lang) = languages.iter().find(|lang| !tessdata.join(format!("{}.traineddata", lang)).is_file()) {
+                bail!("ocr.languages includes {}, but {} has no {}.traineddata", lang, tessdata.display(), lang);
+            }
+        }
+        if self.ocr.workers == 0 {
+            bail!("ocr.workers must be at least 1");
+        }
+        if self.ocr.queue_size == 0 {
+            bail!("ocr.queue_size must be at least 1");
+        }
+        if self.ocr.timeout_secs == 0 {
+            bail!("ocr.timeout_secs must be at least 1");
+        }
+        if !(0.0..=100.0).contains(&self.ocr.min_confidence) {
+            bail!("ocr.min_confidence must be between 0 and 100, got {}", self.ocr.min_confidence);
+        }
+        if !(1..=32).contains(&self.ocr.gif_frames) {
+            bail!("ocr.gif_frames must be between 1 and 32, got {}", self.ocr.gif_frames);
+        }
+        if self.ocr.spool_above_bytes == Some(0) {
+            bail!("ocr.spool_above_bytes must be at least 1, leave it unset to never spool images");
+        }
+        if let Some(spool_dir) = &self.ocr.spool_dir {
+            if serving && !spool_dir.is_dir() {
+                bail!("ocr.spool_dir {} is not a directory", spool_dir.display());
+            }
+        }
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.name.trim().is_empty() {
+                bail!("profiles[{}].name must not be empty", i);
+            }
+            if profile.api_key.len() < 16 {
+                bail!("profile {} api_key must be at least 16 characters long", profile.name);
+            }
+            if self.profiles[..i].iter().any(|other| other.name == profile.name || other.api_key == profile.api_key) {
+                bail!("profile {} must have a name and api_key unlike every other profile", profile.name);
+            }
+            if let Some((category, threshold)) = profile.thresholds.iter().find(|(_, threshold)| **threshold <= 0.0) {
+                bail!("profile {} threshold for {} must be above 0, got {}", profile.name, category, threshold);
+            }
+            if let Some(threshold) = profile.unrecoverable_threshold {
+                if !(0.0..=1.0).contains(&threshold) {
+                    bail!("profile {} unrecoverable_threshold must be between 0 and 1, got {}", profile.name, threshold);
+                }
+            }
+        }
+        let preprocess = &self.ocr.preprocess;
+        if preprocess.min_side == 0 {
+            bail!("ocr.preprocess.min_side must be at least 1");
+        }
+        if !(preprocess.max_upscale >= 1.0 && preprocess.max_upscale <= 8.0) {
+            bail!("ocr.preprocess.max_upscale must be between 1 and 8, got {}", preprocess.max_upscale);
+        }
+        if preprocess.threshold_window != 0 && (preprocess.threshold_window < 3 || preprocess.threshold_window % 2 == 0) {
+            bail!("ocr.preprocess.threshold_window must be 0 or an odd number of at least 3, got {}", preprocess.threshold_window);
+        }
+        if preprocess.threshold_offset > 100 {
+            bail!("ocr.preprocess.threshold_offset must be a percentage, got {}", preprocess.threshold_offset);
+        }
+        if !(0.0..=45.0).contains(&preprocess.max_skew) {
+            bail!("ocr.preprocess.max_skew must be between 0 and 45 degrees, got {}", preprocess.max_skew);
+        }
+        if preprocess.max_pixels == 0 {
+            bail!("ocr.preprocess.max_pixels must be at least 1");
+        }
+        let video = &self.ocr.video;
+        if video.max_bytes == 0 {
+            bail!("ocr.video.max_bytes must be at least 1");
+        }
+        if video.max_duration_secs == 0 {
+            bail!("ocr.video.max_duration_secs must be at least 1");
+        }
+        if !(1..=32).contains(&video.frames) {
+            bail!("ocr.video.frames must be between 1 and 32, got {}", video.frames);
+        }
+        if self.ocr.cache.max_distance > scan_cache::MAX_DISTANCE {
+            bail!("ocr.cache.max_distance must be at most {}, got {}", scan_cache::MAX_DISTANCE, self.ocr.cache.max_distance);
+        }
+        if self.queue.workers == 0 {
+            bail!("queue.workers must be at least 1");
+        }
+        if self.queue.reserved_visible_workers >= self.queue.workers {
+            bail!("queue.reserved_visible_workers ({}) must be less than queue.workers ({})", self.queue.reserved_visible_workers, self.queue.workers);
+        }
+        if self.queue.max_jobs == 0 {
+            bail!("queue.max_jobs must be at least 1");
+        }
+        if self.queue.reserved_visible_jobs >= self.queue.max_jobs {
+            bail!("queue.reserved_visible_jobs ({}) must be less than queue.max_jobs ({})", self.queue.reserved_visible_jobs, self.queue.max_jobs);
+        }
+        Ok(())
+    }
+}
+
+impl OcrConfig {
+    ///Returns the tessdata directory in the form leptess expects.
+    pub fn tessdata(&self) -> Option<&str> {
+        self.tessdata.as_ref().and_then(|path| path.to_str())
+    }
+    ///Returns the directory spooled images are written to.
+    pub fn spool_dir(&self) -> PathBuf {
+        self.spool_dir.clone().unwrap_or_else(env::temp_dir)
+    }
+    ///Returns a version covering every setting which changes the text read from an image, so cached scans are only reused when they would be read the same way again.
+    pub fn version(&self) -> String {
+        version_of(&format!("{} {} {:?}", self.languages, self.min_confidence, self.preprocess))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A config the webserver can run with, as long as it is run from the crate directory.
+    fn serving() -> Config {
+        let mut config = Config::default();
+        config.imgur.client_id = "client".to_owned();
+        config
+    }
+
+    fn error(config: &Config, serving: bool) -> String {
+        format!("{:#}", config.validate(serving).unwrap_err())
+    }
+
+    #[test]
+    fn load_applies_the_file_then_the_environment_then_flags() {
+        let path = env::temp_dir().join(format!("config_test_{}.toml", std::process::id()));
+        fs::write(&path, "[server]\nport = 1000\n[database]\nname = \"from_file\"\n[imgur]\nclient_id = \"from_file\"\nmax_connections = 3\n").unwrap();
+        let mut vars: Environment = vec![("SCRAPER_SERVER_PORT", "2000"), ("SCRAPER_DATABASE_NAME", "from_env")]
+            .into_iter().map(|(key, value)| (key.to_owned(), value.to_owned())).collect();
+        let args = CommandLine { config: Some(path.clone()), port: Some(3000), ..CommandLine::default() };
+        let config = Config::layered(&args, &vars).unwrap();
+        assert_eq!(config.server.port, 3000);
+        assert_eq!(config.database.name, "from_env");
+        assert_eq!((config.imgur.client_id.as_str(), config.imgur.max_connections), ("from_file", 3));
+        assert_eq!(config.queue.workers, QueueConfig::default().workers);
+
+        vars.insert("SCRAPER_SERVER_PORT".to_owned(), "many".to_owned());
+        let e = Config::layered(&CommandLine { config: Some(path.clone()), ..CommandLine::default() }, &vars).unwrap_err();
+        assert!(format!("{:#}", e).contains("SCRAPER_SERVER_PORT"), "{:#}", e);
+
+        fs::write(&path, "[server]\nprot = 1000\n").unwrap();
+        assert!(format!("{:#}", Config::from_file(&path).unwrap_err()).contains("unknown field `prot`"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_values_the_server_cannot_run_with() {
+        assert!(serving().validate(true).is_ok());
+        //Maintenance commands don't talk to imgur.
+        assert!(Config::default().validate(false).is_ok());
+        assert!(error(&Config::default(), true).starts_with("imgur.client_id must be set"));
+
+        let mut config = serving();
+        config.server.tls_cert = Some(PathBuf::from("cert.pem"));
+        assert_eq!(error(&config, true), "server.tls_cert and server.tls_key must be set together");
+        let mut config = serving();
+        config.server.admin_token = Some("short".to_owned());
+        assert_eq!(error(&config, true), "server.admin_token must be at least 16 characters long");
+        let mut config = serving();
+        config.filter.word_list = PathBuf::from("missing_word_list.txt");
+        assert_eq!(error(&config, true), "filter.word_list missing_word_list.txt does not exist");
+        let mut config = serving();
+        config.filter.unrecoverable_threshold = 1.5;
+        assert!(error(&config, true).starts_with("filter.unrecoverable_threshold must be between 0 and 1"));
+        for (window, valid) in [(0, true), (1, false), (3, true), (4, false), (15, true)].iter() {
+            let mut config = serving();
+            config.ocr.preprocess.threshold_window = *window;
+            assert_eq!(config.validate(true).is_ok(), *valid, "threshold_window {}", window);
+        }
+        let mut config = serving();
+        config.queue.reserved_visible_workers = config.queue.workers;
+        assert!(error(&config, true).starts_with("queue.reserved_visible_workers"));
+    }
+
+    #[test]
+    fn validate_checks_profiles() {
+        let named = |name: &str, api_key: &str| Profile {
+            name: name.to_owned(),
+            api_key: api_key.to_owned(),
+            categories: vec![],
+            thresholds: Default::default(),
+            unrecoverable_threshold: None,
+        };
+        let mut config = serving();
+        config.profiles = vec![named("alice", "a-long-enough-api-key"), named("bob", "another-long-api-key")];
+        assert!(config.validate(true).is_ok());
+        config.profiles[1].api_key = "a-long-enough-api-key".to_owned();
+        assert_eq!(error(&config, true), "profile bob must have a name and api_key unlike every other profile");
+        config.profiles[1] = named("bob", "short");
+        assert_eq!(error(&config, true), "profile bob api_key must be at least 16 characters long");
+        config.profiles[1] = Profile { thresholds: vec![("politics".to_owned(), 0.0)].into_iter().collect(), ..named("bob", "another-long-api-key") };
+        assert_eq!(error(&config, true), "profile bob threshold for politics must be above 0, got 0");
+    }
+}
@@ -0,0 +1,247 @@
+//!This module measures the filter against a labelled dataset, so changes to the rules or the image threshold can be reviewed with numbers rather than guessed at.
+
+//Imports
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::Path,
+};
+use serde::Serialize;
+use anyhow::{Context, Result, bail};
+use crate::feedback::{Label, LabelledExample};
+use crate::filter::Filter;
+use crate::mongo_db_interface::{Image, Post};
+use crate::verdict;
+
+///Counts of predicted against expected verdicts, treating unsafe as the positive class.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ConfusionMatrix {
+    pub true_positives: u64,
+    pub false_positives: u64,
+    pub true_negatives: u64,
+    pub false_negatives: u64,
+}
+
+///A rule term, with the number of examples it was found in.
+#[derive(Serialize, Clone, Debug)]
+pub struct TermCount {
+    pub category: String,
+    pub term: String,
+    pub examples: u64,
+}
+
+///The result of evaluating the filter over a dataset.
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    pub filter_version: String,
+    pub unrecoverable_threshold: f32,
+    pub examples: u64,
+    pub matrix: ConfusionMatrix,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub accuracy: f64,
+    ///The terms which blocked safe examples, most frequent first.
+    pub false_positive_terms: Vec<TermCount>,
+    ///The terms found in unsafe examples which were let through, because their category or the image threshold was not reached. Most frequent first.
+    pub false_negative_terms: Vec<TermCount>,
+}
+
+///Reads a JSONL dataset of labelled examples. Errors name the offending line.
+pub fn load_dataset(path: &Path) -> Result<Vec<LabelledExample>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read dataset {}", path.display()))?;
+    let mut examples = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let example = serde_json::from_str(line)
+            .with_context(|| format!("Invalid dataset {}, line {}", path.display(), i + 1))?;
+        examples.push(example);
+    }
+    if examples.is_empty() {
+        bail!("Dataset {} contains no examples", path.display());
+    }
+    Ok(examples)
+}
+
+///Builds the post the filter would have seen for an example.
+fn to_post(example: &LabelledExample) -> Post {
+    let texts = if example.images.is_empty() && !example.ocr_text.trim().is_empty() {
+        vec![example.ocr_text.clone()]
+    } else {
+        example.images.clone()
+    };
+    let images = texts.into_iter().enumerate()
+        .map(|(i, text)| Image { id: i.to_string(), image_ocr_text: Some(text), ..Image::default() })
+        .collect();
+    Post {
+        id: example.post_id.clone(),
+        images,
+        description: Some(example.description.clone()),
+        title: Some(example.title.clone()),
+        ..Post::default()
+    }
+}
+
+///The verdict for one example.
+struct Judgement {
+    unsafe_: bool,
+    ///The distinct terms which made the example unsafe.
+    blocking: BTreeSet<(String, String)>,
+    ///The distinct terms found anywhere in the example, whether or not they reached their threshold.
+    found: BTreeSet<(String, String)>,
+}
+
+///Judges one example. Examples labelling a single image are judged on that image alone, the same way the image is judged within its post.
+fn judge(filter: &Filter, threshold: f32, example: &LabelledExample) -> Judgement {
+    let mut post = to_post(example);
+    let unsafe_ = if example.image_id.is_some() {
+        post.images.iter_mut().fold(false, |found, image| {
+            verdict::check_image(filter, image);
+            found || image.unrecoverable == Some(true)
+        })
+    } else {
+        verdict::evaluate(filter, &mut post, threshold, &[]);
+        post.unrecoverable == Some(true)
+    };
+    let blocking = post.reasons.iter()
+        .chain(post.images.iter().flat_map(|image| &image.reasons))
+        .map(|reason| (reason.category.clone(), reason.term.clone()))
+        .collect();
+    //Every match is collected, not just blocking ones, so near misses can be reported for false negatives.
+    let mut texts = vec![example.title.as_str(), example.description.as_str()];
+    texts.extend(post.images.iter().filter_map(|image| image.image_ocr_text.as_deref()));
+    let found = texts.into_iter()
+        .flat_map(|text| filter.check(text).matches)
+        .map(|m| (m.category, m.term))
+        .collect();
+    Judgement { unsafe_, blocking, found }
+}
+
+///Sorts term counts most frequent first, keeping the top `top`.
+fn top_terms(counts: HashMap<(String, String), u64>, top: usize) -> Vec<TermCount> {
+    let mut terms: Vec<TermCount> = counts.into_iter()
+        .map(|((category, term), examples)| TermCount { category, term, examples })
+        .collect();
+    terms.sort_by(|a, b| b.examples.cmp(&a.examples).then_with(|| (&a.category, &a.term).cmp(&(&b.category, &b.term))));
+    terms.truncate(top);
+    terms
+}
+
+///Divides, treating an empty denominator as a score of zero.
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 { 0.0 } else { numerator as f64 / denominator as f64 }
+}
+
+///Runs the filter and the post threshold over every example and scores the predictions.
+pub fn evaluate(filter: &Filter, threshold: f32, examples: &[LabelledExample], top: usize) -> Report {
+    let mut matrix = ConfusionMatrix::default();
+    let mut false_positive_terms = HashMap::new();
+    let mut false_negative_terms = HashMap::new();
+    for example in examples {
+        let judgement = judge(filter, threshold, example);
+        let terms = match (judgement.unsafe_, example.label) {
+            (true, Label::Unsafe) => { matrix.true_positives += 1; None },
+            (true, Label::Safe) => { matrix.false_positives += 1; Some((&mut false_positive_terms, judgement.blocking)) },
+            (false, Label::Safe) => { matrix.true_negatives += 1; None },
+            (false, Label::Unsafe) => { matrix.false_negatives += 1; Some((&mut false_negative_terms, judgement.found)) },
+        };
+        if let Some((counts, terms)) = terms {
+            for term in terms {
+                *counts.entry(term).or_insert(0) += 1;
+            }
+        }
+    }
+    let precision = ratio(matrix.true_positives, matrix.true_positives + matrix.false_positives);
+    let recall = ratio(matrix.true_positives, matrix.true_positives + matrix.false_negatives);
+    let f1 = if precision + recall == 0.0 { 0.0 } else { 2.0 * precision * recall / (precision + recall) };
+    Report {
+        filter_version: filter.version().to_owned(),
+        unrecoverable_threshold: threshold,
+        examples: examples.len() as u64,
+        accuracy: ratio(matrix.true_positives + matrix.true_negatives, examples.len() as u64),
+        matrix,
+        precision,
+        recall,
+        f1,
+        false_positive_terms: top_terms(false_positive_terms, top),
+        false_negative_terms: top_terms(false_negative_terms, top),
+    }
+}
+
+///Prints a list of term counts, or a placeholder if there are none.
+fn print_terms(title: &str, terms: &[TermCount]) {
+    println!("{}", title);
+    if terms.is_empty() {
+        println!("  (none)");
+    }
+    for term in terms {
+        println!("  {:>6}  {}: {}", term.examples, term.category, term.term);
+    }
+}
+
+impl Report {
+    ///Prints the report as a table for reading in a terminal.
+    pub fn print(&self) {
+        let m = &self.matrix;
+        println!("Evaluated {} examples with filter version {} and unrecoverable threshold {}.", self.examples, self.filter_version, self.unrecoverable_threshold);
+        println!();
+        println!("{:>16} {:>17} {:>15}", "", "predicted unsafe", "predicted safe");
+        println!("{:>16} {:>17} {:>15}", "actually unsafe", m.true_positives, m.false_negatives);
+        println!("{:>16} {:>17} {:>15}", "actually safe", m.false_positives, m.true_negatives);
+        println!();
+        println!("precision {:.3}  recall {:.3}  F1 {:.3}  accuracy {:.3}", self.precision, self.recall, self.f1, self.accuracy);
+        println!();
+        print_terms("Top terms blocking safe examples (false positives):", &self.false_positive_terms);
+        print_terms("Top terms found in missed unsafe examples (false negatives):", &self.false_negative_terms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter_rules::RuleSet;
+
+    fn terms(terms: &[TermCount]) -> Vec<(&str, &str, u64)> {
+        terms.iter().map(|t| (t.category.as_str(), t.term.as_str(), t.examples)).collect()
+    }
+
+    #[test]
+    fn evaluate_scores_predictions_against_labels() {
+        let filter = Filter::compile(RuleSet::parse("[politics threshold=2]\nvote\nelection\n[sport]\ngoal\n").unwrap());
+        let examples = vec![
+            LabelledExample::new(None, "vote in the election", &[], Label::Unsafe),
+            LabelledExample::new(None, "what a goal", &[], Label::Safe),
+            LabelledExample::new(None, "", &["the goal", "a cat"], Label::Safe),
+            LabelledExample::new(None, "a cat", &["a dog"], Label::Safe),
+            LabelledExample::new(None, "just vote", &[], Label::Unsafe),
+            //An image label is judged on that image alone, where one politics term doesn't reach the threshold.
+            LabelledExample::new(Some("0"), "", &["vote here"], Label::Unsafe),
+            LabelledExample::new(None, "the election", &[], Label::Unsafe),
+        ];
+        let report = evaluate(&filter, 0.5, &examples, 1);
+        let m = &report.matrix;
+        assert_eq!((m.true_positives, m.false_positives, m.true_negatives, m.false_negatives), (1, 2, 1, 3));
+        assert_eq!(report.examples, 7);
+        assert!((report.precision - 1.0 / 3.0).abs() < 1e-9);
+        assert!((report.recall - 0.25).abs() < 1e-9);
+        assert!((report.f1 - 2.0 / 7.0).abs() < 1e-9);
+        assert!((report.accuracy - 2.0 / 7.0).abs() < 1e-9);
+        assert_eq!(terms(&report.false_positive_terms), vec![("sport", "goal", 2)]);
+        //Only the most frequent of the terms found in missed examples is kept.
+        assert_eq!(terms(&report.false_negative_terms), vec![("politics", "vote", 2)]);
+        assert_eq!(terms(&evaluate(&filter, 0.5, &examples, 5).false_negative_terms), vec![("politics", "vote", 2), ("politics", "election", 1)]);
+    }
+
+    #[test]
+    fn evaluate_scores_empty_denominators_as_zero() {
+        let filter = Filter::compile(RuleSet::parse("[politics]\nvote\n").unwrap());
+        let report = evaluate(&filter, 0.5, &[LabelledExample::new(None, "a cat", &[], Label::Safe)], 10);
+        assert_eq!((report.precision, report.recall, report.f1, report.accuracy), (0.0, 0.0, 0.0, 1.0));
+        let report = evaluate(&filter, 0.5, &[], 10);
+        assert_eq!((report.examples, report.precision, report.recall, report.f1, report.accuracy), (0, 0.0, 0.0, 0.0, 0.0));
+        assert!(report.false_positive_terms.is_empty() && report.false_negative_terms.is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+//!This module records corrections sent by users when a verdict was wrong, applies them to the posts that user is served, and exports them as labelled training data.
+
+//Imports
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use crate::mongo_db_interface::Post;
+use crate::post_store::Store;
+use crate::verdict;
+
+///How many feedback entries are read from the database at a time when exporting.
+const BATCH_SIZE: usize = 100;
+
+///The verdict a user says a post or image should have had.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    ///Not political, it should have been shown.
+    Safe,
+    ///Political, it should have been hidden.
+    Unsafe,
+}
+
+///The body of a `POST /feedback` request.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FeedbackRequest {
+    pub post_id: String,
+    ///Set when the correction is for a single image rather than the whole post.
+    #[serde(default)]
+    pub image_id: Option<String>,
+    pub user: String,
+    pub label: Label,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+///A correction as stored in the database. A user has at most one correction for each post, and for each image of a post.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Feedback {
+    pub post_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+    pub user: String,
+    pub label: Label,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    ///When the correction was made, in milliseconds since the unix epoch.
+    pub created: String,
+}
+
+///One labelled example of training data, with the text the filter saw and the verdict a user gave it.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LabelledExample {
+    pub post_id: String,
+    ///Set when the label is for a single image, in which case only that image's text is included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+    pub title: String,
+    pub description: String,
+    ///The OCR text of the labelled image, or of every image in the post joined by blank lines.
+    #[serde(default)]
+    pub ocr_text: String,
+    ///The OCR text of each image separately, so the image threshold can be applied. Hand written examples may leave this out and give only `ocr_text`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    pub label: Label,
+}
+
+///Checks a user id is a short opaque token, as generated by the extension.
+pub fn is_valid_user_id(user: &str) -> bool {
+    !user.is_empty() && user.len() <= 64 && user.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl FeedbackRequest {
+    ///Checks the request refers to a stored post and image, returning the feedback to store.
+    pub fn validate(self, post: &Post, created: String) -> Result<Feedback, String> {
+        if !is_valid_user_id(&self.user) {
+            return Err("Invalid user id".to_owned());
+        }
+        if let Some(image_id) = &self.image_id {
+            if !post.images.iter().any(|image| &image.id == image_id) {
+                return Err(format!("Post {} has no image {}", post.id, image_id));
+            }
+        }
+        if self.comment.as_ref().is_some_and(|comment| comment.chars().count() > 1000) {
+            return Err("Comment must be at most 1000 characters".to_owned());
+        }
+        Ok(Feedback {
+            post_id: self.post_id,
+            image_id: self.image_id,
+            user: self.user,
+            label: self.label,
+            comment: self.comment,
+            created,
+        })
+    }
+}
+
+///Applies a user's corrections to a post before it is returned to them. Image corrections change the image and the post is judged again from its images, unless the user also corrected the post itself.
+pub fn apply(post: &mut Post, feedback: &[Feedback], unrecoverable_threshold: f32) {
+    let feedback: Vec<Feedback> = feedback.iter().filter(|f| f.post_id == post.id).cloned().collect();
+    let mut images_changed = false;
+    for correction in &feedback {
+        if let Some(image_id) = &correction.image_id {
+            if let Some(image) = post.images.iter_mut().find(|image| &image.id == image_id) {
+                image.unrecoverable = Some(correction.label == Label::Unsafe);
+                images_changed = true;
+            }
+        }
+    }
+    match feedback.iter().find(|f| f.image_id.is_none()) {
+        Some(correction) => post.unrecoverable = Some(correction.label == Label::Unsafe),
+        None if images_changed => {
+            post.unrecoverable = Some(!post.reasons.is_empty() || verdict::images_unsafe(&post.images, unrecoverable_threshold));
+        },
+        None => {},
+    }
+    post.feedback = feedback;
+}
+
+///Builds a labelled example from a stored post, including only the text of one image when an image id is given.
+pub fn labelled_example(post: &Post, image_id: Option<&str>, label: Label) -> LabelledExample {
+    //Unscanned videos are left out like they are when the post is judged.
+    let images: Vec<String> = post.images.iter()
+        .filter(|image| image_id.is_none_or(|id| image.id == id))
+        .filter(|image| verdict::is_scanned(image))
+        .map(|image| image.image_ocr_text.clone().unwrap_or_default())
+        .collect();
+    let ocr_text = images.iter()
+        .filter(|text| !text.trim().is_empty())
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    LabelledExample {
+        post_id: post.id.clone(),
+        image_id: image_id.map(str::to_owned),
+        title: post.title.clone().unwrap_or_default(),
+        description: post.description.clone().unwrap_or_default(),
+        ocr_text,
+        images,
+        label,
+    }
+}
+
+#[cfg(test)]
+impl LabelledExample {
+    ///An example of post "post" with no description, for tests.
+    pub fn new(image_id: Option<&str>, title: &str, images: &[&str], label: Label) -> LabelledExample {
+        LabelledExample {
+            post_id: "post".to_owned(),
+            image_id: image_id.map(str::to_owned),
+            title: title.to_owned(),
+            description: String::new(),
+            ocr_text: images.join("\n\n"),
+            images: images.iter().map(|text| text.to_string()).collect(),
+            label,
+        }
+    }
+}
+
+///Exports every correction as a labelled example. Corrections for posts which are no longer stored are skipped, as their text is gone.
+pub async fn export(db: &Store) -> Result<Vec<LabelledExample>> {
+    let mut examples = vec![];
+    let mut skip = 0;
+    //Feedback is listed by post id, so each post only needs fetching once.
+    let mut current: Option<(String, Option<Post>)> = None;
+    loop {
+        let batch = db.list_feedback(skip, BATCH_SIZE).await?;
+        if batch.is_empty() {
+            break;
+        }
+        skip += batch.len();
+        for feedback in batch {
+            if current.as_ref().map(|(id, _)| id) != Some(&feedback.post_id) {
+                current = Some((feedback.post_id.clone(), db.get_post(&feedback.post_id).await?));
+            }
+            if let Some((_, Some(post))) = &current {
+                examples.push(labelled_example(post, feedback.image_id.as_deref(), feedback.label));
+            }
+        }
+    }
+    Ok(examples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::memory_db_interface::MemoryStore;
+    use crate::mongo_db_interface::{Image, Reason, ReasonSource};
+
+    fn feedback(post_id: &str, user: &str, image_id: Option<&str>, label: Label) -> Feedback {
+        Feedback {
+            post_id: post_id.to_owned(),
+            image_id: image_id.map(str::to_owned),
+            user: user.to_owned(),
+            label,
+            comment: None,
+            created: "0".to_owned(),
+        }
+    }
+
+    fn request(user: &str, image_id: Option<&str>, comment: Option<String>) -> FeedbackRequest {
+        FeedbackRequest { post_id: "p".to_owned(), image_id: image_id.map(str::to_owned), user: user.to_owned(), label: Label::Safe, comment }
+    }
+
+    #[test]
+    fn validate_checks_the_user_image_and_comment() {
+        let stored = Post::titled("p", "title", vec![Image::scanned("a", "", Some(false))]);
+        let valid = request("user-1", Some("a"), Some("not political".to_owned())).validate(&stored, "5".to_owned()).unwrap();
+        assert_eq!(valid, Feedback { created: "5".to_owned(), comment: Some("not political".to_owned()), ..feedback("p", "user-1", Some("a"), Label::Safe) });
+        assert_eq!(request("user 1", None, None).validate(&stored, "5".to_owned()).unwrap_err(), "Invalid user id");
+        assert_eq!(request(&"u".repeat(65), None, None).validate(&stored, "5".to_owned()).unwrap_err(), "Invalid user id");
+        assert_eq!(request("user", Some("b"), None).validate(&stored, "5".to_owned()).unwrap_err(), "Post p has no image b");
+        assert!(request("user", None, Some("é".repeat(1000))).validate(&stored, "5".to_owned()).is_ok());
+        assert!(request("user", None, Some("é".repeat(1001))).validate(&stored, "5".to_owned()).is_err());
+    }
+
+    #[test]
+    fn apply_corrects_images_then_the_post() {
+        let judged = || Post { unrecoverable: Some(true), ..Post::titled("p", "title", vec![Image::scanned("a", "vote", Some(true)), Image::scanned("b", "", Some(false))]) };
+        //An image correction judges the post again from its images.
+        let mut corrected = judged();
+        apply(&mut corrected, &[feedback("p", "user", Some("a"), Label::Safe)], 0.5);
+        assert_eq!((corrected.images[0].unrecoverable, corrected.unrecoverable), (Some(false), Some(false)));
+        assert_eq!(corrected.feedback.len(), 1);
+        //Unless the title already hides it.
+        let mut corrected = Post { reasons: vec![Reason { source: ReasonSource::Title, category: "politics".to_owned(), term: "vote".to_owned(), start: 0, end: 4 }], ..judged() };
+        apply(&mut corrected, &[feedback("p", "user", Some("a"), Label::Safe)], 0.5);
+        assert_eq!(corrected.unrecoverable, Some(true));
+        //A correction of the post itself wins over its images.
+        let mut corrected = judged();
+        apply(&mut corrected, &[feedback("p", "user", Some("b"), Label::Unsafe), feedback("p", "user", None, Label::Safe)], 0.5);
+        assert_eq!((corrected.images[1].unrecoverable, corrected.unrecoverable), (Some(true), Some(false)));
+        //Corrections of other posts and unknown images change nothing.
+        let mut corrected = judged();
+        apply(&mut corrected, &[feedback("q", "user", None, Label::Safe), feedback("p", "user", Some("z"), Label::Safe)], 0.5);
+        assert_eq!(corrected.unrecoverable, Some(true));
+        assert_eq!(corrected.feedback.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn export_labels_stored_posts_and_skips_missing_ones() {
+        let db: Store = Arc::new(MemoryStore::new());
+        db.upload_post(Post::titled("p", "title", vec![Image::scanned("a", "first", Some(true)), Image::scanned("b", "", Some(false)), Image::scanned("c", "third", Some(false))])).await.unwrap();
+        db.add_feedback(feedback("p", "user", None, Label::Unsafe)).await.unwrap();
+        db.add_feedback(feedback("p", "other", Some("c"), Label::Safe)).await.unwrap();
+        db.add_feedback(feedback("gone", "user", None, Label::Safe)).await.unwrap();
+        let mut examples = export(&db).await.unwrap();
+        examples.sort_by(|a, b| a.image_id.cmp(&b.image_id));
+        assert_eq!(examples.len(), 2);
+        assert_eq!((examples[0].image_id.as_deref(), examples[0].label), (None, Label::Unsafe));
+        assert_eq!(examples[0].images, vec!["first", "", "third"]);
+        assert_eq!(examples[0].ocr_text, "first\n\nthird");
+        assert_eq!((examples[1].image_id.as_deref(), examples[1].label), (Some("c"), Label::Safe));
+        assert_eq!((examples[1].title.as_str(), examples[1].ocr_text.as_str()), ("title", "third"));
+    }
+}
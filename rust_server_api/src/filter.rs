@@ -1,40 +1,725 @@
-///This module is designed to check if forbidden words exist in a given string.
+//!This module is designed to score a given string against categories of forbidden words.
 
 //Imports
 use std::{
-    fs::File,
-    io::{prelude::*, BufReader},
-    path::Path,
+    collections::HashMap,
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, RwLock, mpsc},
+    thread,
+    time::Duration,
 };
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use whatlang::{Detector, Lang};
+use anyhow::Context;
+use crate::classifier::{self, Classifier};
+use crate::config::{ClassifierMode, FilterConfig};
+use regex::Regex;
+use crate::filter_rules::{Category, Pattern, Rule, RuleSet, Token, tokenize, version_of};
+use crate::filter_normalise::{MatchOptions, collapse_spaced, leet_letter, stem, word_matches};
 
-///This struct holds a vector of forbidden words, along with methods for scanning.
+///This struct holds the parsed rule set compiled for scanning, along with methods for scanning.
 pub struct Filter {
-    words: Vec<String>
+    rules: RuleSet,
+    ///One automaton per way of normalising the text, which together find every rule that can be matched by exact comparison.
+    matchers: Vec<ViewMatcher>,
+    ///Proximity rules, regex rules and rules using `~fuzzy` cannot be compiled into an automaton, so they are checked one by one.
+    scanned: Vec<RuleRef>,
+    ///Whether any rule uses `~spaced`, so the spaced out letters in the text need joining.
+    spaced: bool,
+    ///The trained classifier, when the config combines one with the rules.
+    classifier: Option<AttachedClassifier>,
+    ///The rule files of other languages, when the config names any.
+    languages: Option<Languages>,
+    ///The rule file version, combined with the versions of the language rule files and the classifier settings.
+    version: String,
+}
+
+///Rule files for text in other languages, checked on top of the main rules.
+struct Languages {
+    ///Detects the language of the checked text, choosing between English and the languages with a rule file.
+    detector: Detector,
+    filters: Vec<(Lang, Filter)>,
+}
+
+///A trained classifier used alongside the rules, with how its verdict is combined with theirs.
+pub struct AttachedClassifier {
+    pub model: Box<dyn Classifier>,
+    pub mode: ClassifierMode,
+    ///The probability from which the classifier judges text political.
+    pub threshold: f32,
+}
+
+///The position of a rule in the rule set.
+#[derive(Clone, Copy, Debug)]
+struct RuleRef {
+    category: usize,
+    rule: usize,
+}
+
+///How the words of the text are normalised before they are searched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct View {
+    unicode: bool,
+    stem: bool,
+    ///Leetspeak is read as letters, so the automaton finds candidates which are then compared letter by letter.
+    leet: bool,
+}
+
+///An automaton matching every compiled rule which shares one view of the text.
+///Words are joined with single spaces and patterns are padded with spaces, so a pattern can only match whole words.
+struct ViewMatcher {
+    view: View,
+    automaton: AhoCorasick,
+    ///The rules behind each pattern of the automaton, as several rules may share one pattern.
+    rules: Vec<Vec<RuleRef>>,
+    ///The number of words in each pattern.
+    lengths: Vec<usize>,
+    ///Whether any of the rules uses `~spaced`.
+    spaced: bool,
+    ///In a leet view, the position of every word of every pattern, keyed by the word's length and first letter. A word masked with `*` can only match rule words with the same key.
+    masked: HashMap<(usize, char), Vec<(usize, usize)>>,
+}
+
+///The character ranges found for every rule, indexed by category then rule.
+type Found = Vec<Vec<Vec<Range<usize>>>>;
+
+///The filter currently in use, shared between every worker. Reloading swaps in a whole new filter, so a check always sees one consistent rule set.
+#[derive(Clone)]
+pub struct SharedFilter {
+    current: Arc<RwLock<Arc<Filter>>>,
+    config: FilterConfig,
+    ///Signalled every time a new filter is swapped in.
+    changed: Arc<watch::Sender<()>>,
+    subscriber: watch::Receiver<()>,
+}
+
+///A term found in the checked text.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FilterMatch {
+    pub category: String,
+    ///The term as written in the rule file.
+    pub term: String,
+    ///Character offsets of the match in the checked text.
+    pub start: usize,
+    pub end: usize,
+}
+
+///The score of a single category for one piece of text.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CategoryScore {
+    pub category: String,
+    pub score: f32,
+    pub threshold: f32,
+}
+
+///The result of checking a piece of text, with one score per category that matched and every term that contributed to it.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct FilterResult {
+    pub categories: Vec<CategoryScore>,
+    pub matches: Vec<FilterMatch>,
+}
+
+impl FilterResult {
+    ///Adds the result of checking the same text against another rule file. Scores of categories with the same name are summed, keeping the threshold already found, so the main rule file's threshold wins over a language file's.
+    fn merge(&mut self, other: FilterResult) {
+        for score in other.categories {
+            match self.categories.iter_mut().find(|c| c.category == score.category) {
+                Some(existing) => existing.score += score.score,
+                None => self.categories.push(score),
+            }
+        }
+        self.matches.extend(other.matches);
+    }
+}
+
+impl Languages {
+    ///Returns the filters to check some text with. When the language is detected reliably only its rules apply, otherwise every language's rules are checked, as short text is often misdetected.
+    fn applicable(&self, input: &str) -> Vec<&Filter> {
+        match self.detector.detect(input) {
+            Some(info) if info.is_reliable() => self.filters.iter()
+                .filter(|(lang, _)| *lang == info.lang())
+                .map(|(_, filter)| filter)
+                .collect(),
+            _ => self.filters.iter().map(|(_, filter)| filter).collect(),
+        }
+    }
+}
+
+///The checked text split into words, along with the same words with spaced out letters joined for rules with the `~spaced` option.
+struct Words<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    collapsed: Vec<Token>,
+}
+
+///Finds every position at which some words appear consecutively in the given tokens, as a range of character offsets.
+fn find_in(words: &[String], options: &MatchOptions, tokens: &[Token]) -> Vec<Range<usize>> {
+    let len = words.len();
+    if tokens.len() < len {
+        return vec![];
+    }
+    (0..=tokens.len() - len)
+        .filter(|&start| words.iter().zip(&tokens[start..start + len]).all(|(a, b)| word_matches(b, a, options)))
+        .map(|start| tokens[start].start..tokens[start + len - 1].end)
+        .collect()
+}
+
+///Finds every match of some words in the text, including matches of spaced out letters when the options allow it.
+fn find_words(words: &[String], options: &MatchOptions, text: &Words) -> Vec<Range<usize>> {
+    let mut found = find_in(words, options, &text.tokens);
+    if options.spaced {
+        for m in find_in(words, options, &text.collapsed) {
+            if !found.contains(&m) {
+                found.push(m);
+            }
+        }
+    }
+    found
+}
+
+///Finds every pair of matches of two terms which are at most `distance` words apart, as a range covering both.
+fn find_near(first: &[Range<usize>], second: &[Range<usize>], distance: usize, tokens: &[Token]) -> Vec<Range<usize>> {
+    //Matches always start and end on word boundaries, so their character offsets can be turned back into word positions.
+    let words_of = |m: &Range<usize>| {
+        let start = tokens.binary_search_by_key(&m.start, |t| t.start).ok()?;
+        let end = tokens.binary_search_by_key(&m.end, |t| t.end).ok()?;
+        Some((start, end))
+    };
+    let mut found = vec![];
+    for a in first {
+        for b in second {
+            let (a_words, b_words) = match (words_of(a), words_of(b)) {
+                (Some(a_words), Some(b_words)) => (a_words, b_words),
+                _ => continue,
+            };
+            let apart = if a_words.1 < b_words.0 {
+                b_words.0 - a_words.1
+            } else if b_words.1 < a_words.0 {
+                a_words.0 - b_words.1
+            } else {
+                //The terms overlap, so they are not two separate mentions.
+                continue;
+            };
+            let range = a.start.min(b.start)..a.end.max(b.end);
+            if apart <= distance && !found.contains(&range) {
+                found.push(range);
+            }
+        }
+    }
+    found
+}
+
+///Finds every match of a regex, converting its byte offsets into character offsets.
+fn find_regex(regex: &Regex, input: &str) -> Vec<Range<usize>> {
+    let mut found = vec![];
+    //Matches are found in order, so the character count can be carried forward from the previous match.
+    let (mut byte, mut chars) = (0, 0);
+    for m in regex.find_iter(input) {
+        chars += input[byte..m.start()].chars().count();
+        let start = chars;
+        chars += m.as_str().chars().count();
+        byte = m.end();
+        found.push(start..chars);
+    }
+    found
+}
+
+///Finds every match of a rule in the text.
+fn find_rule(rule: &Rule, text: &Words) -> Vec<Range<usize>> {
+    match &rule.pattern {
+        Pattern::Words(words) => find_words(words, &rule.options, text),
+        Pattern::Near { first, second, distance } => {
+            let first = find_words(first, &rule.options, text);
+            if first.is_empty() {
+                return vec![];
+            }
+            find_near(&first, &find_words(second, &rule.options, text), *distance, &text.tokens)
+        },
+        Pattern::Regex(regex) => find_regex(regex, text.input),
+    }
+}
+
+impl View {
+    ///Appends a word of the text to a haystack, normalised for this view.
+    fn push_word(&self, haystack: &mut String, token: &Token) {
+        let text = if self.unicode { &token.folded } else { &token.text };
+        let stemmed;
+        let text = if self.stem {
+            stemmed = stem(text);
+            &stemmed
+        } else {
+            text
+        };
+        if self.leet {
+            haystack.extend(text.chars().map(leet_letter));
+        } else {
+            haystack.push_str(text);
+        }
+    }
+}
+
+///Returns true if some words of the text match every word of a rule, compared with the rule's options.
+fn words_match(tokens: &[Token], words: &[String], options: &MatchOptions) -> bool {
+    tokens.len() == words.len() && tokens.iter().zip(words).all(|(token, word)| word_matches(token, word, options))
+}
+
+impl ViewMatcher {
+    ///Compiles the rules which use this view into an automaton.
+    ///Every rule must be a plain term.
+    fn new(view: View, rule_set: &RuleSet, refs: Vec<RuleRef>) -> ViewMatcher {
+        let mut patterns: Vec<String> = vec![];
+        let mut rules: Vec<Vec<RuleRef>> = vec![];
+        let mut lengths = vec![];
+        let mut index: HashMap<String, usize> = HashMap::new();
+        let mut spaced = false;
+        let mut masked: HashMap<(usize, char), Vec<(usize, usize)>> = HashMap::new();
+        for r in refs {
+            spaced |= rule_set.categories[r.category].rules[r.rule].options.spaced;
+            let words = rule_words(rule_set, r);
+            let words: Vec<String> = if view.leet { words.iter().map(|word| word.chars().map(leet_letter).collect()).collect() } else { words.to_vec() };
+            let pattern = format!(" {} ", words.join(" "));
+            let i = match index.get(&pattern) {
+                Some(&i) => i,
+                None => {
+                    if view.leet {
+                        for (position, word) in words.iter().enumerate() {
+                            masked.entry(masked_key(word)).or_default().push((patterns.len(), position));
+                        }
+                    }
+                    index.insert(pattern.clone(), patterns.len());
+                    patterns.push(pattern);
+                    rules.push(vec![]);
+                    lengths.push(words.len());
+                    patterns.len() - 1
+                },
+            };
+            rules[i].push(r);
+        }
+        let automaton = AhoCorasickBuilder::new().dfa(true).build(&patterns);
+        ViewMatcher { view, automaton, rules, lengths, spaced, masked }
+    }
+    ///Searches the text, adding every match to `found`.
+    fn search(&self, rule_set: &RuleSet, words: &Words, found: &mut Found) {
+        self.search_in(rule_set, &words.tokens, false, found);
+        if self.spaced {
+            self.search_in(rule_set, &words.collapsed, true, found);
+        }
+    }
+    ///Searches one stream of words. When searching the joined spaced out letters only `~spaced` rules are kept.
+    fn search_in(&self, rule_set: &RuleSet, tokens: &[Token], spaced_only: bool, found: &mut Found) {
+        //The byte offset of the space before each word, used to turn a match back into word positions.
+        let mut starts = Vec::with_capacity(tokens.len());
+        let mut haystack = String::from(" ");
+        for token in tokens {
+            starts.push(haystack.len() - 1);
+            self.view.push_word(&mut haystack, token);
+            haystack.push(' ');
+        }
+        for m in self.automaton.find_overlapping_iter(&haystack) {
+            let first = match starts.binary_search(&m.start()) {
+                Ok(first) => first,
+                Err(_) => continue,
+            };
+            let words = &tokens[first..first + self.lengths[m.pattern()]];
+            for r in &self.rules[m.pattern()] {
+                //Folding leetspeak loses which letter a digit stood for, so the words are compared again with the rule's own options.
+                if !self.view.leet || words_match(words, rule_words(rule_set, *r), &rule_set.categories[r.category].rules[r.rule].options) {
+                    add_match(rule_set, *r, words, spaced_only, found);
+                }
+            }
+        }
+        if self.view.leet {
+            self.search_masked(rule_set, tokens, spaced_only, found);
+        }
+    }
+    ///Compares the rules with the words around each word masked with `*`. It stands for any letter, which the automaton can't express, but masked words are rare.
+    fn search_masked(&self, rule_set: &RuleSet, tokens: &[Token], spaced_only: bool, found: &mut Found) {
+        for (masked, token) in tokens.iter().enumerate().filter(|(_, token)| token.text.contains('*')) {
+            let mut word = String::new();
+            self.view.push_word(&mut word, token);
+            for &(pattern, position) in self.masked.get(&masked_key(&word)).into_iter().flatten() {
+                let window = match masked.checked_sub(position).and_then(|start| tokens.get(start..start + self.lengths[pattern])) {
+                    Some(window) => window,
+                    None => continue,
+                };
+                for r in &self.rules[pattern] {
+                    if words_match(window, rule_words(rule_set, *r), &rule_set.categories[r.category].rules[r.rule].options) {
+                        add_match(rule_set, *r, window, spaced_only, found);
+                    }
+                }
+            }
+        }
+    }
+}
+
+///The key a word of a leet view is indexed by for matching masked words: its length and first letter. The tokenizer never starts a word with `*`.
+fn masked_key(word: &str) -> (usize, char) {
+    (word.chars().count(), word.chars().next().unwrap_or(' '))
+}
+
+///Returns the words of a compiled rule, which is always a plain term.
+fn rule_words(rule_set: &RuleSet, r: RuleRef) -> &[String] {
+    match &rule_set.categories[r.category].rules[r.rule].pattern {
+        Pattern::Words(words) => words,
+        _ => unreachable!("only plain terms are compiled"),
+    }
+}
+
+///Records a match of a rule over some words of the text. When searching the joined spaced out letters, only `~spaced` rules are kept, and matches already found in the plain words are skipped.
+fn add_match(rule_set: &RuleSet, r: RuleRef, words: &[Token], spaced_only: bool, found: &mut Found) {
+    let range = words[0].start..words[words.len() - 1].end;
+    let ranges = &mut found[r.category][r.rule];
+    if ranges.contains(&range) || (spaced_only && !rule_set.categories[r.category].rules[r.rule].options.spaced) {
+        return;
+    }
+    ranges.push(range);
+}
+
+///Scores one category from the matches found for each of its rules. Each term counts once however often it appears, and matches overlapping an allow term are ignored.
+fn score_category(category: &Category, found: Vec<Vec<Range<usize>>>, matches: &mut Vec<FilterMatch>) -> f32 {
+    let allowed: Vec<Range<usize>> = category.rules.iter().zip(&found)
+        .filter(|(rule, _)| rule.allow)
+        .flat_map(|(_, ranges)| ranges.iter().cloned())
+        .collect();
+    let mut score = 0.0;
+    for (rule, ranges) in category.rules.iter().zip(found).filter(|(rule, _)| !rule.allow) {
+        let ranges: Vec<Range<usize>> = ranges.into_iter()
+            .filter(|m| !allowed.iter().any(|a| m.start < a.end && a.start < m.end))
+            .collect();
+        if ranges.is_empty() {
+            continue;
+        }
+        score += rule.weight;
+        matches.extend(ranges.into_iter().map(|m| FilterMatch {
+            category: category.name.clone(),
+            term: rule.term.clone(),
+            start: m.start,
+            end: m.end,
+        }));
+    }
+    score
 }
 
 impl Filter {
-    ///Creates a new filter struct.
-    pub fn new(filename: impl AsRef<Path>) -> Result<Filter, anyhow::Error> {
-        //Open and read the given input file, loading the list of forbidden words.
-        let file = File::open(filename).expect("no such file");
-        let buf = BufReader::new(file);
-        let words = buf.lines()
-            .map(|l| l.expect("Could not parse line"))
+    ///Creates a new filter struct from the rule file named in the config, along with the classifier model if the config uses one.
+    pub fn new(config: &FilterConfig) -> Result<Filter, anyhow::Error> {
+        let mut filter = Filter::compile(RuleSet::load(&config.word_list)?);
+        if !config.languages.is_empty() {
+            let mut languages = vec![];
+            for (code, path) in &config.languages {
+                let lang = Lang::from_code(code)
+                    .with_context(|| format!("filter.languages has unknown language code {}", code))?;
+                languages.push((lang, Filter::compile(RuleSet::load(path)?)));
+            }
+            filter = filter.with_languages(languages);
+        }
+        if config.classifier_mode == ClassifierMode::Keywords {
+            return Ok(filter);
+        }
+        let path = config.classifier_model.as_ref()
+            .context("filter.classifier_model must be set when filter.classifier_mode is not keywords")?;
+        Ok(filter.with_classifier(classifier::load(path)?, config.classifier_mode, config.classifier_threshold))
+    }
+    ///Attaches rule files for other languages. The version then changes with any of them, so stored posts are rescored when a language's rules change.
+    pub fn with_languages(self, filters: Vec<(Lang, Filter)>) -> Filter {
+        let versions: Vec<String> = filters.iter().map(|(lang, filter)| format!("{}:{}", lang.code(), filter.version)).collect();
+        let version = version_of(&format!("{} {}", self.version, versions.join(" ")));
+        let mut whitelist: Vec<Lang> = filters.iter().map(|(lang, _)| *lang).collect();
+        if !whitelist.contains(&Lang::Eng) {
+            whitelist.push(Lang::Eng);
+        }
+        let detector = Detector::with_whitelist(whitelist);
+        Filter { languages: Some(Languages { detector, filters }), version, ..self }
+    }
+    ///Attaches a trained classifier. The version then changes with the model and its settings as well as the rules, so a new model rescores stored posts like new rules do.
+    pub fn with_classifier(self, model: Box<dyn Classifier>, mode: ClassifierMode, threshold: f32) -> Filter {
+        let version = version_of(&format!("{} {} {:?} {}", self.version, model.version(), mode, threshold));
+        Filter { classifier: Some(AttachedClassifier { model, mode, threshold }), version, ..self }
+    }
+    ///Compiles a rule set into automatons. Proximity and regex rules, and rules which need `~fuzzy` comparisons, are left to be checked one by one.
+    pub fn compile(rules: RuleSet) -> Filter {
+        let mut views: Vec<(View, Vec<RuleRef>)> = vec![];
+        let mut scanned = vec![];
+        for (c, category) in rules.categories.iter().enumerate() {
+            for (r, rule) in category.rules.iter().enumerate() {
+                let rule_ref = RuleRef { category: c, rule: r };
+                if !matches!(rule.pattern, Pattern::Words(_)) || rule.options.fuzzy > 0 {
+                    scanned.push(rule_ref);
+                    continue;
+                }
+                let view = View { unicode: rule.options.unicode, stem: rule.options.stem, leet: rule.options.leet };
+                match views.iter_mut().find(|(v, _)| *v == view) {
+                    Some((_, refs)) => refs.push(rule_ref),
+                    None => views.push((view, vec![rule_ref])),
+                }
+            }
+        }
+        let matchers = views.into_iter().map(|(view, refs)| ViewMatcher::new(view, &rules, refs)).collect();
+        let spaced = rules.categories.iter().any(|c| c.rules.iter().any(|r| r.options.spaced));
+        let version = rules.version.clone();
+        Filter { rules, matchers, scanned, spaced, classifier: None, languages: None, version }
+    }
+    ///Builds a filter which checks every rule one by one without any automatons, used as a baseline by the filter benchmark.
+    pub fn uncompiled(rules: RuleSet) -> Filter {
+        let scanned = rules.categories.iter().enumerate()
+            .flat_map(|(c, category)| (0..category.rules.len()).map(move |r| RuleRef { category: c, rule: r }))
             .collect();
-        Ok(Filter{words})
+        let spaced = rules.categories.iter().any(|c| c.rules.iter().any(|r| r.options.spaced));
+        let version = rules.version.clone();
+        Filter { rules, matchers: vec![], scanned, spaced, classifier: None, languages: None, version }
+    }
+    ///Returns the version of the rules and classifier this filter was built from.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+    ///Returns the attached classifier, if the config uses one.
+    pub fn classifier(&self) -> Option<&AttachedClassifier> {
+        self.classifier.as_ref()
+    }
+    ///Returns the number of terms across every category, including those of every language.
+    pub fn rule_count(&self) -> usize {
+        let languages: usize = self.languages.iter()
+            .flat_map(|languages| &languages.filters)
+            .map(|(_, filter)| filter.rule_count())
+            .sum();
+        self.rules.categories.iter().map(|c| c.rules.len()).sum::<usize>() + languages
+    }
+    ///Takes a pointer to a string, and scores it against every category of forbidden words, in the main rule file and the rule file of the language the text is in.
+    pub fn check(&self, input: &str) -> FilterResult {
+        let mut result = self.check_rules(input);
+        if let Some(languages) = &self.languages {
+            for filter in languages.applicable(input) {
+                result.merge(filter.check_rules(input));
+            }
+            //A category the main file defines keeps its threshold even when only a language file scored it.
+            for score in &mut result.categories {
+                if let Some(category) = self.rules.categories.iter().find(|c| c.name == score.category) {
+                    score.threshold = category.threshold;
+                }
+            }
+        }
+        result
     }
-    ///Takes a pointer to a string, and returns a boolean which determines whether or not the input string contains any forbidden words.
-    pub fn is_unsafe(&self, input: &str) -> bool {
-        //Filter the input string, removing puncutation and other non-ascii chars.
-        let input = format!(" {} ", input.to_lowercase().replace(&['(', ')', ',', '\"', '.', ';', ':', '\'', '!', '@', '#', '$', '%', '^', '&', '*', '-', '_', '+', '=', '`', '~', '\n', '\r', '\\', '/', '{', '}', '°', '’', '‘', '>', '<', '»', '¢', '?'][..], " "));
-        //Loop over each word in the input string, checking it against each check word. If we find a match, return true.
-        for word in &self.words {
-            let check_str = format!(" {} ", word);
-            if input.contains(&check_str) {
-                return true;
+    ///Scores a string against the rules of this filter's own rule file.
+    fn check_rules(&self, input: &str) -> FilterResult {
+        //Split the input string into lowercase words, ignoring punctuation.
+        let tokens = tokenize(input);
+        let collapsed = if self.spaced { collapse_spaced(&tokens) } else { vec![] };
+        let words = Words { input, tokens, collapsed };
+
+        let mut found: Found = self.rules.categories.iter().map(|c| vec![vec![]; c.rules.len()]).collect();
+        for matcher in &self.matchers {
+            matcher.search(&self.rules, &words, &mut found);
+        }
+        for r in &self.scanned {
+            found[r.category][r.rule] = find_rule(&self.rules.categories[r.category].rules[r.rule], &words);
+        }
+
+        let mut result = FilterResult::default();
+        for (category, found) in self.rules.categories.iter().zip(found) {
+            let score = score_category(category, found, &mut result.matches);
+            if score != 0.0 {
+                result.categories.push(CategoryScore {
+                    category: category.name.clone(),
+                    score,
+                    threshold: category.threshold,
+                });
             }
         }
-        //By default return false.
-        return false;
+        result
+    }
+}
+
+impl SharedFilter {
+    ///Loads the filter named in the config.
+    pub fn new(config: &FilterConfig) -> Result<SharedFilter, anyhow::Error> {
+        let (changed, subscriber) = watch::channel(());
+        Ok(SharedFilter {
+            current: Arc::new(RwLock::new(Arc::new(Filter::new(config)?))),
+            config: config.clone(),
+            changed: Arc::new(changed),
+            subscriber,
+        })
     }
-}
\ No newline at end of file
+    ///Returns a receiver which yields once immediately, then again whenever the filter is reloaded.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.subscriber.clone()
+    }
+    ///Returns the filter currently in use.
+    pub fn current(&self) -> Arc<Filter> {
+        self.current.read().unwrap().clone()
+    }
+    ///Reloads the rule file from disk. If the new file is invalid the error is returned and the current filter is kept.
+    pub fn reload(&self) -> Result<Arc<Filter>, anyhow::Error> {
+        let filter = Arc::new(Filter::new(&self.config)?);
+        *self.current.write().unwrap() = filter.clone();
+        let _ = self.changed.broadcast(());
+        Ok(filter)
+    }
+    ///Watches the rule file and the language rule files, reloading the filter whenever one changes. The directories are watched rather than the files, so editors which save by replacing the file are picked up.
+    pub fn watch(&self) -> Result<(), anyhow::Error> {
+        let mut paths: Vec<PathBuf> = vec![];
+        for file in std::iter::once(&self.config.word_list).chain(self.config.languages.values()) {
+            paths.push(file.canonicalize().with_context(|| format!("Failed to find rule file {}", file.display()))?);
+        }
+        let mut dirs: Vec<PathBuf> = paths.iter().map(|path| path.parent().map(|dir| dir.to_path_buf()).unwrap_or_default()).collect();
+        dirs.sort();
+        dirs.dedup();
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::watcher(tx, Duration::from_secs(1))?;
+        for dir in &dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        let shared = self.clone();
+        thread::spawn(move || {
+            //The watcher stops when dropped, so keep it alive for the life of the thread.
+            let _watcher = watcher;
+            for event in rx {
+                let changed = match event {
+                    DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Rename(_, p) => paths.iter().find(|path| **path == p).cloned(),
+                    _ => None,
+                };
+                let path = match changed {
+                    Some(path) => path,
+                    None => continue,
+                };
+                match shared.reload() {
+                    Ok(filter) => println!("Reloaded filter rules after {} changed, {} rules loaded.", path.display(), filter.rule_count()),
+                    Err(e) => println!("Rejected changed filter rules, keeping the current filter: {:#}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(contents: &str) -> Filter {
+        Filter::compile(RuleSet::parse(contents).unwrap())
+    }
+
+    fn scores(result: &FilterResult) -> Vec<(&str, f32, f32)> {
+        result.categories.iter().map(|c| (c.category.as_str(), c.score, c.threshold)).collect()
+    }
+
+    #[test]
+    fn scores_each_category_with_weights_and_phrases() {
+        let filter = filter("[politics threshold=2]\nelection = 1.5\nwhite house\n[sport]\ngoal\n");
+        let result = filter.check("The White House called the election, then a goal and another goal.");
+        //Each term counts once however often it appears, though every match is listed.
+        assert_eq!(scores(&result), vec![("politics", 2.5, 2.0), ("sport", 1.0, 1.0)]);
+        assert_eq!(result.matches.len(), 4);
+        //Phrases only match their words in order, and categories without matches are left out.
+        assert_eq!(scores(&filter.check("the house is white")), vec![]);
+        assert_eq!(filter.check("").categories, vec![]);
+    }
+
+    #[test]
+    fn allow_terms_cancel_overlapping_matches() {
+        let filter = filter("[politics]\nvote\ntrump\n!vote of thanks\n!trump card\n");
+        assert_eq!(scores(&filter.check("a vote of thanks to the trump card")), vec![]);
+        //Matches elsewhere in the text still count.
+        let result = filter.check("a vote of thanks, then vote");
+        assert_eq!(scores(&result), vec![("politics", 1.0, 1.0)]);
+        assert_eq!(result.matches.len(), 1);
+    }
+
+    #[test]
+    fn matches_report_the_term_and_character_offsets() {
+        let filter = filter("[politics ~unicode]\nwhite house\n");
+        //Offsets count characters rather than bytes, and cover the words as written in the text.
+        let result = filter.check("Ünïcode: the Whïte  House!");
+        assert_eq!(result.matches, vec![FilterMatch { category: "politics".to_owned(), term: "white house".to_owned(), start: 13, end: 25 }]);
+        let text: String = "Ünïcode: the Whïte  House!".chars().skip(13).take(12).collect();
+        assert_eq!(text, "Whïte  House");
+    }
+
+    #[test]
+    fn compiled_rules_match_like_rules_checked_one_by_one() {
+        let contents = "[politics ~leet ~unicode]\ntrump\nwhite house\nelection ~stem\nballot box ~spaced\n!trump card\n\
+            [plain]\nvote\nvoting ~stem\ngovernor ~fuzzy=1\nmask NEAR/2 mandate\n/vote for \\w+/\n";
+        let compiled = Filter::compile(RuleSet::parse(contents).unwrap());
+        let uncompiled = Filter::uncompiled(RuleSet::parse(contents).unwrap());
+        assert!(!compiled.matchers.is_empty());
+        let texts = [
+            "Trump and the WHITE HOUSE",
+            "tr*mp wh1te h0use, 7rump, the trump card",
+            "elections and 3l3ct1ons, el*ction",
+            "b a l l o t b o x and b4ll0t b0x",
+            "vote, votes, voting, v0te for pedro",
+            "the govenor said mask up, a mandate",
+            "Trümp wrote ｔｒｕｍｐ",
+            "",
+        ];
+        for text in texts.iter() {
+            let sorted = |mut result: FilterResult| {
+                result.matches.sort_by(|a, b| (a.start, a.end, &a.term).cmp(&(b.start, b.end, &b.term)));
+                result
+            };
+            assert_eq!(sorted(compiled.check(text)), sorted(uncompiled.check(text)), "{}", text);
+        }
+        assert_eq!(scores(&compiled.check("tr*mp wh1te h0use, 7rump, the trump card")), vec![("politics", 2.0, 1.0)]);
+    }
+
+    #[test]
+    fn merge_sums_shared_categories_keeping_the_first_threshold() {
+        let score = |category: &str, score: f32, threshold: f32| CategoryScore { category: category.to_owned(), score, threshold };
+        let found = |category: &str, term: &str| FilterMatch { category: category.to_owned(), term: term.to_owned(), start: 0, end: term.len() };
+        let mut result = FilterResult { categories: vec![score("politics", 1.0, 2.0)], matches: vec![found("politics", "vote")] };
+        result.merge(FilterResult { categories: vec![score("politics", 1.5, 5.0), score("sport", 1.0, 1.0)], matches: vec![found("politics", "votar"), found("sport", "gol")] });
+        assert_eq!(scores(&result), vec![("politics", 2.5, 2.0), ("sport", 1.0, 1.0)]);
+        assert_eq!(result.matches.len(), 3);
+    }
+
+    #[test]
+    fn language_rules_apply_to_text_detected_in_that_language() {
+        let spanish = filter("[politics threshold=3]\nelección\n[spanish]\nelection\n");
+        let filter = filter("[politics]\ntrump\nelection\n").with_languages(vec![(Lang::Spa, spanish)]);
+        assert_eq!(filter.rule_count(), 4);
+        //Reliably detected Spanish is checked against both files, with the main file's threshold for the shared category.
+        let result = filter.check("El presidente Trump habló ayer sobre la elección de mañana y pidió a todos los ciudadanos que voten temprano.");
+        assert_eq!(scores(&result), vec![("politics", 2.0, 1.0)]);
+        //Reliably detected English isn't checked against the Spanish rules.
+        let result = filter.check("The president talked about the election tomorrow and asked every citizen to vote early at their polling station.");
+        assert_eq!(scores(&result), vec![("politics", 1.0, 1.0)]);
+        //Text too short to detect is checked against every language.
+        assert_eq!(scores(&filter.check("election")), vec![("politics", 1.0, 1.0), ("spanish", 1.0, 1.0)]);
+    }
+
+    #[test]
+    fn main_file_threshold_applies_when_only_a_language_file_matches() {
+        let spanish = filter("[politics threshold=1]\nelección\n");
+        let filter = filter("[politics threshold=3]\ntrump\n").with_languages(vec![(Lang::Spa, spanish)]);
+        let result = filter.check("El presidente habló ayer sobre la elección de mañana y pidió a todos los ciudadanos que voten temprano.");
+        assert_eq!(scores(&result), vec![("politics", 1.0, 3.0)]);
+    }
+
+    #[test]
+    fn reload_keeps_the_current_rules_when_the_file_is_invalid() {
+        let path = std::env::temp_dir().join(format!("reload_test_{}.txt", std::process::id()));
+        std::fs::write(&path, "[politics]\nelection\n").unwrap();
+        let shared = SharedFilter::new(&FilterConfig { word_list: path.clone(), ..FilterConfig::default() }).unwrap();
+        let first = shared.current().version().to_owned();
+
+        std::fs::write(&path, "[politics]\nvote\n").unwrap();
+        shared.reload().unwrap();
+        assert_ne!(shared.current().version(), first);
+        assert_eq!(scores(&shared.current().check("vote")), vec![("politics", 1.0, 1.0)]);
+
+        std::fs::write(&path, "[politics\nelection\n").unwrap();
+        match shared.reload() {
+            Ok(_) => panic!("an invalid rule file was loaded"),
+            Err(e) => assert!(format!("{:#}", e).contains("line 1"), "{:#}", e),
+        }
+        assert_eq!(scores(&shared.current().check("vote")), vec![("politics", 1.0, 1.0)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//!This module holds the optional normalisation steps used to catch obfuscated words: leetspeak, look-alike unicode, plurals, spaced out letters and OCR misreads.
+
+//Imports
+use unicode_normalization::UnicodeNormalization;
+use crate::filter_rules::Token;
+
+///Characters kept inside a word rather than treated as punctuation, because they are commonly used to mask letters ("tr*mp", "el@ction").
+pub const MASK_CHARS: &[char] = &['*', '@', '$'];
+///Fuzzy matching is only applied to words at least this long, so short words like "us" do not match everything.
+const MIN_FUZZY_LENGTH: usize = 4;
+///At least this many single letters in a row are joined back into a word ("f a u c i").
+const MIN_SPACED_LETTERS: usize = 3;
+
+///Which normalisation steps a rule uses when it is matched.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchOptions {
+    ///Digits and symbols stand in for the letters they resemble, and `*` stands in for any letter.
+    pub leet: bool,
+    ///Accents are removed and look-alike letters from other scripts are treated as latin.
+    pub unicode: bool,
+    ///Plurals are reduced to their singular.
+    pub stem: bool,
+    ///Runs of single letters separated by spaces are joined into words.
+    pub spaced: bool,
+    ///The number of letters which may differ per word.
+    pub fuzzy: u8,
+}
+
+impl MatchOptions {
+    ///Returns true if no normalisation is enabled.
+    pub fn is_exact(&self) -> bool {
+        *self == MatchOptions::default()
+    }
+}
+
+///Maps letters from other scripts which look identical to a latin letter.
+fn confusable(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'е' | 'ε' => 'e',
+        'н' | 'η' => 'h',
+        'і' | 'ι' | 'ı' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'п' => 'n',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ш' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        _ => c,
+    }
+}
+
+///Removes accents and replaces look-alike letters, so "électión" and "еlection" (with a cyrillic е) read as "election".
+pub fn fold_unicode(word: &str) -> String {
+    if word.is_ascii() {
+        return word.to_owned();
+    }
+    word.nfkd()
+        .filter(|c| !('\u{300}'..='\u{36f}').contains(c))
+        .map(confusable)
+        .collect()
+}
+
+///Reduces an english plural to its singular form. This is deliberately simple, it only needs to make "elections" and "election" agree.
+pub fn stem(word: &str) -> String {
+    let len = word.chars().count();
+    if len > 4 && word.ends_with("ies") {
+        return format!("{}y", &word[..word.len() - 3]);
+    }
+    if word.ends_with("sses") || word.ends_with("xes") || word.ends_with("ches") || word.ends_with("shes") {
+        return word[..word.len() - 2].to_owned();
+    }
+    if len > 3 && word.ends_with('s') && !word.ends_with("ss") && !word.ends_with("us") && !word.ends_with("is") {
+        return word[..word.len() - 1].to_owned();
+    }
+    word.to_owned()
+}
+
+///Returns true if a character of the text can stand for a letter of a rule, allowing for leetspeak when enabled.
+fn char_matches(text: char, rule: char, leet: bool) -> bool {
+    if text == rule {
+        return true;
+    }
+    if !leet {
+        return false;
+    }
+    match text {
+        '*' => true,
+        '4' | '@' => rule == 'a',
+        '8' => rule == 'b',
+        '3' => rule == 'e',
+        '6' | '9' => rule == 'g',
+        '1' | '!' | '|' => rule == 'i' || rule == 'l',
+        '0' => rule == 'o',
+        '5' | '$' => rule == 's',
+        '7' | '+' => rule == 't',
+        '2' => rule == 'z',
+        _ => false,
+    }
+}
+
+///Returns the letter `~leet` reads a character as, so text and rules can be compared as plain strings.
+///`1`, `!` and `|` can stand for both i and l, so l is read as i too. Words which agree after this only match if `word_matches` agrees, and `*` is kept as it stands for any letter.
+pub fn leet_letter(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '8' => 'b',
+        '3' => 'e',
+        '6' | '9' => 'g',
+        '1' | '!' | '|' | 'l' => 'i',
+        '0' => 'o',
+        '5' | '$' => 's',
+        '7' | '+' => 't',
+        '2' => 'z',
+        _ => c,
+    }
+}
+
+///Computes whether the edit distance between two words is at most `max`, treating leetspeak substitutions as equal when enabled.
+fn within_distance(text: &[char], rule: &[char], max: usize, leet: bool) -> bool {
+    if (text.len() as isize - rule.len() as isize).unsigned_abs() > max {
+        return false;
+    }
+    let mut previous: Vec<usize> = (0..=rule.len()).collect();
+    for (i, &t) in text.iter().enumerate() {
+        let mut current = vec![i + 1; rule.len() + 1];
+        for (j, &r) in rule.iter().enumerate() {
+            let substitution = previous[j] + if char_matches(t, r, leet) { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        //Every path through the rest of the table passes through this row, so if it is all over the limit the words are too far apart.
+        if current.iter().all(|&d| d > max) {
+            return false;
+        }
+        previous = current;
+    }
+    previous[rule.len()] <= max
+}
+
+///Prepares a word of a rule for matching with the given options.
+pub fn prepare_rule_word(word: &str, options: &MatchOptions) -> String {
+    let word = if options.unicode { fold_unicode(word) } else { word.to_owned() };
+    if options.stem { stem(&word) } else { word }
+}
+
+///Returns true if a word of the text matches a prepared word of a rule.
+pub fn word_matches(token: &Token, rule_word: &str, options: &MatchOptions) -> bool {
+    if options.is_exact() {
+        return token.text == rule_word;
+    }
+    let text = if options.unicode { &token.folded } else { &token.text };
+    let text = if options.stem { stem(text) } else { text.clone() };
+    let text: Vec<char> = text.chars().collect();
+    let rule: Vec<char> = rule_word.chars().collect();
+    if options.fuzzy > 0 && rule.len() >= MIN_FUZZY_LENGTH {
+        return within_distance(&text, &rule, options.fuzzy as usize, options.leet);
+    }
+    text.len() == rule.len() && text.iter().zip(&rule).all(|(&t, &r)| char_matches(t, r, options.leet))
+}
+
+///Joins runs of single letters back into words, so "f a u c i" becomes "fauci". Other words are kept as they are.
+pub fn collapse_spaced(tokens: &[Token]) -> Vec<Token> {
+    let mut collapsed = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let run = tokens[i..].iter().take_while(|t| t.text.chars().count() == 1).count();
+        if run >= MIN_SPACED_LETTERS {
+            let letters = &tokens[i..i + run];
+            collapsed.push(Token {
+                text: letters.iter().map(|t| t.text.as_str()).collect(),
+                folded: letters.iter().map(|t| t.folded.as_str()).collect(),
+                start: letters[0].start,
+                end: letters[run - 1].end,
+            });
+            i += run;
+        } else {
+            collapsed.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter_rules::tokenize;
+
+    fn chars(word: &str) -> Vec<char> {
+        word.chars().collect()
+    }
+
+    fn matches(text: &str, rule: &str, options: MatchOptions) -> bool {
+        word_matches(&tokenize(text)[0], &prepare_rule_word(rule, &options), &options)
+    }
+
+    #[test]
+    fn stem_reduces_plurals() {
+        assert_eq!(stem("elections"), "election");
+        assert_eq!(stem("parties"), "party");
+        assert_eq!(stem("taxes"), "tax");
+        assert_eq!(stem("churches"), "church");
+        assert_eq!(stem("classes"), "class");
+    }
+
+    #[test]
+    fn stem_keeps_words_which_are_not_plurals() {
+        for word in &["class", "virus", "crisis", "gas", "vote"] {
+            assert_eq!(&stem(word), word);
+        }
+    }
+
+    #[test]
+    fn within_distance_counts_edits() {
+        assert!(within_distance(&chars("election"), &chars("election"), 0, false));
+        assert!(within_distance(&chars("elecfion"), &chars("election"), 1, false));
+        assert!(within_distance(&chars("electon"), &chars("election"), 1, false));
+        assert!(within_distance(&chars("elecctionn"), &chars("election"), 2, false));
+        assert!(!within_distance(&chars("elecfon"), &chars("election"), 1, false));
+        assert!(!within_distance(&chars("elect"), &chars("election"), 2, false));
+    }
+
+    #[test]
+    fn within_distance_reads_leet_as_letters() {
+        assert!(!within_distance(&chars("3l3ction"), &chars("election"), 1, false));
+        assert!(within_distance(&chars("3l3ction"), &chars("election"), 0, true));
+        assert!(within_distance(&chars("3l3ct1om"), &chars("election"), 1, true));
+    }
+
+    #[test]
+    fn fold_unicode_removes_accents_and_look_alikes() {
+        assert_eq!(fold_unicode("électión"), "election");
+        assert_eq!(fold_unicode("\u{435}lection"), "election");
+        assert_eq!(fold_unicode("plain"), "plain");
+    }
+
+    #[test]
+    fn words_match_with_their_options() {
+        let leet = MatchOptions { leet: true, ..MatchOptions::default() };
+        assert!(matches("tr*mp", "trump", leet));
+        assert!(!matches("tr*mp", "trump", MatchOptions::default()));
+        assert!(matches("Elections", "election", MatchOptions { stem: true, ..MatchOptions::default() }));
+        assert!(matches("électión", "election", MatchOptions { unicode: true, ..MatchOptions::default() }));
+        let fuzzy = MatchOptions { fuzzy: 1, ..MatchOptions::default() };
+        assert!(matches("elecfion", "election", fuzzy));
+        //Short words are never matched fuzzily.
+        assert!(!matches("as", "us", fuzzy));
+    }
+
+    #[test]
+    fn collapse_spaced_joins_runs_of_letters() {
+        let collapsed = collapse_spaced(&tokenize("ask f a u c i now a b"));
+        let texts: Vec<&str> = collapsed.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, vec!["ask", "fauci", "now", "a", "b"]);
+        assert_eq!((collapsed[1].start, collapsed[1].end), (4, 13));
+    }
+}
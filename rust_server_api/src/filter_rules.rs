@@ -0,0 +1,446 @@
+//!This module parses the filter rule file into categories of weighted terms.
+//!
+//!The format is line based:
+//!```text
+//! # A comment.
+//! [us_politics threshold=2]   Starts a category. The threshold defaults to 1.
+//! trump                       A term with the default weight of 1.
+//! white house = 2.5           A multi-word phrase with its own weight.
+//! !trump card                 An allow term. Matches of this category which overlap it are ignored.
+//! election ~stem ~fuzzy=1     A term matched with normalisation options, see below.
+//! mask NEAR/3 mandate         A proximity rule, both terms must appear at most 3 words apart in either order.
+//! /vote for \w+/ = 2          A regular expression, matched case-insensitively against the original text.
+//! /and\/or/                    A '/' inside a regular expression is written `\/`.
+//!```
+//!Terms listed before the first category header belong to the `default` category, so a plain word list is still a valid rule file.
+//!
+//!Options catch obfuscated words and can be given on a term, or on a category header to apply to every term in it:
+//!- `~leet` reads digits and symbols as the letters they resemble ("el3ction"), and `*` as any letter ("tr*mp").
+//!- `~unicode` removes accents and reads look-alike letters from other scripts as latin.
+//!- `~stem` reduces plurals to their singular, so "elections" matches "election".
+//!- `~spaced` joins letters separated by spaces, so "f a u c i" matches "fauci".
+//!- `~fuzzy=N` allows up to N letters per word to differ, for OCR misreads. Only words of 4 or more letters are matched fuzzily.
+//!- `~exact` on a term turns off every option inherited from its category.
+//!
+//!Options apply to both sides of a proximity rule. Regular expressions are never normalised, so they cannot be given options and ignore those of their category.
+
+//Imports
+use std::{fs, path::Path};
+use anyhow::{Context, Result, anyhow, bail};
+use regex::Regex;
+use crate::filter_normalise::{MASK_CHARS, MatchOptions, fold_unicode, prepare_rule_word};
+
+///The category used for terms which appear before any header.
+pub const DEFAULT_CATEGORY: &str = "default";
+///The threshold used when a category header does not set one.
+const DEFAULT_THRESHOLD: f32 = 1.0;
+///The weight used when a term does not set one.
+const DEFAULT_WEIGHT: f32 = 1.0;
+
+///Characters which are treated as word breaks, both in rule terms and in the text being filtered.
+const SEPARATORS: &[char] = &['(', ')', ',', '\"', '.', ';', ':', '\'', '!', '@', '#', '$', '%', '^', '&', '*', '-', '_', '+', '=', '`', '~', '\n', '\r', '\\', '/', '{', '}', '°', '’', '‘', '>', '<', '»', '¢', '?'];
+
+///The keyword separating the two terms of a proximity rule, followed by the distance.
+const NEAR: &str = "NEAR/";
+
+///What a rule looks for in the text.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    ///The normalised words of a term, which must appear consecutively in the text.
+    Words(Vec<String>),
+    ///Two terms which must both appear, at most `distance` words apart.
+    Near {
+        first: Vec<String>,
+        second: Vec<String>,
+        distance: usize,
+    },
+    ///A case-insensitive regular expression, matched against the original text.
+    Regex(Regex),
+}
+
+///A single term in a category.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    ///The term as written in the rule file, used when reporting matches.
+    pub term: String,
+    pub pattern: Pattern,
+    pub weight: f32,
+    ///Allow terms never add to the score, they suppress overlapping matches instead.
+    pub allow: bool,
+    pub options: MatchOptions,
+}
+
+///A named group of rules, scored together against its threshold.
+#[derive(Clone, Debug)]
+pub struct Category {
+    pub name: String,
+    pub threshold: f32,
+    ///The options given on the header, used by every rule in the category unless it sets its own.
+    pub options: MatchOptions,
+    pub rules: Vec<Rule>,
+}
+
+///Every category read from a rule file.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    pub categories: Vec<Category>,
+    ///A hash of the rule file contents, stored with each verdict so posts judged by older rules can be found.
+    pub version: String,
+}
+
+///Hashes the contents of a rule or model file with 64-bit FNV-1a, which is stable across builds and platforms.
+pub fn version_of(contents: &str) -> String {
+    let hash = contents.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+///A lowercased word of some text, with its position in that text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub text: String,
+    ///The text with accents removed and look-alike letters replaced, used by rules with the `~unicode` option.
+    pub folded: String,
+    ///Character offsets of the word in the original text.
+    pub start: usize,
+    pub end: usize,
+}
+
+///Splits text into lowercased words, treating whitespace and punctuation as word breaks.
+///Mask characters such as `*` are kept when they sit between letters, so "tr*mp" stays one word for the `~leet` option to match.
+pub fn tokenize(input: &str) -> Vec<Token> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut current: Option<Token> = None;
+    for (i, &c) in chars.iter().enumerate() {
+        let masked = MASK_CHARS.contains(&c)
+            && current.is_some()
+            && chars.get(i + 1).is_some_and(|&next| next.is_alphanumeric() || MASK_CHARS.contains(&next));
+        if !masked && (c.is_whitespace() || SEPARATORS.contains(&c)) {
+            tokens.extend(current.take());
+            continue;
+        }
+        let token = current.get_or_insert_with(|| Token { text: String::new(), folded: String::new(), start: i, end: i });
+        token.text.extend(c.to_lowercase());
+        token.end = i + 1;
+    }
+    tokens.extend(current);
+    for token in &mut tokens {
+        token.folded = fold_unicode(&token.text);
+    }
+    tokens
+}
+
+///Parses a weight or threshold value.
+fn parse_number(value: &str, what: &str) -> Result<f32> {
+    let number: f32 = value.trim().parse().map_err(|_| anyhow!("{} {:?} is not a number", what, value.trim()))?;
+    if !number.is_finite() {
+        bail!("{} {:?} is not a finite number", what, value.trim());
+    }
+    Ok(number)
+}
+
+///Parses a `~option`, applying it to the given options.
+fn parse_option(option: &str, options: &mut MatchOptions) -> Result<()> {
+    match option.split_once('=') {
+        Some(("fuzzy", value)) => {
+            options.fuzzy = value.parse().ok().filter(|&n| n <= 3)
+                .ok_or_else(|| anyhow!("fuzzy distance {:?} must be a whole number from 0 to 3", value))?;
+        },
+        None => match option {
+            "leet" => options.leet = true,
+            "unicode" => options.unicode = true,
+            "stem" => options.stem = true,
+            "spaced" => options.spaced = true,
+            "exact" => *options = MatchOptions::default(),
+            _ => bail!("unknown match option \"~{}\"", option),
+        },
+        _ => bail!("unknown match option \"~{}\"", option),
+    }
+    Ok(())
+}
+
+///Parses a `[name threshold=N ~option]` category header.
+fn parse_header(header: &str) -> Result<Category> {
+    let mut parts = header.split_whitespace();
+    let name = parts.next().ok_or_else(|| anyhow!("category header has no name"))?;
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("category name {:?} may only contain letters, digits and underscores", name);
+    }
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut options = MatchOptions::default();
+    for option in parts {
+        if let Some(option) = option.strip_prefix('~') {
+            parse_option(option, &mut options)?;
+            continue;
+        }
+        match option.split_once('=') {
+            Some(("threshold", value)) => {
+                threshold = parse_number(value, "threshold")?;
+                if threshold <= 0.0 {
+                    bail!("threshold must be greater than 0");
+                }
+            },
+            _ => bail!("unknown category option {:?}", option),
+        }
+    }
+    Ok(Category { name: name.to_owned(), threshold, options, rules: vec![] })
+}
+
+///Splits a term into normalised words.
+fn parse_words(term: &str, options: &MatchOptions) -> Result<Vec<String>> {
+    let words: Vec<String> = tokenize(term).iter().map(|token| prepare_rule_word(&token.text, options)).collect();
+    if words.is_empty() {
+        bail!("term {:?} contains no words", term);
+    }
+    Ok(words)
+}
+
+///Parses a plain term, or a `first NEAR/N second` proximity rule.
+fn parse_term(term: &str, options: &MatchOptions) -> Result<Pattern> {
+    let parts: Vec<&str> = term.split_whitespace().collect();
+    let mut near = parts.iter().enumerate().filter(|(_, part)| part.starts_with(NEAR)).map(|(i, _)| i);
+    let i = match near.next() {
+        Some(i) => i,
+        None => return Ok(Pattern::Words(parse_words(term, options)?)),
+    };
+    if near.next().is_some() {
+        bail!("a rule may only contain one {}", NEAR);
+    }
+    let distance = parts[i][NEAR.len()..].parse().ok().filter(|&n| n > 0)
+        .ok_or_else(|| anyhow!("{:?} must give a distance of at least 1 word", parts[i]))?;
+    Ok(Pattern::Near {
+        first: parse_words(&parts[..i].join(" "), options)?,
+        second: parse_words(&parts[i + 1..].join(" "), options)?,
+        distance,
+    })
+}
+
+///Splits a regex rule, following its opening '/', at the first '/' which isn't escaped as `\/`.
+///Returns the pattern as written, the pattern with `\/` unescaped for compiling, and the rest of the line.
+fn split_regex(rest: &str) -> Result<(&str, String, &str)> {
+    let mut source = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' => return Ok((&rest[..i], source, &rest[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '/')) => source.push('/'),
+                Some((_, escaped)) => {
+                    source.push('\\');
+                    source.push(escaped);
+                },
+                None => source.push('\\'),
+            },
+            _ => source.push(c),
+        }
+    }
+    bail!("regex is missing a closing '/'")
+}
+
+///Compiles the pattern of a regex rule.
+fn parse_regex(source: &str) -> Result<Regex> {
+    let regex = Regex::new(&format!("(?i){}", source)).map_err(|e| anyhow!("invalid regex /{}/: {}", source, e))?;
+    if regex.is_match("") {
+        bail!("regex /{}/ matches empty text", source);
+    }
+    Ok(regex)
+}
+
+///Parses a `[!]term [~option] [= weight]` line, starting from the options of its category.
+fn parse_rule(line: &str, mut options: MatchOptions) -> Result<Rule> {
+    let (allow, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest.trim()),
+        None => (false, line),
+    };
+    //A regex may contain spaces and '=', so it is split off before the options and weight.
+    let (regex, line) = match line.strip_prefix('/') {
+        Some(rest) => {
+            let (written, source, rest) = split_regex(rest)?;
+            (Some((written, source)), rest)
+        },
+        None => (None, line),
+    };
+    let mut rest = vec![];
+    for part in line.split_whitespace() {
+        match part.strip_prefix('~') {
+            Some(option) if regex.is_some() => bail!("option \"~{}\" cannot be used with a regex", option),
+            Some(option) => parse_option(option, &mut options)?,
+            None => rest.push(part),
+        }
+    }
+    let line = rest.join(" ");
+    let (term, weight) = match line.rsplit_once('=') {
+        Some((term, weight)) => (term.trim(), parse_number(weight, "weight")?),
+        None => (line.as_str(), DEFAULT_WEIGHT),
+    };
+    let (term, pattern) = match regex {
+        Some((written, source)) => {
+            if !term.is_empty() {
+                bail!("unexpected {:?} after regex", term);
+            }
+            options = MatchOptions::default();
+            (format!("/{}/", written), Pattern::Regex(parse_regex(&source)?))
+        },
+        None => (term.to_owned(), parse_term(term, &options)?),
+    };
+    Ok(Rule { term, pattern, weight, allow, options })
+}
+
+impl RuleSet {
+    ///Reads and parses a rule file.
+    pub fn load(path: impl AsRef<Path>) -> Result<RuleSet> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read rule file {}", path.display()))?;
+        RuleSet::parse(&contents)
+            .with_context(|| format!("Invalid rule file {}", path.display()))
+    }
+    ///Parses the contents of a rule file. Errors name the offending line.
+    pub fn parse(contents: &str) -> Result<RuleSet> {
+        let mut categories = vec![Category { name: DEFAULT_CATEGORY.to_owned(), threshold: DEFAULT_THRESHOLD, options: MatchOptions::default(), rules: vec![] }];
+        for (i, line) in contents.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.strip_suffix(']')
+                    .ok_or_else(|| anyhow!("line {}: category header is missing a closing ']'", line_number))?;
+                let category = parse_header(header).with_context(|| format!("line {}", line_number))?;
+                if categories.iter().any(|c| c.name == category.name) {
+                    bail!("line {}: category {:?} is defined twice", line_number, category.name);
+                }
+                categories.push(category);
+                continue;
+            }
+            let category = categories.last_mut().unwrap();
+            let rule = parse_rule(line, category.options).with_context(|| format!("line {}", line_number))?;
+            category.rules.push(rule);
+        }
+        categories.retain(|c| !c.rules.is_empty());
+        if categories.iter().all(|c| c.rules.iter().all(|r| r.allow)) {
+            bail!("rule file contains no terms");
+        }
+        Ok(RuleSet { categories, version: version_of(contents) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(line: &str) -> Rule {
+        parse_rule(line, MatchOptions::default()).unwrap()
+    }
+
+    fn regex(rule: &Rule) -> &Regex {
+        match &rule.pattern {
+            Pattern::Regex(regex) => regex,
+            pattern => panic!("expected a regex, got {:?}", pattern),
+        }
+    }
+
+    fn words(rule: &Rule) -> Vec<&str> {
+        match &rule.pattern {
+            Pattern::Words(words) => words.iter().map(String::as_str).collect(),
+            pattern => panic!("expected words, got {:?}", pattern),
+        }
+    }
+
+    fn error(contents: &str) -> String {
+        format!("{:#}", RuleSet::parse(contents).unwrap_err())
+    }
+
+    #[test]
+    fn parses_categories_terms_weights_and_allow_terms() {
+        let rules = RuleSet::parse("# A comment.\nplain\n\n[us_politics threshold=2]\ntrump\nWhite House = 2.5\n!trump card\n").unwrap();
+        let names: Vec<&str> = rules.categories.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec![DEFAULT_CATEGORY, "us_politics"]);
+        assert_eq!(rules.categories[0].threshold, DEFAULT_THRESHOLD);
+        let politics = &rules.categories[1];
+        assert_eq!(politics.threshold, 2.0);
+        assert_eq!(politics.rules.len(), 3);
+        assert_eq!(words(&politics.rules[1]), vec!["white", "house"]);
+        assert_eq!((politics.rules[1].weight, politics.rules[1].allow), (2.5, false));
+        assert_eq!((words(&politics.rules[2]), politics.rules[2].allow), (vec!["trump", "card"], true));
+    }
+
+    #[test]
+    fn a_plain_word_list_is_a_rule_file() {
+        let rules = RuleSet::parse("one\ntwo\n").unwrap();
+        assert_eq!(rules.categories.len(), 1);
+        assert_eq!(rules.categories[0].rules.len(), 2);
+    }
+
+    #[test]
+    fn terms_inherit_header_options_unless_exact() {
+        let rules = RuleSet::parse("[words ~stem ~fuzzy=1]\nelections\nballots ~exact\n").unwrap();
+        let category = &rules.categories[0];
+        assert_eq!(words(&category.rules[0]), vec!["election"]);
+        assert_eq!((category.rules[0].options.stem, category.rules[0].options.fuzzy), (true, 1));
+        assert_eq!(words(&category.rules[1]), vec!["ballots"]);
+        assert!(category.rules[1].options.is_exact());
+    }
+
+    #[test]
+    fn parses_proximity_rules() {
+        match rule("mask NEAR/3 mandate").pattern {
+            Pattern::Near { first, second, distance } => assert_eq!((first, second, distance), (vec!["mask".to_owned()], vec!["mandate".to_owned()], 3)),
+            pattern => panic!("expected a proximity rule, got {:?}", pattern),
+        }
+        assert!(parse_rule("mask NEAR/0 mandate", MatchOptions::default()).is_err());
+        assert!(parse_rule("a NEAR/1 b NEAR/1 c", MatchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(error("one\n\n[bad"), "line 3: category header is missing a closing ']'");
+        assert_eq!(error("[a]\nx\n[a]\ny"), "line 3: category \"a\" is defined twice");
+        assert_eq!(error("[a threshold=0]\nx"), "line 1: threshold must be greater than 0");
+        assert_eq!(error("x = heavy"), "line 1: weight \"heavy\" is not a number");
+        assert_eq!(error("# only\nx ~loud"), "line 2: unknown match option \"~loud\"");
+        assert!(error("/(/").starts_with("line 1: invalid regex /(/: "));
+        assert_eq!(error("/a*/ ~stem"), "line 1: option \"~stem\" cannot be used with a regex");
+        assert_eq!(error("/a*/"), "line 1: regex /a*/ matches empty text");
+        assert_eq!(error("!allowed"), "rule file contains no terms");
+    }
+
+    #[test]
+    fn tokenize_keeps_offsets_and_masked_words() {
+        let tokens = tokenize("Tr*mp, SAID hi");
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, vec!["tr*mp", "said", "hi"]);
+        assert_eq!((tokens[1].start, tokens[1].end), (7, 11));
+    }
+
+    #[test]
+    fn versions_are_stable_hashes() {
+        assert_eq!(version_of(""), "cbf29ce484222325");
+        assert_ne!(version_of("a"), version_of("b"));
+    }
+
+    #[test]
+    fn regex_rules_end_at_the_first_unescaped_slash() {
+        let rule = rule(r"/and\/or/ = 2");
+        assert_eq!(rule.term, r"/and\/or/");
+        assert_eq!(rule.weight, 2.0);
+        assert!(regex(&rule).is_match("This AND/OR that"));
+    }
+
+    #[test]
+    fn a_slash_after_a_regex_is_not_part_of_it() {
+        let error = parse_rule("/vote/ = 1/2", MatchOptions::default()).unwrap_err();
+        assert!(error.to_string().contains("weight"), "{}", error);
+        assert!(parse_rule("/vote/ for/", MatchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn other_escapes_are_kept() {
+        let rule = rule(r"/vote\s+for\\/");
+        assert!(regex(&rule).is_match(r"vote  for\"));
+        assert!(parse_rule(r"/vote\/", MatchOptions::default()).is_err());
+    }
+}
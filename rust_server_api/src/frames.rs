@@ -0,0 +1,326 @@
+//!This module picks the frames of animated images and videos worth scanning. Scanning every frame would be far too slow, and neighbouring frames usually show the same text, so a few representative frames are chosen: the first, the middle, and those where the picture changes the most.
+
+//Imports
+use std::{
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use image::{AnimationDecoder, DynamicImage, ImageFormat, codecs::gif::GifDecoder};
+use anyhow::{Context, Result, anyhow, bail};
+use crate::config::VideoConfig;
+
+///Frames beyond this are never decoded, so a very long animation can't tie up an OCR thread.
+const MAX_FRAMES: usize = 1000;
+///Frames are compared on thumbnails of this many pixels square.
+const SIGNATURE_SIZE: u32 = 16;
+///How different a frame must be from the one before it to count as a scene change, as the mean difference of its thumbnail from 0 to 1.
+const SCENE_CHANGE: f32 = 0.1;
+///How often a running ffmpeg is checked for having finished or overrun its deadline.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+///Every PNG file starts with these bytes.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+///The most ffmpeg may write for each chosen video frame, as a PNG. Larger frames fail the video rather than filling memory.
+const MAX_PNG_BYTES: usize = 32 * 1024 * 1024;
+///The most ffprobe or a version check may write.
+const MAX_TOOL_OUTPUT: usize = 64 * 1024;
+
+///A frame chosen for scanning.
+pub struct TimedFrame {
+    ///Seconds from the start.
+    pub time: f32,
+    pub image: DynamicImage,
+}
+
+///Returns true if the bytes start like a GIF file.
+pub fn is_gif(header: &[u8]) -> bool {
+    header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a")
+}
+
+///Shrinks a frame to a small grayscale thumbnail, which is enough to tell whether the picture changed.
+fn signature(frame: &DynamicImage) -> Vec<u8> {
+    frame.thumbnail_exact(SIGNATURE_SIZE, SIGNATURE_SIZE).to_luma8().into_raw()
+}
+
+///The mean difference between two thumbnails, from 0 (identical) to 1.
+fn difference(a: &[u8], b: &[u8]) -> f32 {
+    let total: u32 = a.iter().zip(b).map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs()).sum();
+    total as f32 / (a.len().max(1) as f32 * 255.0)
+}
+
+///Chooses up to `count` frames from their thumbnails: the first, then the middle, then the largest scene changes. Returns their indices in order.
+pub fn select(signatures: &[Vec<u8>], count: usize) -> Vec<usize> {
+    let mut chosen = vec![];
+    if signatures.is_empty() || count == 0 {
+        return chosen;
+    }
+    chosen.push(0);
+    if count > 1 && signatures.len() > 2 {
+        chosen.push(signatures.len() / 2);
+    }
+    let mut changes: Vec<(usize, f32)> = signatures.windows(2)
+        .enumerate()
+        .map(|(i, pair)| (i + 1, difference(&pair[0], &pair[1])))
+        .filter(|(i, change)| *change >= SCENE_CHANGE && !chosen.contains(i))
+        .collect();
+    changes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+    let room = count.saturating_sub(chosen.len());
+    chosen.extend(changes.into_iter().take(room).map(|(i, _)| i));
+    chosen.sort_unstable();
+    chosen
+}
+
+///Decodes the frames of a GIF and returns the `count` most representative, in order.
+///The GIF is decoded twice, first to compare thumbnails of every frame and then to keep the chosen frames, so only those are ever held at full size. `open` must return a new reader over the file each time.
+pub fn gif_frames<R: Read, F: Fn() -> Result<R>>(open: F, count: usize) -> Result<Vec<TimedFrame>> {
+    let mut signatures = vec![];
+    let mut times = vec![];
+    let mut elapsed = 0.0;
+    for frame in GifDecoder::new(open()?).context("Failed to read GIF")?.into_frames().take(MAX_FRAMES) {
+        let frame = frame.context("Failed to decode GIF frame")?;
+        times.push(elapsed);
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        elapsed += numerator as f32 / denominator.max(1) as f32 / 1000.0;
+        signatures.push(signature(&DynamicImage::ImageRgba8(frame.into_buffer())));
+    }
+    let chosen = select(&signatures, count);
+    let mut frames = Vec::with_capacity(chosen.len());
+    for (i, frame) in GifDecoder::new(open()?).context("Failed to read GIF")?.into_frames().enumerate().take(MAX_FRAMES) {
+        if chosen.contains(&i) {
+            let image = DynamicImage::ImageRgba8(frame.context("Failed to decode GIF frame")?.into_buffer());
+            frames.push(TimedFrame { time: times[i], image });
+        }
+        if frames.len() == chosen.len() {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+///Runs a command to completion, killing it if it is still running at the deadline. Returns what it wrote to stdout and stderr.
+///Fails if it writes more than `limit` bytes to stdout, which is closed once the limit is passed so the command stops.
+fn run(command: &mut Command, deadline: Instant, limit: usize) -> Result<(Vec<u8>, String)> {
+    let program = format!("{:?}", command);
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    //Both pipes are drained on their own threads, otherwise a full pipe would stall the child.
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let out = thread::spawn(move || {
+        let mut buf = vec![];
+        stdout.by_ref().take(limit as u64 + 1).read_to_end(&mut buf).map(|_| buf)
+    });
+    let err = thread::spawn(move || {
+        let mut buf = String::new();
+        stderr.read_to_string(&mut buf).map(|_| buf)
+    });
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{} was stopped after running out of time", program);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let out = out.join().map_err(|_| anyhow!("Failed to read the output of {}", program))??;
+    let err = err.join().map_err(|_| anyhow!("Failed to read the output of {}", program))??;
+    if out.len() > limit {
+        bail!("{} wrote more than {} bytes", program, limit);
+    }
+    if !status.success() {
+        bail!("{} failed with {}: {}", program, status, err.trim());
+    }
+    Ok((out, err))
+}
+
+///Checks ffmpeg and ffprobe can be run, so a missing binary stops the server at startup rather than failing every video.
+pub fn check_video_tools(config: &VideoConfig) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    for program in &[&config.ffmpeg, &config.ffprobe] {
+        run(Command::new(program).arg("-version"), deadline, MAX_TOOL_OUTPUT)
+            .with_context(|| format!("ocr.video is enabled, but {} could not be run", program.display()))?;
+    }
+    Ok(())
+}
+
+///Returns the length of a video in seconds, or None if ffprobe can't tell.
+fn duration(path: &Path, config: &VideoConfig, deadline: Instant) -> Result<Option<f32>> {
+    let (out, _) = run(Command::new(&config.ffprobe)
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path), deadline, MAX_TOOL_OUTPUT)?;
+    Ok(String::from_utf8_lossy(&out).trim().parse().ok())
+}
+
+///Splits the PNG files ffmpeg writes one after another into a pipe, by walking the chunks of each to its IEND chunk.
+fn split_pngs(mut data: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut pngs = vec![];
+    while !data.is_empty() {
+        if !data.starts_with(PNG_SIGNATURE) {
+            bail!("ffmpeg wrote something other than a PNG");
+        }
+        let mut end = PNG_SIGNATURE.len();
+        loop {
+            if data.len() < end + 8 {
+                bail!("ffmpeg wrote a truncated PNG");
+            }
+            let length = u32::from_be_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]) as usize;
+            let kind = &data[end + 4..end + 8];
+            //Length, type, data and checksum.
+            end += 12 + length;
+            if kind == b"IEND" {
+                break;
+            }
+        }
+        if end > data.len() {
+            bail!("ffmpeg wrote a truncated PNG");
+        }
+        pngs.push(&data[..end]);
+        data = &data[end..];
+    }
+    Ok(pngs)
+}
+
+///Reads the time of every frame from the log of ffmpeg's showinfo filter, which prints a line with `pts_time:` for each frame in order.
+fn frame_times(log: &str) -> Vec<f32> {
+    log.lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| line.split("pts_time:").nth(1))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .filter_map(|time| time.parse().ok())
+        .collect()
+}
+
+///Runs ffmpeg over the keyframes of a video, at most `max_duration_secs` of it and `MAX_FRAMES` frames, filtering them with `filter` and writing them to stdout.
+fn keyframes(path: &Path, config: &VideoConfig, filter: &str, output: &[&str], deadline: Instant, limit: usize) -> Result<(Vec<u8>, String)> {
+    run(Command::new(&config.ffmpeg)
+        .args(["-hide_banner", "-nostats", "-loglevel", "info", "-skip_frame", "nokey"])
+        .arg("-t").arg(config.max_duration_secs.to_string())
+        .arg("-i").arg(path)
+        .args(["-an", "-vf", filter, "-vsync", "vfr"])
+        .arg("-frames:v").arg(MAX_FRAMES.to_string())
+        .args(output)
+        .arg("-"), deadline, limit)
+}
+
+///The ffmpeg filter keeping only the frames at the given indices.
+fn select_filter(chosen: &[usize]) -> String {
+    let frames: Vec<String> = chosen.iter().map(|i| format!("eq(n\\,{})", i)).collect();
+    format!("select='{}'", frames.join("+"))
+}
+
+///Extracts the keyframes of a video with ffmpeg and returns the `count` most representative, in order.
+///Videos longer than `max_duration_secs` are refused, and ffmpeg never reads past that point in case the length was unknown.
+///ffmpeg is run twice: first writing a small grayscale thumbnail of every keyframe to compare them, then writing only the chosen frames at full size, so the other frames are never held in memory.
+pub fn video_frames(path: &Path, config: &VideoConfig, count: usize, deadline: Instant) -> Result<Vec<TimedFrame>> {
+    if let Some(duration) = duration(path, config, deadline)? {
+        if duration > config.max_duration_secs as f32 {
+            bail!("Video is {:.0}s long, longer than ocr.video.max_duration_secs", duration);
+        }
+    }
+    let thumbnail = (SIGNATURE_SIZE * SIGNATURE_SIZE) as usize;
+    let filter = format!("showinfo,scale={0}:{0},format=gray", SIGNATURE_SIZE);
+    let (out, log) = keyframes(path, config, &filter, &["-f", "rawvideo", "-pix_fmt", "gray"], deadline, MAX_FRAMES * thumbnail)?;
+    let signatures: Vec<Vec<u8>> = out.chunks_exact(thumbnail).map(<[u8]>::to_vec).collect();
+    let times = frame_times(&log);
+    let chosen = select(&signatures, count);
+    if chosen.is_empty() {
+        return Ok(vec![]);
+    }
+    let (out, _) = keyframes(path, config, &select_filter(&chosen), &["-f", "image2pipe", "-c:v", "png"], deadline, chosen.len() * MAX_PNG_BYTES)?;
+    let pngs = split_pngs(&out)?;
+    if pngs.len() != chosen.len() {
+        bail!("ffmpeg wrote {} of the {} frames chosen", pngs.len(), chosen.len());
+    }
+    chosen.into_iter().zip(pngs)
+        .map(|(i, png)| Ok(TimedFrame {
+            time: times.get(i).copied().unwrap_or(0.0),
+            image: image::load_from_memory_with_format(png, ImageFormat::Png).context("Failed to decode video frame")?,
+        }))
+        .collect()
+}
+
+///Joins the text read from several frames, keeping each line only the first time it appears, as most text stays on screen across frames.
+pub fn merge_text<'a, I: IntoIterator<Item = &'a str>>(texts: I) -> String {
+    let mut lines: Vec<&str> = vec![];
+    for line in texts.into_iter().flat_map(str::lines).map(str::trim) {
+        if !line.is_empty() && !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn png(shade: u8) -> Vec<u8> {
+        let mut png = vec![];
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(4, 3, Luma([shade]))).write_to(&mut png, ImageFormat::Png).unwrap();
+        png
+    }
+
+    #[test]
+    fn split_pngs_finds_each_file() {
+        let (first, second) = (png(0), png(255));
+        let piped = [first.clone(), second.clone()].concat();
+        assert_eq!(split_pngs(&piped).unwrap(), vec![&first[..], &second[..]]);
+        assert!(split_pngs(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_pngs_rejects_truncated_and_foreign_data() {
+        let first = png(0);
+        assert!(split_pngs(&first[..first.len() - 4]).is_err());
+        assert!(split_pngs(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn frame_times_reads_showinfo_lines() {
+        let log = "Input #0, mov,mp4\n\
+            [Parsed_showinfo_0 @ 0x1] n:   0 pts:      0 pts_time:0       duration:512\n\
+            [Parsed_showinfo_0 @ 0x1] n:   1 pts:  12800 pts_time:2.5     duration:512\n\
+            frame=    2 fps=0.0 pts_time:9\n";
+        assert_eq!(frame_times(log), vec![0.0, 2.5]);
+    }
+
+    #[test]
+    fn select_takes_first_middle_and_scene_changes() {
+        let still = vec![0u8; 4];
+        let bright = vec![255u8; 4];
+        let signatures = vec![still.clone(), still.clone(), still.clone(), bright.clone(), bright.clone(), bright, still.clone(), still];
+        assert_eq!(select(&signatures, 4), vec![0, 3, 4, 6]);
+        assert_eq!(select(&signatures, 1), vec![0]);
+        assert!(select(&signatures, 0).is_empty());
+        assert!(select(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn select_filter_lists_the_chosen_frames() {
+        assert_eq!(select_filter(&[0, 4, 9]), "select='eq(n\\,0)+eq(n\\,4)+eq(n\\,9)'");
+    }
+
+    #[test]
+    fn run_stops_commands_writing_too_much() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let error = run(&mut Command::new("yes"), deadline, 1000).unwrap_err();
+        assert!(error.to_string().contains("wrote more than 1000 bytes"), "{}", error);
+        let (out, _) = run(Command::new("echo").arg("hi"), deadline, 1000).unwrap();
+        assert_eq!(out, b"hi\n");
+    }
+
+    #[test]
+    fn merge_text_keeps_each_line_once() {
+        assert_eq!(merge_text(vec!["top\nbottom", " top \n\nend"]), "top\nbottom\nend");
+    }
+}
@@ -1,32 +1,32 @@
-///This module handles connections to the imgur api and website.
+//!This module handles connections to the imgur api and website.
 
 //Imports
 use std::cmp::min;
-use std::path::PathBuf;
 use bytes::BufMut;
 use hyper::{ body::HttpBody as httpbody, client::ResponseFuture, Client, Uri };
 use hyper_tls::HttpsConnector;
 use tokio::{
-    fs::{ create_dir_all, File },
+    fs::File,
     prelude::*
 };
-use url::Url;
 use futures::TryFutureExt;
-use std::error;
-use std::path::Path;
 use serde::{Deserialize};
 use reqwest::header::USER_AGENT;
-use anyhow::{Result, bail};
-use crate::mongo_db_interface::Database;
-use async_std::fs;
+use anyhow::{Context, Result, bail};
+use crate::post_store::Store;
 use std::time::{SystemTime, UNIX_EPOCH};
-
-///Config
-const DEFAULT_MAX_CONNECTION: usize = 10;
-const UNRECOVERABLE_THRESHOLD: f32 = 0.2;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::verdict::{self, Evaluation};
+use crate::ocr_pool::{Media, OcrPool, Scanned, SpoolFile};
+use crate::access_lists::{AccessLists, ListAction};
+use crate::scan_cache::{self, CachedScan, Fingerprint};
 
 ///This struct holds data needed to request images from the imgur api.
 #[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
 pub struct Image {
     id: String,
     title: Option<String>,
@@ -34,8 +34,15 @@ pub struct Image {
     link: String
 }
 
-///This struct models the data from the imgur api response.
+///A tag on an imgur post.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tag {
+    name: String,
+}
+
+///This struct models the data from the imgur api response. Not every field is used yet.
 #[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
 pub struct Post {
     id: String,
     title: Option<String>,
@@ -48,7 +55,10 @@ pub struct Post {
     nsfw: Option<bool>,
     images_count: Option<u32>,
     is_ad: bool,
-    images: Vec<Image>
+    images: Vec<Image>,
+    ///Only the gallery endpoints return tags, so they are missing from other responses.
+    #[serde(default)]
+    tags: Vec<Tag>,
 }
 
 ///The response received from the imgur api.
@@ -69,6 +79,8 @@ struct ImageRaw {
     link: String,
     nsfw: Option<bool>,
     is_ad: bool,
+    #[serde(default)]
+    tags: Vec<Tag>,
 }
 
 ///This models the response received from the imgur api for a single image.
@@ -77,116 +89,240 @@ struct ResponseImage {
     data: ImageRaw,
 }
 
+impl ImageRaw {
+    ///Makes a post holding just this image.
+    fn into_post(self) -> Post {
+        Post {
+            id: self.id.clone(),
+            datetime: self.datetime,
+            title: self.title.clone(),
+            is_ad: self.is_ad,
+            description: self.description.clone(),
+            account_url: self.account_url,
+            views: self.views,
+            link: format!("https://imgur.com/gallery/{}", &self.id),
+            is_album: false,
+            nsfw: self.nsfw,
+            images_count: Some(1),
+            images: vec![Image {
+                id: self.id,
+                title: self.title,
+                description: self.description,
+                link: self.link,
+            }],
+            tags: self.tags,
+        }
+    }
+}
+
+///An endpoint of the imgur api a post can be read from, and whether it returns an album or a single image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Endpoint {
+    path: &'static str,
+    album: bool,
+}
+
+const GALLERY_ALBUM: Endpoint = Endpoint { path: "gallery/album", album: true };
+const GALLERY_IMAGE: Endpoint = Endpoint { path: "gallery/image", album: false };
+const ALBUM: Endpoint = Endpoint { path: "album", album: true };
+const IMAGE: Endpoint = Endpoint { path: "image", album: false };
+
+///The most post ids whose endpoint is remembered. The cache is emptied when it fills up.
+const ENDPOINT_CACHE_SIZE: usize = 10_000;
+
+///Remembers which endpoint each post was found at, so fetching a post again costs a single request against the client id's rate limit. Cloning it is cheap, every clone refers to the same cache.
+#[derive(Clone, Default)]
+pub struct EndpointCache {
+    resolved: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+impl EndpointCache {
+    fn get(&self, post_id: &str) -> Option<Endpoint> {
+        self.resolved.lock().unwrap().get(post_id).copied()
+    }
+    fn insert(&self, post_id: &str, endpoint: Endpoint) {
+        let mut resolved = self.resolved.lock().unwrap();
+        if resolved.len() >= ENDPOINT_CACHE_SIZE {
+            resolved.clear();
+        }
+        resolved.insert(post_id.to_owned(), endpoint);
+    }
+}
+
+///Returns the endpoints to try for a post, most likely first. Album ids are usually five characters long and image ids seven, so the endpoints of the kind the id looks like are tried first.
+///The gallery endpoint of each kind comes first, as only the gallery endpoints return the tags of a post.
+fn endpoints_for(post_id: &str, cached: Option<Endpoint>) -> Vec<Endpoint> {
+    let mut endpoints = if post_id.len() == 5 {
+        vec![GALLERY_ALBUM, ALBUM, GALLERY_IMAGE, IMAGE]
+    } else {
+        vec![GALLERY_IMAGE, IMAGE, GALLERY_ALBUM, ALBUM]
+    };
+    if let Some(cached) = cached {
+        endpoints.retain(|endpoint| *endpoint != cached);
+        endpoints.insert(0, cached);
+    }
+    endpoints
+}
+
 ///This struct is the main function of this module. It is a downloader to acquire images from an imgur post.
 pub struct Downloader {
     post_id: Uri,
-    save_path: PathBuf,
+    endpoints: EndpointCache,
     max_conn: usize,
-    db: Database
-}
-
-///Creates and returns a filename from a url.
-fn create_filename(url: &str) -> Result<String, Box<dyn error::Error>> {
-    let tmp = &Url::parse(&url)?;
-    let res = &tmp.path();
-    let path = format!("{}", res[1..res.len()].to_owned());
-    Ok(path)
+    db: Store,
+    config: Arc<Config>,
+    filter: Arc<Filter>,
+    lists: AccessLists,
+    ocr: OcrPool,
 }
 
 ///Collects the current system time.
-fn get_time() -> u128 {
+pub fn get_time() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis()
 }
 
 impl Downloader {
     ///Generates a new downloader.
-    pub fn new(post_id: &str, db: Database) -> Self {
-        Downloader {
-            post_id: post_id.parse::<Uri>().unwrap_or_else(|_| panic!("failed to parse URL: {}", post_id)),
-            save_path: Path::new(post_id).to_path_buf(),
-            max_conn: DEFAULT_MAX_CONNECTION,
-            db: db
-        }
+    pub fn new(post_id: &str, endpoints: EndpointCache, db: Store, config: Arc<Config>, filter: Arc<Filter>, lists: AccessLists, ocr: OcrPool) -> Result<Self> {
+        Ok(Downloader {
+            post_id: post_id.parse::<Uri>().with_context(|| format!("failed to parse URL: {}", post_id))?,
+            endpoints,
+            max_conn: config.imgur.max_connections,
+            db,
+            config,
+            filter,
+            lists,
+            ocr,
+        })
     }
-    ///The function which recieves the bytes when downloading an image.
-    async fn recv(&self, fut: ResponseFuture) -> Result<bytes::BytesMut, anyhow::Error> {
+    ///The function which recieves the bytes when downloading an image. They are kept in memory, unless the image grows past `ocr.spool_above_bytes`, then it is written to a spool file as it arrives.
+    ///Videos are always spooled, and the download is abandoned once it passes `ocr.video.max_bytes`.
+    async fn recv(&self, fut: ResponseFuture, video: bool) -> Result<Media, anyhow::Error> {
         let mut buf = bytes::BytesMut::new();
+        let mut spool: Option<(SpoolFile, File)> = None;
+        let mut received = 0u64;
+        let spool_above = if video { Some(0) } else { self.config.ocr.spool_above_bytes };
         let mut res = fut.await?;
 
         while let Some(next) = res.data().await {
             let chunk = next?;
-            buf.put(chunk);
+            received += chunk.len() as u64;
+            if video && received > self.config.ocr.video.max_bytes {
+                bail!("Video is larger than ocr.video.max_bytes");
+            }
+            if let Some((_, file)) = spool.as_mut() {
+                file.write_all(&chunk).await?;
+                continue;
+            }
+            match spool_above {
+                Some(limit) if received > limit => {
+                    let spool_file = SpoolFile::new(&self.config.ocr.spool_dir());
+                    let mut file = File::create(&spool_file.path).await?;
+                    file.write_all(&buf).await?;
+                    file.write_all(&chunk).await?;
+                    buf = bytes::BytesMut::new();
+                    spool = Some((spool_file, file));
+                },
+                _ => buf.put(chunk),
+            }
         }
 
-        Ok(buf)
+        match spool {
+            Some((spool_file, mut file)) => {
+                file.flush().await?;
+                if video {
+                    Ok(Media::Video(spool_file))
+                } else {
+                    Ok(Media::Spooled(spool_file))
+                }
+            },
+            None if video => bail!("Video is empty"),
+            None => Ok(Media::Memory(buf.freeze())),
+        }
     }
-    ///Creates a downloader module to acquire the image. Returns a string with the url, and the downloaded image bytes.
-    async fn get_downloader(&self, url: Uri) -> Result<(String, bytes::BytesMut), anyhow::Error> {
+    ///Creates a downloader module to acquire the image or video. Returns the downloaded media.
+    async fn get_downloader(&self, url: Uri, video: bool) -> Result<Media, anyhow::Error> {
         let https = HttpsConnector::new();
         let client = Client::builder().build::<_, hyper::Body>(https);
 
-        let content = self.recv(client.get(url.clone())).await?;
-
-        Ok((url.to_string(), content))
+        self.recv(client.get(url), video).await
     }
-    ///Takes a url and the downloaded bytes of an image, and saves it to the drive.
-    async fn download(&self, url: String, res: bytes::BytesMut) -> anyhow::Result<PathBuf> {
-        let file_name = create_filename(&url).unwrap();
-
-        create_dir_all(&self.save_path).await.unwrap();
-
-        let save_path = self.save_path.join(&file_name);
-
-        let mut file = File::create(&save_path).await.unwrap();
-        file.write_all(&res).await.unwrap();
-        Ok(save_path)
-    }
-    ///Takes an image path and scans it with tesseract OCR, returns any text it finds in the form of a string.
-    async fn scan_image(&self, path: PathBuf) -> anyhow::Result<String>{
-        let mut scanner = leptess::LepTess::new(Some("/home/ubuntu/PersonalProjects/0015_ImgurScraper/extension_contact_server/tessdata"), "eng").expect("Failed to load OCR Scanner.");
-        scanner.set_image(&path).expect("Failed to set image for OCR scanner.");
-
-        scanner.set_fallback_source_resolution(70);
-        Ok(scanner.get_utf8_text().expect("Failed to get utf8 text for OCR."))
+    ///Scans downloaded media. When the scan cache is enabled, the text of a similar still image scanned before is reused instead of running tesseract.
+    ///Returns the fingerprint of an image which was scanned afresh, so its scan can be cached once the post has been judged.
+    async fn scan(&self, media: Media) -> Result<(Scanned, Option<Fingerprint>), anyhow::Error> {
+        let cache = &self.config.ocr.cache;
+        let fingerprint = if cache.enabled { scan_cache::fingerprint(&media, self.config.ocr.preprocess.max_pixels).await } else { None };
+        let fingerprint = match fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Ok((self.ocr.scan(media).await?, None)),
+        };
+        match scan_cache::find(&self.db, &fingerprint, &self.config.ocr.version(), cache).await {
+            Ok(Some(cached)) => {
+                let words = scan_cache::rescale_words(&cached, &fingerprint);
+                let scanned = Scanned { text: cached.text, frames: vec![], words };
+                return Ok((scanned, None));
+            },
+            Ok(None) => {},
+            Err(e) => println!("Failed to look up the scan cache, scanning the image: {}", e),
+        }
+        Ok((self.ocr.scan(media).await?, Some(fingerprint)))
     }
-    ///Manages to the multi-stage download of an image. First downloading, then scanning, then deleting.
-    async fn dl(&self, uri: Uri) -> anyhow::Result<String> {
-        let input_string = uri.to_string();
-        let extension: Vec<&str> = input_string.split(".").collect();
-        let extension = extension[extension.len() -1 ];
-        if extension == "mp4" || extension == "gif" || extension == "gifv" {
-            return Ok("".to_owned());
+    ///Manages to the multi-stage download of an image. First downloading, then scanning. Spooled images and videos are deleted once scanned.
+    ///Returns None for images which weren't scanned, either because they are videos and video scanning is off, or because downloading or OCR failed. They are left out of the verdict rather than counted as images without text.
+    async fn dl(&self, uri: Uri) -> (Option<Scanned>, Option<Fingerprint>) {
+        let video = verdict::is_video(&uri.to_string());
+        if video && !self.config.ocr.video.enabled {
+            return (None, None);
         }
-        let client = self.get_downloader(uri);
-        let text = match client
-            .and_then(|(res, url)| self.download(res, url) )
-            .and_then(|path| self.scan_image(path) )
+        //A gifv link is a web page playing the mp4 of the same name.
+        let uri = match uri.to_string().strip_suffix(".gifv").map(|stem| format!("{}.mp4", stem).parse::<Uri>()) {
+            Some(Ok(mp4)) => mp4,
+            Some(Err(e)) => {
+                println!("Failed to find the mp4 of {}: {}", uri, e);
+                return (None, None);
+            },
+            None => uri,
+        };
+        let client = self.get_downloader(uri, video);
+        match client
+            .and_then(|media| self.scan(media) )
         .await {
-            Ok(f) => f,
+            Ok((scanned, hash)) => (Some(scanned), hash),
             Err(e) => {
                 println!("An error occured while downloading and scanning image {}", e);
-                "".to_owned()
+                (None, None)
             }
-        };  
-        Ok(text)
+        }
     }
     ///Downloads all images from a post, carrying out OCR on them and returning a Post.
     pub async fn download_post_images(&self, mut input: Post) -> anyhow::Result<crate::mongo_db_interface::Post> {
         let mut urls_to_download: Vec<Uri> = vec![];
-        let mut text_from_images: Vec<String> = vec![];
-        let filter = crate::filter::Filter::new("/home/ubuntu/PersonalProjects/0015_ImgurScraper/extension_contact_server/filter_word_list.txt")?;
-        let mut output: crate::mongo_db_interface::Post;
-        if filter.is_unsafe(&input.title.clone().unwrap_or("".to_owned())) || filter.is_unsafe(&input.description.clone().unwrap_or("".to_owned())) {
-            output = crate::mongo_db_interface::Post {
-                id: input.id,
-                images: vec![],
-                post_url: input.link,
-                datetime: get_time().to_string(),
-                unrecoverable: Some(true),
-                description: Some(input.description.unwrap_or("".to_owned())),
-                title: Some(input.title.unwrap_or("".to_owned())),
+        let mut text_from_images: Vec<(Option<Scanned>, Option<Fingerprint>)> = vec![];
+        let filter = &self.filter;
+        let mut output = crate::mongo_db_interface::Post {
+            id: input.id.clone(),
+            images: vec![],
+            post_url: input.link.clone(),
+            datetime: get_time().to_string(),
+            description: Some(input.description.clone().unwrap_or("".to_owned())),
+            title: Some(input.title.clone().unwrap_or("".to_owned())),
+            filter_version: Some(filter.version().to_owned()),
+            account: input.account_url.clone(),
+            tags: input.tags.iter().map(|tag| tag.name.clone()).collect(),
+            ..Default::default()
+        };
+        if let Some(entry) = self.lists.decide(&output.id, output.account.as_deref(), &output.tags) {
+            //The post is on the allowlist or denylist, so there is no need to download or filter it.
+            output.listed = Some(entry.action);
+            output.unrecoverable = Some(entry.action == ListAction::Deny);
+            if entry.action == ListAction::Deny {
+                output.reasons = vec![entry.reason()];
             }
         } else {
+            verdict::check_text(filter, &mut output);
+        }
+        if output.listed.is_none() && !verdict::text_unsafe_for_all(&output, &self.config.profiles) {
+            //The title and description are safe by the default thresholds or for some profile, so download and scan the images.
             for image in &input.images {
                 urls_to_download.push(image.link.parse::<Uri>()?);
             }
@@ -199,58 +335,40 @@ impl Downloader {
                     clients_vec.push(download);
                 }
                 for res in futures::future::join_all(clients_vec).await {
-                    text_from_images.push(res.unwrap());
+                    text_from_images.push(res);
                 }
             }
     
-            //Run check
-            output = crate::mongo_db_interface::Post {
-                id: input.id.clone(),
-                images: vec![],
-                post_url: input.link,
-                datetime: get_time().to_string(),
-                unrecoverable: Some(false),
-                description: Some(input.description.clone().unwrap_or("".to_owned())),
-                title: Some(input.title.clone().unwrap_or("".to_owned())),
-            };
-            let mut num_unrecoverable = 0;
-            let mut num_images = 0;
-            assert_eq!(text_from_images.len(), input.images.len());
-            for (i, image) in input.images.iter_mut().enumerate() {
-                //Check each image, then push it to the output arr.
-    
-                let mut unrecoverable = false;
-                if filter.is_unsafe(&image.description.clone().unwrap_or("".to_owned())) {
-                    unrecoverable = true;
-                }
-                if filter.is_unsafe(&text_from_images[i]) {
-                    unrecoverable = true;
-                }
-                let new_image = crate::mongo_db_interface::Image {
+            if text_from_images.len() != input.images.len() {
+                bail!("Scanned {} of the {} images of post {}", text_from_images.len(), input.images.len(), input.id);
+            }
+            let mut fingerprints = Vec::with_capacity(text_from_images.len());
+            for (image, (scanned, fingerprint)) in input.images.iter_mut().zip(text_from_images) {
+                fingerprints.push(fingerprint);
+                let (text, frames, words) = match scanned {
+                    Some(scanned) => (Some(scanned.text), scanned.frames, scanned.words),
+                    None => (None, vec![], vec![]),
+                };
+                output.images.push(crate::mongo_db_interface::Image {
                     id: image.id.clone(),
                     description: image.description.clone().unwrap_or("".to_owned()),
                     url: image.link.clone(),
-                    unrecoverable: Some(unrecoverable),
-                    image_ocr_text: Some(text_from_images[i].clone())
-                };
-                if unrecoverable {
-                    num_unrecoverable += 1;
-                }
-                let extension: Vec<&str> = image.link.split(".").collect();
-                let extension = extension[extension.len() - 1];
-                if extension != "mp4" && extension != "gif" && extension != "gifv"{
-                    num_images += 1;
-                }
-                output.images.push(new_image);
+                    image_ocr_text: text,
+                    frames,
+                    words,
+                    ..Default::default()
+                });
             };
-            //Check # of (non-video) images marked as unrecoverable doesn't cross threshold.
-            if (num_unrecoverable as f32 / num_images as f32) as f32 >= UNRECOVERABLE_THRESHOLD {
-                output.unrecoverable = Some(true);
-            }
-            //Remove Folder
-            if fs::remove_dir_all(&self.save_path).await.is_err() {
-                println!("Failed to remove folder.");
+            //Run check
+            let threshold = self.config.filter.unrecoverable_threshold;
+            if verdict::evaluate(filter, &mut output, threshold, &self.config.profiles) == Evaluation::NeedsImages {
+                //The post has no images at all, so it is judged on its title and description alone.
+                verdict::judge(&mut output, None, threshold);
             }
+            self.cache_scans(&output, &fingerprints).await;
+        } else if output.listed.is_none() {
+            //The title or description hides the post for everyone, so it is judged without its images.
+            verdict::judge(&mut output, None, self.config.filter.unrecoverable_threshold);
         }
 
         //Upload to DB
@@ -261,62 +379,120 @@ impl Downloader {
         //Return Result
         Ok(output)
     }
-    ///Takes a url to imgur post, contacts the imgur inc api to collect data about the post.
-    pub async fn get_post(&self) -> Result<Post, anyhow::Error> {
-        let client = reqwest::Client::new();
-        let mut url = format!("https://api.imgur.com/3/album/{}", self.post_id);
-        let mut response = client
-            .get(&url)
+    ///Caches the scans of the images of a judged post which were read by tesseract, with the verdict each was given.
+    async fn cache_scans(&self, post: &crate::mongo_db_interface::Post, fingerprints: &[Option<Fingerprint>]) {
+        let ocr_version = self.config.ocr.version();
+        for (image, fingerprint) in post.images.iter().zip(fingerprints) {
+            let fingerprint = match fingerprint {
+                Some(fingerprint) => fingerprint,
+                None => continue,
+            };
+            let scan = CachedScan {
+                hash: scan_cache::format_hash(fingerprint.hash),
+                ocr_version: ocr_version.clone(),
+                detail: scan_cache::format_detail(&fingerprint.detail),
+                width: fingerprint.width,
+                height: fingerprint.height,
+                text: image.image_ocr_text.clone().unwrap_or_default(),
+                words: image.words.clone(),
+                unrecoverable: image.unrecoverable.unwrap_or(false),
+                reasons: image.reasons.clone(),
+                filter_version: post.filter_version.clone(),
+                post_id: post.id.clone(),
+                image_id: image.id.clone(),
+                created: get_time().to_string(),
+            };
+            if let Err(e) = self.db.cache_scan(scan).await {
+                println!("Failed to cache the scan of image {}: {}", image.id, e);
+            }
+        }
+    }
+    ///Requests the post from an endpoint of the imgur api. Returns None if imgur has no such post there.
+    async fn request(&self, client: &reqwest::Client, endpoint: &str) -> Result<Option<String>, anyhow::Error> {
+        let response = client
+            .get(&format!("https://api.imgur.com/3/{}/{}", endpoint, self.post_id))
             .header(USER_AGENT, "PostmanRuntime/7.26.8")
-            .header("Authorization", "Client-ID ")
+            .header("Authorization", format!("Client-ID {}", self.config.imgur.client_id))
             .header("Accept", "*/*")
             .header("Connection", "keep-alive")
             .send()
             .await?;
-
-        if response.status().as_u16() == 404 {
-            url = format!("https://api.imgur.com/3/image/{}", self.post_id);
-            response = client
-                .get(&url)
-                .header(USER_AGENT, "PostmanRuntime/7.26.8")
-                .header("Authorization", "Client-ID ")
-                .header("Accept", "*/*")
-                .header("Connection", "keep-alive")
-                .send()
-                .await?;
-            
-            let result = response.text().await?;
-            //Process the response
-            let v: ResponseImage = serde_json::from_str(&*result)?;
-            let v = v.data;
-            let post = Post {
-                id: v.id.clone(),
-                datetime: v.datetime,
-                title: v.title.clone(),
-                is_ad: v.is_ad,
-                description: v.description.clone(),
-                account_url: v.account_url,
-                views: v.views,
-                link: format!("https://imgur.com/gallery/{}", &v.id),
-                is_album: false,
-                nsfw: v.nsfw,
-                images_count: Some(1),
-                images: vec![Image {
-                    id: v.id,
-                    title: v.title,
-                    description: v.description,
-                    link: v.link,
-                }]
-            };
-
-            Ok(post)
+        match response.status().as_u16() {
+            200 => Ok(Some(response.text().await?)),
+            404 => Ok(None),
+            _ => bail!("Imgur server error: {}", response.text().await?),
+        }
+    }
+    ///Reads the post from an endpoint of the imgur api, which returns either an album or a single image.
+    async fn fetch(&self, client: &reqwest::Client, endpoint: Endpoint) -> Result<Option<Post>, anyhow::Error> {
+        let result = match self.request(client, endpoint.path).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        if endpoint.album {
+            let v: Response = serde_json::from_str(&result)?;
+            Ok(Some(v.data))
         } else {
-            if response.status().as_u16() != 200 {
-                bail!("Imgur server error: {}", response.text().await?);
+            let v: ResponseImage = serde_json::from_str(&result)?;
+            Ok(Some(v.data.into_post()))
+        }
+    }
+    ///Takes a url to imgur post, contacts the imgur inc api to collect data about the post.
+    ///The endpoint the post was last found at is tried first, otherwise the endpoints the shape of the id suggests. Posts which were never shared to the gallery are read from the album and image endpoints.
+    ///The next endpoint is only tried when imgur answers 404, so an outage or a rate limit fails the post rather than storing it without its tags. Every extra request is logged, as each counts against the client id's rate limit.
+    pub async fn get_post(&self) -> Result<Post, anyhow::Error> {
+        let client = reqwest::Client::new();
+        let post_id = self.post_id.to_string();
+        let endpoints = endpoints_for(&post_id, self.endpoints.get(&post_id));
+        for (i, &endpoint) in endpoints.iter().enumerate() {
+            if let Some(post) = self.fetch(&client, endpoint).await? {
+                self.endpoints.insert(&post_id, endpoint);
+                return Ok(post);
+            }
+            if let Some(next) = endpoints.get(i + 1) {
+                println!("Imgur has no post {} at {}, trying {}.", post_id, endpoint.path, next.path);
             }
-            let result = response.text().await?;
-            let v: Response = serde_json::from_str(&*result)?;
-            Ok(v.data)
         }
+        bail!("Imgur has no post {}", self.post_id)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gallery_images_keep_their_tags() {
+        let response = r#"{"data": {"id": "abc", "title": "A title", "description": null, "datetime": 1600000000,
+            "account_url": "someone", "views": 12, "link": "https://i.imgur.com/abc.jpg", "nsfw": false, "is_ad": false,
+            "in_gallery": true, "tags": [{"name": "funny", "display_name": "Funny"}, {"name": "cats"}]}, "success": true, "status": 200}"#;
+        let post = serde_json::from_str::<ResponseImage>(response).unwrap().data.into_post();
+        assert_eq!(post.tags.iter().map(|tag| tag.name.as_str()).collect::<Vec<_>>(), vec!["funny", "cats"]);
+        assert_eq!(post.images.len(), 1);
+        assert_eq!(post.images[0].link, "https://i.imgur.com/abc.jpg");
+        assert_eq!(post.link, "https://imgur.com/gallery/abc");
+    }
+
+    #[test]
+    fn posts_outside_the_gallery_have_no_tags() {
+        let response = r#"{"data": {"id": "xyz", "title": null, "description": null, "datetime": 1600000000, "account_url": null,
+            "views": 3, "link": "https://imgur.com/a/xyz", "is_album": true, "nsfw": null, "images_count": 1, "is_ad": false,
+            "images": [{"id": "img", "title": null, "description": "words", "link": "https://i.imgur.com/img.png"}]}}"#;
+        let post = serde_json::from_str::<Response>(response).unwrap().data;
+        assert!(post.tags.is_empty());
+        assert_eq!(post.images[0].description.as_deref(), Some("words"));
+    }
+
+    #[test]
+    fn endpoints_follow_the_id_shape_and_the_cache() {
+        let paths = |endpoints: Vec<Endpoint>| endpoints.into_iter().map(|endpoint| endpoint.path).collect::<Vec<_>>();
+        assert_eq!(paths(endpoints_for("abcde", None)), vec!["gallery/album", "album", "gallery/image", "image"]);
+        assert_eq!(paths(endpoints_for("abcdefg", None)), vec!["gallery/image", "image", "gallery/album", "album"]);
+        assert_eq!(paths(endpoints_for("abcde", Some(IMAGE))), vec!["image", "gallery/album", "album", "gallery/image"]);
+
+        let cache = EndpointCache::default();
+        cache.insert("abcde", ALBUM);
+        assert_eq!(cache.get("abcde"), Some(ALBUM));
+        assert_eq!(cache.get("abcdefg"), None);
+    }
+}
@@ -0,0 +1,471 @@
+//!This module holds the in-process job queue. Requests enqueue a post id and return immediately, while a pool of workers downloads, scans and filters the posts in the background.
+
+//Imports
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+use anyhow::Result;
+use crate::config::Config;
+use crate::filter::SharedFilter;
+use crate::access_lists::AccessLists;
+use crate::imgur_interface::{Downloader, EndpointCache};
+use crate::ocr_pool::OcrPool;
+use crate::mongo_db_interface::Post;
+use crate::post_store::Store;
+
+///How urgently a post is needed. Higher priorities are always taken off the queue first.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    ///Work nobody is waiting on, such as re-checking old posts.
+    Background,
+    ///A post the extension expects the user to scroll to soon.
+    Prefetch,
+    ///The post the user is currently looking at.
+    #[default]
+    Visible,
+}
+
+///The body of a request to check a post. Older versions of the extension do not send a priority, so those requests are treated as visible.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CheckRequest {
+    pub id: String,
+    pub post_url: String,
+    #[serde(default)]
+    pub priority: Priority,
+    ///The id the extension generated for this user, used to apply their own corrections to the verdict.
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+///The query string of a poll for a job.
+#[derive(Deserialize)]
+pub struct PollQuery {
+    ///The same id as the request which created the job, so the user's own corrections are applied to the verdict.
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+///The state of a single job, as reported to the extension.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase", tag = "status")]
+pub enum JobStatus {
+    ///Waiting for a free worker.
+    Queued,
+    ///A worker is downloading and scanning the post.
+    Processing,
+    ///The post has been processed, the verdict is included.
+    Done { post: Box<Post> },
+    ///Processing failed, the job will not be retried.
+    Failed { error: String },
+}
+
+///The body returned from the check endpoints.
+#[derive(Serialize, Clone, Debug)]
+pub struct JobReport {
+    ///Unset when the post was already in the database and no job was needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<u64>,
+    pub post_id: String,
+    pub priority: Priority,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+///A job tracked by the queue.
+struct Job {
+    post_id: String,
+    priority: Priority,
+    status: JobStatus,
+    updated: Instant,
+}
+
+///An entry in the pending heap. Entries are ordered by priority, then oldest first.
+#[derive(PartialEq, Eq)]
+struct Pending {
+    priority: Priority,
+    seq: Reverse<u64>,
+    job_id: u64,
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///The state shared between the request handlers and the workers.
+struct Inner {
+    next_id: u64,
+    next_seq: u64,
+    ///Jobs waiting for a worker. Promoting a job pushes a second entry, the stale one is skipped when popped.
+    pending: BinaryHeap<Pending>,
+    jobs: HashMap<u64, Job>,
+    ///The latest job for each post id, so concurrent requests for one post share a single job and result while it is queued or processing.
+    by_post: HashMap<String, u64>,
+    last_purge: Instant,
+}
+
+///How often expired jobs are purged. A full queue purges early, but at most this often.
+const PURGE_INTERVAL: Duration = Duration::from_secs(5);
+
+///The queue already tracks as many jobs as the post's priority allows, so it was not queued.
+#[derive(Debug, PartialEq)]
+pub struct QueueFull;
+
+///A handle to the job queue. Cloning it is cheap, every clone refers to the same queue.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Mutex<Inner>>,
+    ///Wakes every idle worker whenever a job is queued or promoted.
+    wakeup: Arc<watch::Sender<()>>,
+    retention: Duration,
+    ///The most jobs tracked for visible posts.
+    max_jobs: usize,
+    ///The most jobs tracked for background and prefetch posts, leaving the rest for visible posts.
+    max_background_jobs: usize,
+}
+
+impl JobQueue {
+    ///Creates a new queue and spawns its worker pool onto the tokio runtime.
+    ///The first `queue.reserved_visible_workers` workers only take visible posts, so prefetching can never occupy the whole pool.
+    pub fn start(db: Store, config: Arc<Config>, filter: SharedFilter, lists: AccessLists, ocr: OcrPool) -> JobQueue {
+        let (queue, receiver) = JobQueue::new(Duration::from_secs(config.queue.job_retention_secs), config.queue.max_jobs, config.queue.reserved_visible_jobs);
+        tokio::spawn(queue.clone().purge_periodically());
+        let endpoints = EndpointCache::default();
+        for i in 0..config.queue.workers {
+            let min_priority = if i < config.queue.reserved_visible_workers { Priority::Visible } else { Priority::Background };
+            let processor = Processor { db: db.clone(), config: config.clone(), filter: filter.clone(), lists: lists.clone(), ocr: ocr.clone(), endpoints: endpoints.clone() };
+            tokio::spawn(queue.clone().worker(min_priority, receiver.clone(), processor));
+        }
+        queue
+    }
+    ///Creates an empty queue without workers, returning the receiver workers are woken through.
+    fn new(retention: Duration, max_jobs: usize, reserved_visible_jobs: usize) -> (JobQueue, watch::Receiver<()>) {
+        let (wakeup, receiver) = watch::channel(());
+        let queue = JobQueue {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 0,
+                next_seq: 0,
+                pending: BinaryHeap::new(),
+                jobs: HashMap::new(),
+                by_post: HashMap::new(),
+                last_purge: Instant::now(),
+            })),
+            wakeup: Arc::new(wakeup),
+            retention,
+            max_jobs,
+            max_background_jobs: max_jobs.saturating_sub(reserved_visible_jobs),
+        };
+        (queue, receiver)
+    }
+    ///Adds a post to the queue, returning its job.
+    ///If the post already has a job which is queued or processing, that job is returned instead so the post is only downloaded and scanned once. A queued job is promoted to the higher of the two priorities.
+    ///Finished jobs are never reused, as the post may have changed since, for example by an edit to the allowlist or denylist. Their status can still be polled until they expire.
+    ///Once `queue.max_jobs` jobs are tracked, new posts are rejected. Background and prefetch posts are rejected `queue.reserved_visible_jobs` jobs sooner, so a user who is waiting on a post can still queue it.
+    pub fn enqueue(&self, post_id: &str, priority: Priority) -> Result<JobReport, QueueFull> {
+        let mut inner = self.inner.lock().unwrap();
+        let existing = inner.by_post.get(post_id)
+            .and_then(|job_id| inner.jobs.get(job_id).map(|job| (*job_id, job)))
+            .filter(|(_, job)| matches!(job.status, JobStatus::Queued | JobStatus::Processing))
+            .map(|(job_id, job)| (job_id, job.priority, matches!(job.status, JobStatus::Queued)));
+        let job_id = match existing {
+            Some((job_id, current, true)) if current < priority => {
+                inner.jobs.get_mut(&job_id).unwrap().priority = priority;
+                self.push_pending(&mut inner, job_id, priority);
+                job_id
+            },
+            Some((job_id, _, _)) => job_id,
+            None if self.is_full(&mut inner, priority) => return Err(QueueFull),
+            None => {
+                inner.next_id += 1;
+                let job_id = inner.next_id;
+                inner.jobs.insert(job_id, Job {
+                    post_id: post_id.to_owned(),
+                    priority,
+                    status: JobStatus::Queued,
+                    updated: Instant::now(),
+                });
+                inner.by_post.insert(post_id.to_owned(), job_id);
+                self.push_pending(&mut inner, job_id, priority);
+                job_id
+            }
+        };
+        let job = &inner.jobs[&job_id];
+        Ok(JobReport { job_id: Some(job_id), post_id: post_id.to_owned(), priority: job.priority, status: job.status.clone() })
+    }
+    ///Returns the current state of a job, or `None` if it is unknown or has expired.
+    pub fn status(&self, job_id: u64) -> Option<JobReport> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.get(&job_id).map(|job| JobReport {
+            job_id: Some(job_id),
+            post_id: job.post_id.clone(),
+            priority: job.priority,
+            status: job.status.clone(),
+        })
+    }
+    ///Pushes a job onto the pending heap and wakes the workers.
+    fn push_pending(&self, inner: &mut Inner, job_id: u64, priority: Priority) {
+        inner.next_seq += 1;
+        let seq = Reverse(inner.next_seq);
+        inner.pending.push(Pending { priority, seq, job_id });
+        let _ = self.wakeup.broadcast(());
+    }
+    ///Whether the queue is too full to take a new post of `priority`. Expired jobs are purged first if that has not happened recently.
+    fn is_full(&self, inner: &mut Inner, priority: Priority) -> bool {
+        let limit = if priority == Priority::Visible { self.max_jobs } else { self.max_background_jobs };
+        if inner.jobs.len() >= limit && inner.last_purge.elapsed() >= PURGE_INTERVAL {
+            self.purge(inner);
+        }
+        inner.jobs.len() >= limit
+    }
+    ///Purges the queue every `PURGE_INTERVAL`, so memory is given back even while nothing new is queued.
+    async fn purge_periodically(self) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut inner = self.inner.lock().unwrap();
+            self.purge(&mut inner);
+        }
+    }
+    ///Drops finished jobs which have been kept longer than the retention period, and the pending entries left behind by promoted jobs.
+    fn purge(&self, inner: &mut Inner) {
+        inner.last_purge = Instant::now();
+        let retention = self.retention;
+        inner.jobs.retain(|_, job| match job.status {
+            JobStatus::Done { .. } | JobStatus::Failed { .. } => job.updated.elapsed() < retention,
+            _ => true,
+        });
+        let Inner { jobs, by_post, pending, .. } = inner;
+        by_post.retain(|_, job_id| jobs.contains_key(job_id));
+        pending.retain(|entry| jobs.get(&entry.job_id)
+            .is_some_and(|job| matches!(job.status, JobStatus::Queued) && job.priority == entry.priority));
+    }
+    ///Updates the state of a job.
+    fn set_status(&self, job_id: u64, status: JobStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.get_mut(&job_id) {
+            job.status = status;
+            job.updated = Instant::now();
+        }
+    }
+    ///Takes the highest priority job of at least `min_priority` off the queue, if there is one.
+    fn take_job(&self, min_priority: Priority) -> Option<(u64, String)> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            let top = inner.pending.peek()?;
+            if top.priority < min_priority {
+                return None;
+            }
+            let Pending { priority, job_id, .. } = inner.pending.pop().unwrap();
+            match inner.jobs.get_mut(&job_id) {
+                //Skip entries left behind when a job was promoted.
+                Some(job) if matches!(job.status, JobStatus::Queued) && job.priority == priority => {
+                    job.status = JobStatus::Processing;
+                    job.updated = Instant::now();
+                    return Some((job_id, job.post_id.clone()));
+                },
+                _ => continue,
+            }
+        }
+    }
+    ///Takes the next job off the queue, waiting until one is available.
+    async fn next_job(&self, min_priority: Priority, wakeup: &mut watch::Receiver<()>) -> (u64, String) {
+        loop {
+            if let Some(job) = self.take_job(min_priority) {
+                return job;
+            }
+            wakeup.recv().await;
+        }
+    }
+    ///A single worker, which processes jobs of at least `min_priority` until the server shuts down.
+    ///Each job runs in a task of its own, so a job which panics fails on its own instead of taking the worker down with it.
+    async fn worker(self, min_priority: Priority, mut wakeup: watch::Receiver<()>, processor: Processor) {
+        loop {
+            let (job_id, post_id) = self.next_job(min_priority, &mut wakeup).await;
+            let job = processor.clone();
+            let id = post_id.clone();
+            let status = match tokio::spawn(async move { job.process(&id).await }).await {
+                Ok(Ok(post)) => JobStatus::Done { post: Box::new(post) },
+                Ok(Err(e)) => {
+                    println!("Failed to process post {}: {:?}", post_id, e);
+                    JobStatus::Failed { error: e.to_string() }
+                },
+                Err(e) => {
+                    println!("Processing post {} panicked: {}", post_id, e);
+                    JobStatus::Failed { error: "Processing the post panicked".to_owned() }
+                },
+            };
+            self.set_status(job_id, status);
+        }
+    }
+}
+
+impl JobReport {
+    ///Whether the job has reached a final state.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Done { .. } | JobStatus::Failed { .. })
+    }
+}
+
+///Everything a worker needs to process posts.
+#[derive(Clone)]
+struct Processor {
+    db: Store,
+    config: Arc<Config>,
+    filter: SharedFilter,
+    lists: AccessLists,
+    ocr: OcrPool,
+    ///Shared by every worker, so a post fetched again by any of them is read from the endpoint it was found at.
+    endpoints: EndpointCache,
+}
+
+impl Processor {
+    ///Fetches a post from imgur, then downloads, scans and filters its images unless it is on the allowlist or denylist. The result is stored in the database.
+    async fn process(&self, post_id: &str) -> Result<Post> {
+        let downloader = Downloader::new(post_id, self.endpoints.clone(), self.db.clone(), self.config.clone(), self.filter.current(), self.lists.clone(), self.ocr.clone())?;
+        let post = downloader.get_post().await?;
+        downloader.download_post_images(post).await
+    }
+}
+
+///Checks a post id is a plain imgur id, so it is safe to use in urls and paths.
+pub fn is_valid_post_id(post_id: &str) -> bool {
+    !post_id.is_empty() && post_id.len() <= 32 && post_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> JobQueue {
+        JobQueue::new(Duration::from_secs(60), 100, 10).0
+    }
+
+    fn failed() -> JobStatus {
+        JobStatus::Failed { error: String::new() }
+    }
+
+    #[test]
+    fn higher_priorities_are_taken_first_then_oldest_first() {
+        let queue = queue();
+        queue.enqueue("background", Priority::Background).unwrap();
+        queue.enqueue("prefetch1", Priority::Prefetch).unwrap();
+        queue.enqueue("visible", Priority::Visible).unwrap();
+        queue.enqueue("prefetch2", Priority::Prefetch).unwrap();
+        let order: Vec<String> = std::iter::from_fn(|| queue.take_job(Priority::Background).map(|(_, post_id)| post_id)).collect();
+        assert_eq!(order, vec!["visible", "prefetch1", "prefetch2", "background"]);
+    }
+
+    #[test]
+    fn reserved_workers_only_take_visible_posts() {
+        let queue = queue();
+        queue.enqueue("prefetch", Priority::Prefetch).unwrap();
+        assert_eq!(queue.take_job(Priority::Visible), None);
+        assert_eq!(queue.take_job(Priority::Background).map(|(_, post_id)| post_id), Some("prefetch".to_owned()));
+    }
+
+    #[test]
+    fn requests_for_a_queued_post_share_its_job_and_promote_it() {
+        let queue = queue();
+        let first = queue.enqueue("post", Priority::Background).unwrap();
+        queue.enqueue("other", Priority::Prefetch).unwrap();
+        let second = queue.enqueue("post", Priority::Visible).unwrap();
+        assert_eq!(first.job_id, second.job_id);
+        assert_eq!(second.priority, Priority::Visible);
+        assert_eq!(queue.take_job(Priority::Background), Some((first.job_id.unwrap(), "post".to_owned())));
+        //The stale entry left by the promotion is dropped.
+        assert_eq!(queue.take_job(Priority::Background).map(|(_, post_id)| post_id), Some("other".to_owned()));
+        assert_eq!(queue.take_job(Priority::Background), None);
+    }
+
+    #[test]
+    fn requests_for_a_processing_post_share_its_job() {
+        let queue = queue();
+        let first = queue.enqueue("post", Priority::Visible).unwrap();
+        queue.take_job(Priority::Background).unwrap();
+        let second = queue.enqueue("post", Priority::Visible).unwrap();
+        assert_eq!(first.job_id, second.job_id);
+        assert!(matches!(second.status, JobStatus::Processing));
+    }
+
+    #[test]
+    fn finished_jobs_are_not_reused() {
+        let queue = queue();
+        let first = queue.enqueue("post", Priority::Visible).unwrap();
+        let (job_id, _) = queue.take_job(Priority::Background).unwrap();
+        queue.set_status(job_id, failed());
+        let second = queue.enqueue("post", Priority::Visible).unwrap();
+        assert_ne!(first.job_id, second.job_id);
+        assert!(matches!(second.status, JobStatus::Queued));
+        //The finished job can still be polled.
+        assert!(queue.status(job_id).unwrap().is_finished());
+    }
+
+    #[test]
+    fn finished_jobs_expire_after_the_retention_period() {
+        let (queue, _) = JobQueue::new(Duration::from_secs(0), 100, 10);
+        queue.enqueue("post", Priority::Visible).unwrap();
+        let (job_id, _) = queue.take_job(Priority::Background).unwrap();
+        queue.set_status(job_id, failed());
+        assert!(queue.status(job_id).is_some());
+        queue.purge(&mut queue.inner.lock().unwrap());
+        assert!(queue.status(job_id).is_none());
+    }
+
+    #[test]
+    fn a_full_queue_purges_expired_jobs_before_rejecting_posts() {
+        let (queue, _) = JobQueue::new(Duration::from_secs(0), 1, 0);
+        queue.enqueue("post", Priority::Visible).unwrap();
+        let (job_id, _) = queue.take_job(Priority::Background).unwrap();
+        queue.set_status(job_id, failed());
+        //The queue was purged when it was created, so the expired job is only dropped once the interval has passed.
+        assert_eq!(queue.enqueue("other", Priority::Visible).unwrap_err(), QueueFull);
+        queue.inner.lock().unwrap().last_purge -= PURGE_INTERVAL;
+        assert!(queue.enqueue("other", Priority::Visible).is_ok());
+        assert!(queue.status(job_id).is_none());
+    }
+
+    #[test]
+    fn a_full_queue_rejects_background_and_prefetch_posts_before_visible_ones() {
+        let (queue, _) = JobQueue::new(Duration::from_secs(60), 3, 1);
+        let first = queue.enqueue("first", Priority::Background).unwrap();
+        queue.enqueue("second", Priority::Prefetch).unwrap();
+        assert_eq!(queue.enqueue("third", Priority::Prefetch).unwrap_err(), QueueFull);
+        assert_eq!(queue.enqueue("third", Priority::Background).unwrap_err(), QueueFull);
+        //Posts already queued still share their job, and the reserved jobs are left for visible posts.
+        assert_eq!(queue.enqueue("first", Priority::Prefetch).unwrap().job_id, first.job_id);
+        assert!(queue.enqueue("third", Priority::Visible).is_ok());
+        assert_eq!(queue.enqueue("fourth", Priority::Visible).unwrap_err(), QueueFull);
+        assert_eq!(queue.inner.lock().unwrap().jobs.len(), 3);
+    }
+
+    #[test]
+    fn purging_drops_the_entries_of_promoted_jobs() {
+        let queue = queue();
+        queue.enqueue("post", Priority::Background).unwrap();
+        queue.enqueue("post", Priority::Visible).unwrap();
+        assert_eq!(queue.inner.lock().unwrap().pending.len(), 2);
+        queue.purge(&mut queue.inner.lock().unwrap());
+        assert_eq!(queue.inner.lock().unwrap().pending.len(), 1);
+    }
+
+    #[test]
+    fn post_ids_are_plain_imgur_ids() {
+        assert!(is_valid_post_id("aB3dE"));
+        assert!(!is_valid_post_id(""));
+        assert!(!is_valid_post_id("../etc"));
+        assert!(!is_valid_post_id(&"a".repeat(33)));
+    }
+}
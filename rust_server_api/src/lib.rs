@@ -0,0 +1,25 @@
+//!The scraper server, its maintenance commands and its benchmarks share these modules.
+
+//Imports
+pub mod config;
+pub mod post_store;
+pub mod mongo_db_interface;
+pub mod sqlite_db_interface;
+pub mod memory_db_interface;
+pub mod imgur_interface;
+pub mod filter;
+pub mod filter_rules;
+pub mod filter_normalise;
+pub mod job_queue;
+pub mod verdict;
+pub mod rescore;
+pub mod access_lists;
+pub mod feedback;
+pub mod evaluation;
+pub mod classifier;
+pub mod profiles;
+pub mod preprocess;
+pub mod ocr_pool;
+pub mod frames;
+pub mod ocr_words;
+pub mod scan_cache;
@@ -1,47 +1,62 @@
-//Global Config
-const SERVER_IP: &str = "mongodb://localhost:27017";
-
 //Imports
-mod mongo_db_interface;
-mod imgur_interface;
-mod filter;
-
+use std::sync::Arc;
+use anyhow::Context;
+use structopt::StructOpt;
 use warp::{http, Filter, http::Response};
-use crate::mongo_db_interface::{Database, Post};
-use crate::imgur_interface::Downloader;
+use scraper_app::{classifier, evaluation, feedback, post_store, preprocess, profiles, rescore, verdict};
+use scraper_app::config::{Command, CommandLine, Config};
+use scraper_app::filter::SharedFilter;
+use scraper_app::post_store::Store;
+use scraper_app::job_queue::{CheckRequest, JobQueue, JobReport, JobStatus, PollQuery, QueueFull, is_valid_post_id};
+use scraper_app::access_lists::{AccessLists, EntryKind, ListEntry};
+use scraper_app::feedback::{FeedbackRequest, is_valid_user_id};
+use scraper_app::classifier::NaiveBayes;
+use scraper_app::profiles::Profile;
+use scraper_app::imgur_interface::get_time;
+use scraper_app::mongo_db_interface::Post;
+use scraper_app::ocr_pool::OcrPool;
 
-///An api endpoint. Takes a post from the api, and then returns the data in that post. Will OCR scan, and apply filtering if required.
-async fn process_posts_to_queue(new_post: Post, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
+///An api endpoint. Takes a post from the api and returns its verdict if it has already been processed, otherwise queues it for OCR scanning and filtering and returns 202 with the job to poll.
+async fn process_posts_to_queue(new_post: CheckRequest, api_key: Option<String>, db: Store, queue: JobQueue, lists: AccessLists, config: Arc<Config>) -> Result<impl warp::Reply, warp::Rejection> {
     println!("Request received: {}", new_post.post_url);
-    let document: Post = match db.get_post(&new_post.id).await {
-        Ok(data) => {
-            //The post already exists in the database, so return the information we already need.
-            data
+    let profile = match find_profile(api_key.as_deref(), &config) {
+        Ok(profile) => profile,
+        Err(UnknownApiKey) => return Ok(unknown_api_key()),
+    };
+    if !is_valid_post_id(&new_post.id) {
+        let response = Response::builder()
+            .status(http::StatusCode::from_u16(400).unwrap())
+            .body("Invalid post id".to_owned());
+        return Ok(response);
+    }
+    if !new_post.user.as_deref().is_none_or(is_valid_user_id) {
+        let response = Response::builder()
+            .status(http::StatusCode::from_u16(400).unwrap())
+            .body("Invalid user id".to_owned());
+        return Ok(response);
+    }
+    let report = match db.get_post(&new_post.id).await {
+        Ok(Some(mut data)) if lists.is_current(&data) && !verdict::has_unscanned_images(&data, config.ocr.video.enabled) => {
+            //The post already exists in the database, so return the information we already need, judged by this user's profile and corrected by any feedback they gave.
+            if let Some(profile) = profile {
+                profiles::apply(&mut data, profile, config.filter.unrecoverable_threshold);
+            }
+            apply_feedback(&mut data, new_post.user.as_deref(), profile, &db, &config).await;
+            JobReport { job_id: None, post_id: data.id.clone(), priority: new_post.priority, status: JobStatus::Done { post: Box::new(data) } }
         },
-        Err(e) if e.to_string() == "Failed to find document" => {
-            //The post does not exist in the database, or something went wrong.
-            let downloader = Downloader::new(&new_post.id, db.clone());
-            let post = match downloader.get_post().await {
-                Ok(data) => data,
-                Err(e) => {
-                    //Unknown error occured.
-                    println!("A serious error has occured in the database: {:?}", e);
+        Ok(_) => {
+            //The post does not exist in the database, the allowlist or denylist changed since it was stored, or some of its images failed to scan, so hand it to the workers.
+            let mut report = match queue.enqueue(&new_post.id, new_post.priority) {
+                Ok(report) => report,
+                Err(QueueFull) => {
                     let response = Response::builder()
-                        .status(http::StatusCode::from_u16(500).unwrap())
-                        .body("Database Error(2)".to_owned());
+                        .status(http::StatusCode::from_u16(503).unwrap())
+                        .body("Queue is full".to_owned());
                     return Ok(response);
-                }
+                },
             };
-
-            let download = downloader.download_post_images(post).await;
-            if download.is_err() {
-                println!("A serious error has occured in the database: {}", e);
-                let response = Response::builder()
-                    .status(http::StatusCode::from_u16(500).unwrap())
-                    .body("Database Error(3)".to_owned());
-                return Ok(response);
-            }
-            download.unwrap()
+            judge_for_user(&mut report, new_post.user.as_deref(), profile, &db, &config).await;
+            report
         },
         Err(e) => {
             //Unknown error occured.
@@ -53,46 +68,541 @@ async fn process_posts_to_queue(new_post: Post, db: Database) -> Result<impl war
         }
     };
 
+    Ok(job_response(&report))
+}
+
+///An api endpoint. Polls a job created by `process_posts_to_queue`, returning the verdict once the post has been processed.
+///The `user` query parameter applies that user's corrections, as it does when the post is first requested.
+async fn poll_job(job_id: u64, query: PollQuery, api_key: Option<String>, queue: JobQueue, db: Store, config: Arc<Config>) -> Result<impl warp::Reply, warp::Rejection> {
+    let profile = match find_profile(api_key.as_deref(), &config) {
+        Ok(profile) => profile,
+        Err(UnknownApiKey) => return Ok(unknown_api_key()),
+    };
+    if !query.user.as_deref().is_none_or(is_valid_user_id) {
+        let response = Response::builder()
+            .status(http::StatusCode::from_u16(400).unwrap())
+            .body("Invalid user id".to_owned());
+        return Ok(response);
+    }
+    let response = match queue.status(job_id) {
+        Some(mut report) => {
+            judge_for_user(&mut report, query.user.as_deref(), profile, &db, &config).await;
+            job_response(&report)
+        },
+        None => Response::builder()
+            .status(http::StatusCode::from_u16(404).unwrap())
+            .body("Unknown or expired job".to_owned()),
+    };
+    Ok(response)
+}
+
+///An api endpoint. Records a user's correction of the verdict for a post, or for one of its images. The post must already have been processed.
+///Corrections are used to train the classifier, so they are only taken from requests with the api key of a profile or the admin token.
+async fn submit_feedback(request: FeedbackRequest, api_key: Option<String>, auth: Option<String>, db: Store, config: Arc<Config>) -> Result<impl warp::Reply, warp::Rejection> {
+    let has_profile = api_key.as_deref().is_some_and(|key| profiles::find(&config.profiles, key).is_some());
+    if !has_profile && !is_admin(auth.as_deref(), &config) {
+        let response = Response::builder()
+            .status(http::StatusCode::from_u16(401).unwrap())
+            .body("Feedback needs the api key of a profile or the admin token".to_owned());
+        return Ok(response);
+    }
+    if !is_valid_post_id(&request.post_id) {
+        let response = Response::builder()
+            .status(http::StatusCode::from_u16(400).unwrap())
+            .body("Invalid post id".to_owned());
+        return Ok(response);
+    }
+    let post = match db.get_post(&request.post_id).await {
+        Ok(Some(post)) => post,
+        Ok(None) => {
+            let response = Response::builder()
+                .status(http::StatusCode::from_u16(404).unwrap())
+                .body("Unknown post, it must be checked before feedback can be given".to_owned());
+            return Ok(response);
+        },
+        Err(e) => {
+            println!("A serious error has occured in the database: {}", e);
+            let response = Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Database Error(6)".to_owned());
+            return Ok(response);
+        }
+    };
+    let feedback = match request.validate(&post, get_time().to_string()) {
+        Ok(feedback) => feedback,
+        Err(message) => {
+            let response = Response::builder()
+                .status(http::StatusCode::from_u16(400).unwrap())
+                .body(message);
+            return Ok(response);
+        }
+    };
+    let response = match db.add_feedback(feedback.clone()).await {
+        Ok(()) => Response::builder()
+            .status(http::StatusCode::from_u16(200).unwrap())
+            .body(serde_json::to_string(&feedback).unwrap()),
+        Err(e) => {
+            println!("A serious error has occured in the database: {}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Database Error(7)".to_owned())
+        }
+    };
+    Ok(response)
+}
+
+///An admin endpoint. Exports every user correction as labelled training data, one JSON object per line.
+async fn export_feedback(auth: Option<String>, config: Arc<Config>, db: Store) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_admin(auth.as_deref(), &config) {
+        return Ok(unauthorized());
+    }
+    let response = match feedback::export(&db).await {
+        Ok(examples) => Response::builder()
+            .status(http::StatusCode::from_u16(200).unwrap())
+            .header("Content-Type", "application/x-ndjson")
+            .body(to_json_lines(&examples)),
+        Err(e) => {
+            println!("A serious error has occured in the database: {}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Database Error(8)".to_owned())
+        }
+    };
+    Ok(response)
+}
+
+///Serializes values as JSON, one per line.
+fn to_json_lines<T: serde::Serialize>(values: &[T]) -> String {
+    values.iter().map(|value| serde_json::to_string(value).unwrap() + "\n").collect()
+}
+
+///An admin endpoint. Reloads the filter rules from disk. If the file is invalid the current rules are kept and the error is returned.
+///Reading and compiling the rules blocks, so it runs on a blocking thread.
+async fn reload_filter(auth: Option<String>, config: Arc<Config>, filter: SharedFilter) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_admin(auth.as_deref(), &config) {
+        return Ok(unauthorized());
+    }
+    let response = match tokio::task::spawn_blocking(move || filter.reload()).await {
+        Ok(Ok(filter)) => {
+            println!("Reloaded filter rules, {} rules loaded.", filter.rule_count());
+            Response::builder()
+                .status(http::StatusCode::from_u16(200).unwrap())
+                .body(serde_json::json!({"status": "reloaded", "rules": filter.rule_count()}).to_string())
+        },
+        Ok(Err(e)) => Response::builder()
+            .status(http::StatusCode::from_u16(422).unwrap())
+            .body(format!("{:#}", e)),
+        Err(e) => {
+            println!("Reloading the filter rules panicked: {}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Failed to reload the filter rules".to_owned())
+        },
+    };
+    Ok(response)
+}
+
+///An admin endpoint. Returns every allowlist and denylist entry.
+async fn get_lists(auth: Option<String>, config: Arc<Config>, lists: AccessLists) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_admin(auth.as_deref(), &config) {
+        return Ok(unauthorized());
+    }
     let response = Response::builder()
         .status(http::StatusCode::from_u16(200).unwrap())
-        .body(serde_json::to_string(&document).unwrap());
+        .body(serde_json::to_string(&lists.entries()).unwrap());
+    Ok(response)
+}
+
+///An admin endpoint. Adds an allowlist or denylist entry, replacing any entry for the same value. Posts it affects are processed again on their next request.
+async fn add_list_entry(auth: Option<String>, entry: ListEntry, config: Arc<Config>, lists: AccessLists) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_admin(auth.as_deref(), &config) {
+        return Ok(unauthorized());
+    }
+    let response = match lists.add(entry).await {
+        Ok(()) => Response::builder()
+            .status(http::StatusCode::from_u16(200).unwrap())
+            .body(serde_json::json!({"status": "added", "entries": lists.entries().len()}).to_string()),
+        Err(e) => {
+            println!("Failed to add access list entry: {:#}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(400).unwrap())
+                .body(format!("{:#}", e))
+        }
+    };
+    Ok(response)
+}
+
+///An admin endpoint. Removes the allowlist or denylist entry for a value.
+async fn remove_list_entry(kind: String, value: String, auth: Option<String>, config: Arc<Config>, lists: AccessLists) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_admin(auth.as_deref(), &config) {
+        return Ok(unauthorized());
+    }
+    let kind: EntryKind = match serde_json::from_value(serde_json::Value::String(kind)) {
+        Ok(kind) => kind,
+        Err(_) => return Ok(Response::builder()
+            .status(http::StatusCode::from_u16(400).unwrap())
+            .body("Entry kind must be post, account or tag".to_owned())),
+    };
+    let value = percent_encoding::percent_decode_str(&value).decode_utf8_lossy();
+    let response = match lists.remove(kind, &value).await {
+        Ok(true) => Response::builder()
+            .status(http::StatusCode::from_u16(200).unwrap())
+            .body(serde_json::json!({"status": "removed", "entries": lists.entries().len()}).to_string()),
+        Ok(false) => Response::builder()
+            .status(http::StatusCode::from_u16(404).unwrap())
+            .body("No such entry".to_owned()),
+        Err(e) => {
+            println!("Failed to remove access list entry: {:#}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Failed to save access lists".to_owned())
+        }
+    };
+    Ok(response)
+}
+
+///Checks the authorization header of an admin request against the configured admin token. Admin endpoints are disabled when no token is set.
+fn is_admin(auth: Option<&str>, config: &Config) -> bool {
+    match (&config.server.admin_token, auth.and_then(|auth| auth.strip_prefix("Bearer "))) {
+        (Some(token), Some(given)) => constant_time_eq(token.as_bytes(), given.as_bytes()),
+        _ => false,
+    }
+}
+
+///Compares two secrets in time which depends only on their length, so the admin token can't be guessed byte by byte from how quickly requests are refused.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
 
+///The response for a request to an admin endpoint without a valid token.
+fn unauthorized() -> Result<Response<String>, http::Error> {
+    Response::builder()
+        .status(http::StatusCode::from_u16(401).unwrap())
+        .body("Missing or invalid admin token".to_owned())
+}
+
+///The api key a request was sent with doesn't belong to any profile.
+struct UnknownApiKey;
+
+fn unknown_api_key() -> Result<Response<String>, http::Error> {
+    Response::builder()
+        .status(http::StatusCode::from_u16(401).unwrap())
+        .body("Unknown api key".to_owned())
+}
+
+///Finds the profile for the api key a request was sent with. Requests without a key get the default verdict, while an unknown key is rejected so a mistyped key is noticed.
+fn find_profile<'a>(api_key: Option<&str>, config: &'a Config) -> Result<Option<&'a Profile>, UnknownApiKey> {
+    match api_key {
+        None => Ok(None),
+        Some(key) => profiles::find(&config.profiles, key).map(Some).ok_or(UnknownApiKey),
+    }
+}
+
+///Judges the post of a finished job by a user's profile and corrects it by their feedback. Jobs are shared between users, so this is done on a copy for each response.
+async fn judge_for_user(report: &mut JobReport, user: Option<&str>, profile: Option<&Profile>, db: &Store, config: &Config) {
+    if let JobStatus::Done { post } = &mut report.status {
+        if let Some(profile) = profile {
+            profiles::apply(post, profile, config.filter.unrecoverable_threshold);
+        }
+        apply_feedback(post, user, profile, db, config).await;
+    }
+}
+
+///Applies the corrections a user gave for a post, after their profile. A failure to read them is logged and the verdict is returned uncorrected.
+async fn apply_feedback(post: &mut Post, user: Option<&str>, profile: Option<&Profile>, db: &Store, config: &Config) {
+    if let Some(user) = user {
+        match db.user_feedback(&post.id, user).await {
+            Ok(feedback) => feedback::apply(post, &feedback, profiles::unrecoverable_threshold(profile, config.filter.unrecoverable_threshold)),
+            Err(e) => println!("Failed to read feedback for post {}: {}", post.id, e),
+        }
+    }
+}
+
+///Builds the response for a job: 200 once it is finished, 202 while it is still waiting or processing.
+fn job_response(report: &JobReport) -> Result<Response<String>, http::Error> {
+    let status = if report.is_finished() { 200 } else { 202 };
+    Response::builder()
+        .status(http::StatusCode::from_u16(status).unwrap())
+        .body(serde_json::to_string(report).unwrap())
+}
+
+///An api endpoint. Returns summary counts of the posts held in the database.
+async fn get_stats(db: Store) -> Result<impl warp::Reply, warp::Rejection> {
+    let response = match db.stats().await {
+        Ok(stats) => Response::builder()
+            .status(http::StatusCode::from_u16(200).unwrap())
+            .body(serde_json::to_string(&stats).unwrap()),
+        Err(e) => {
+            println!("A serious error has occured in the database: {}", e);
+            Response::builder()
+                .status(http::StatusCode::from_u16(500).unwrap())
+                .body("Database Error(5)".to_owned())
+        }
+    };
     Ok(response)
 }
 
 //Json Parsers
 
 ///Parses the input json to a struct that the internal program can use. If it fails returns 403 bad request along with info to the user.
-fn authenticate_post() -> impl Filter<Extract = (Post,), Error = warp::Rejection> + Clone {
+fn authenticate_post() -> impl Filter<Extract = (CheckRequest,), Error = warp::Rejection> + Clone {
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
+///Passes a clone of some shared state into each request handler.
+fn with_state<T: Clone + Send>(state: T) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+///Evaluates the current filter rules against a labelled dataset and prints the report.
+fn run_evaluation(filter: &SharedFilter, config: &Config, dataset: &std::path::Path, top: usize, json: bool) -> Result<(), anyhow::Error> {
+    let examples = evaluation::load_dataset(dataset)?;
+    let report = evaluation::evaluate(&filter.current(), config.filter.unrecoverable_threshold, &examples, top);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
+
+///Preprocesses an image file the way downloaded images are before OCR, and saves the result.
+fn run_preprocess(config: &Config, input: &std::path::Path, output: &std::path::Path) -> Result<(), anyhow::Error> {
+    let image = image::open(input).with_context(|| format!("Failed to open image {}", input.display()))?;
+    preprocess::preprocess(image, &config.ocr.preprocess).0.save(output)
+        .with_context(|| format!("Failed to save image {}", output.display()))?;
+    Ok(())
+}
+
+///Trains a Naive Bayes classifier from the database and any extra datasets, and writes it to the model file.
+async fn run_training(db: &Store, output: &std::path::Path, include_verdicts: bool, datasets: &[std::path::PathBuf]) -> Result<(), anyhow::Error> {
+    let mut examples = classifier::training_set(db, include_verdicts).await?;
+    for dataset in datasets {
+        examples.extend(evaluation::load_dataset(dataset)?);
+    }
+    let model = NaiveBayes::train(&examples)?;
+    model.save(output)?;
+    let [safe, unsafe_] = model.example_counts();
+    println!("Trained on {} safe and {} unsafe texts with {} distinct words, wrote the model to {}.", safe, unsafe_, model.vocabulary(), output.display());
+    Ok(())
+}
+
+///Writes every user correction as labelled training data, to a file or standard output.
+async fn run_export(db: &Store, output: Option<&std::path::Path>) -> Result<(), anyhow::Error> {
+    let examples = feedback::export(db).await?;
+    let lines = to_json_lines(&examples);
+    match output {
+        Some(path) => {
+            std::fs::write(path, lines)?;
+            eprintln!("Wrote {} labelled examples to {}", examples.len(), path.display());
+        },
+        None => print!("{}", lines),
+    }
+    Ok(())
+}
+
+///Loads the configuration for a command.
+fn load_config(args: &CommandLine) -> Result<Config, anyhow::Error> {
+    Config::load(args).context("Invalid configuration")
+}
+
+///Loads the filter rules for a command.
+fn load_filter(config: &Config) -> Result<SharedFilter, anyhow::Error> {
+    SharedFilter::new(&config.filter).context("Failed to load filter rules")
+}
+
+///Runs a maintenance command from the command line. Each command loads only what it uses: the config, the filter rules and the database.
+async fn run_command(command: &Command, args: &CommandLine) -> Result<(), anyhow::Error> {
+    match command {
+        Command::Preprocess { input, output } => run_preprocess(&load_config(args)?, input, output),
+        //Evaluation only needs the filter rules, so it runs without opening the database.
+        Command::Evaluate { dataset, top, json } => {
+            let config = load_config(args)?;
+            run_evaluation(&load_filter(&config)?, &config, dataset, *top, *json)
+        },
+        //Training doesn't use the filter, and the filter may be configured to load the model being trained.
+        Command::TrainClassifier { output, include_verdicts, dataset } => {
+            let config = load_config(args)?;
+            let db = post_store::open(&config.database).await?;
+            run_training(&db, output, *include_verdicts, dataset).await
+        },
+        Command::ExportFeedback { output } => {
+            let config = load_config(args)?;
+            let db = post_store::open(&config.database).await?;
+            run_export(&db, output.as_deref()).await
+        },
+        Command::Rescore { all } => {
+            let config = load_config(args)?;
+            let filter = load_filter(&config)?.current();
+            let db = post_store::open(&config.database).await?;
+            let stats = rescore::rescore(&db, &filter, &config, *all).await?;
+            println!("Rescored posts for filter version {}: {}", filter.version(), serde_json::to_string(&stats)?);
+            Ok(())
+        },
+    }
+}
+
 //Main
 #[tokio::main]
 async fn main() -> () {
-    let db = Database::new(SERVER_IP).await.expect("Failed to init database.");
+    let args = CommandLine::from_args();
+    if let Some(command) = &args.command {
+        if let Err(e) = run_command(command, &args).await {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = match Config::load(&args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let filter = match SharedFilter::new(&config.filter) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Failed to load filter rules: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let db = match post_store::open(&config.database).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open the database: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let lists = match AccessLists::load(&config.filter.access_lists) {
+        Ok(lists) => lists,
+        Err(e) => {
+            eprintln!("Failed to load access lists: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if config.filter.watch {
+        if let Err(e) = filter.watch() {
+            eprintln!("Failed to watch the filter rule files: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+    if config.filter.rescore_on_change {
+        rescore::spawn_background(db.clone(), filter.clone(), &config);
+    }
+    let ocr = match OcrPool::start(&config.ocr) {
+        Ok(ocr) => ocr,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let queue = JobQueue::start(db.clone(), config.clone(), filter.clone(), lists.clone(), ocr);
 
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers", "Content-Type"])
-        .allow_methods(vec!["POST", "GET", "OPTIONS"]);
+        .allow_headers(vec!["User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers", "Content-Type", "Authorization", "X-Api-Key"])
+        .allow_methods(vec!["POST", "GET", "DELETE", "OPTIONS"]);
 
     let check_post = warp::post()
         .and(warp::path("check_post_priority"))
         .and(warp::path::end())
         .and(authenticate_post())
-        .and(warp::any().map(move || db.clone()))
-        .and_then(|info, db| {
-            process_posts_to_queue(info, db)
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(with_state(db.clone()))
+        .and(with_state(queue.clone()))
+        .and(with_state(lists.clone()))
+        .and(with_state(config.clone()))
+        .and_then(|info, api_key, db, queue, lists, config| {
+            process_posts_to_queue(info, api_key, db, queue, lists, config)
         });
 
-    let routes = check_post.with(cors);
+    let feedback = warp::post()
+        .and(warp::path("feedback"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(db.clone()))
+        .and(with_state(config.clone()))
+        .and_then(submit_feedback);
+
+    let poll_post = warp::get()
+        .and(warp::path("check_post_priority"))
+        .and(warp::path::param::<u64>())
+        .and(warp::path::end())
+        .and(warp::query::<PollQuery>())
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(with_state(queue.clone()))
+        .and(with_state(db.clone()))
+        .and(with_state(config.clone()))
+        .and_then(poll_job);
+
+    let stats = warp::get()
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(with_state(db.clone()))
+        .and_then(get_stats);
+
+    let reload = warp::post()
+        .and(warp::path!("admin" / "reload_filter"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(config.clone()))
+        .and(with_state(filter.clone()))
+        .and_then(reload_filter);
+
+    let list_entries = warp::get()
+        .and(warp::path!("admin" / "lists"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(config.clone()))
+        .and(with_state(lists.clone()))
+        .and_then(get_lists);
+
+    let add_entry = warp::post()
+        .and(warp::path!("admin" / "lists"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and(with_state(config.clone()))
+        .and(with_state(lists.clone()))
+        .and_then(add_list_entry);
+
+    let remove_entry = warp::delete()
+        .and(warp::path!("admin" / "lists" / String / String))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(config.clone()))
+        .and(with_state(lists.clone()))
+        .and_then(remove_list_entry);
 
-    warp::serve(routes)
-        .tls()
-        .cert_path("/home/ubuntu/PersonalProjects/0015_ImgurScraper/extension_contact_server/src/certs/cert.pem")
-        .key_path("/home/ubuntu/PersonalProjects/0015_ImgurScraper/extension_contact_server/src/certs/key1.rsa")
-        .run(([0, 0, 0, 0], 3030))
-        .await;
+    let export = warp::get()
+        .and(warp::path!("admin" / "feedback"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(config.clone()))
+        .and(with_state(db.clone()))
+        .and_then(export_feedback);
+
+    let routes = check_post.or(poll_post).or(feedback).or(stats).or(reload).or(list_entries).or(add_entry).or(remove_entry).or(export).with(cors);
+
+    let address = (config.server.address, config.server.port);
+    match (&config.server.tls_cert, &config.server.tls_key) {
+        (Some(cert), Some(key)) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .run(address)
+                .await;
+        },
+        _ => {
+            println!("No TLS certificate configured, serving over plain http.");
+            warp::serve(routes)
+                .run(address)
+                .await;
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,111 @@
+//!This module keeps posts in memory. Nothing is persisted, which makes it useful for tests and throwaway deployments.
+
+//Imports
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use crate::mongo_db_interface::Post;
+use crate::post_store::{PostStore, StoreStats};
+use crate::feedback::Feedback;
+use crate::scan_cache::{self, CachedScan};
+
+///Feedback is keyed by post id, user and image id.
+type FeedbackKey = (String, String, String);
+
+///A post store held entirely in memory, ordered by post id.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    posts: Arc<RwLock<BTreeMap<String, Post>>>,
+    ///Corrections keyed by post id, user and image id (empty for the whole post).
+    feedback: Arc<RwLock<BTreeMap<FeedbackKey, Feedback>>>,
+    ///Cached scans keyed by hash and OCR version.
+    scans: Arc<RwLock<BTreeMap<(String, String), CachedScan>>>,
+}
+
+impl MemoryStore {
+    ///Creates a new, empty store.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+///The error returned if a thread panicked while holding the lock.
+fn poisoned<T>(_: T) -> anyhow::Error {
+    anyhow!("Memory store lock was poisoned")
+}
+
+#[async_trait]
+impl PostStore for MemoryStore {
+    async fn get_post(&self, id: &str) -> Result<Option<Post>> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        Ok(posts.get(id).cloned())
+    }
+    async fn upload_post(&self, post: Post) -> Result<()> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        posts.insert(post.id.clone(), post.stored());
+        Ok(())
+    }
+    async fn replace_post(&self, post: Post, filter_version: Option<String>) -> Result<bool> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        match posts.get(&post.id) {
+            Some(stored) if stored.datetime == post.datetime && stored.filter_version == filter_version => {
+                posts.insert(post.id.clone(), post.stored());
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+    async fn delete(&self, id: &str) -> Result<bool> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        Ok(posts.remove(id).is_some())
+    }
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Vec<Post>> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        let range = match after {
+            Some(after) => posts.range::<str, _>((Bound::Excluded(after), Bound::Unbounded)),
+            None => posts.range::<str, _>(..),
+        };
+        Ok(range.take(limit).map(|(_, post)| post.clone()).collect())
+    }
+    async fn stats(&self) -> Result<StoreStats> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        Ok(StoreStats {
+            posts: posts.len() as u64,
+            unrecoverable_posts: posts.values().filter(|post| post.unrecoverable == Some(true)).count() as u64,
+            images: posts.values().map(|post| post.images.len() as u64).sum(),
+        })
+    }
+    async fn add_feedback(&self, feedback: Feedback) -> Result<()> {
+        let mut stored = self.feedback.write().map_err(poisoned)?;
+        let key = (feedback.post_id.clone(), feedback.user.clone(), feedback.image_id.clone().unwrap_or_default());
+        stored.insert(key, feedback);
+        Ok(())
+    }
+    async fn user_feedback(&self, post_id: &str, user: &str) -> Result<Vec<Feedback>> {
+        let stored = self.feedback.read().map_err(poisoned)?;
+        Ok(stored.values().filter(|f| f.post_id == post_id && f.user == user).cloned().collect())
+    }
+    async fn list_feedback(&self, skip: usize, limit: usize) -> Result<Vec<Feedback>> {
+        let stored = self.feedback.read().map_err(poisoned)?;
+        Ok(stored.values().skip(skip).take(limit).cloned().collect())
+    }
+    async fn similar_scans(&self, hash: u64, ocr_version: &str) -> Result<Vec<CachedScan>> {
+        let scans = self.scans.read().map_err(poisoned)?;
+        let bands = scan_cache::bands(hash);
+        Ok(scans.values()
+            .filter(|scan| scan.ocr_version == ocr_version)
+            .filter(|scan| scan_cache::parse_hash(&scan.hash)
+                .is_some_and(|other| scan_cache::bands(other).iter().zip(&bands).any(|(a, b)| a == b)))
+            .cloned()
+            .collect())
+    }
+    async fn cache_scan(&self, scan: CachedScan) -> Result<()> {
+        let mut scans = self.scans.write().map_err(poisoned)?;
+        scans.insert((scan.hash.clone(), scan.ocr_version.clone()), scan);
+        Ok(())
+    }
+}
@@ -1,29 +1,107 @@
-///This module handles connections to and from the imgur database.
+//!This module handles connections to and from the imgur database.
 
 //Imports
-use mongodb::{Client, options::ClientOptions, bson::{doc, Bson}, bson};
+use mongodb::{Client, options::{ClientOptions, FindOptions, ReplaceOptions}, bson::{doc, Bson}, bson};
 use serde::{Serialize, Deserialize};
-use anyhow::{Result};
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use futures::StreamExt;
+use crate::config::DatabaseConfig;
+use crate::post_store::{PostStore, StoreStats};
+use crate::access_lists::ListAction;
+use crate::feedback::Feedback;
+use crate::scan_cache::{self, CachedScan};
 
 ///Database struct to handle connections to the database and various collections in the mongo db.
 #[derive(Clone)]
 pub struct Database {
-    server_ip: String,
-    db: mongodb::Database,
-    admin: mongodb::Collection,
     posts: mongodb::Collection,
+    feedback: mongodb::Collection,
+    scans: mongodb::Collection,
 }
+///The piece of a post a filter match was found in.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonSource {
+    Title,
+    Description,
+    ImageDescription,
+    OcrText,
+    ///A denylist entry for the post id.
+    PostId,
+    ///A denylist entry for the account which uploaded the post.
+    Account,
+    ///A denylist entry for one of the tags of the post.
+    Tag,
+}
+
+///Reason struct records a term which caused a post or image to be marked unrecoverable.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Reason {
+    pub source: ReasonSource,
+    pub category: String,
+    pub term: String,
+    ///Character offsets of the match within the source text.
+    pub start: usize,
+    pub end: usize,
+}
+
+///The score of one category in one piece of text, kept whether or not it reached its threshold so user profiles can judge the text by their own thresholds.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Score {
+    pub source: ReasonSource,
+    pub category: String,
+    pub score: f32,
+    ///The threshold from the rule file, or the classifier threshold, which the default verdict uses.
+    pub threshold: f32,
+}
+
+///A word read by OCR, with how sure tesseract was of it and where it is, in pixels of the downloaded image or frame.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Word {
+    pub text: String,
+    ///From 0 to 100.
+    pub confidence: f32,
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+///The text read from one scanned frame of an animated GIF or video.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FrameText {
+    ///Seconds from the start.
+    pub time: f32,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>,
+}
+
 ///Image struct models how images are stored in the database.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Image {
     pub id: String,
     pub description: String,
     pub url: String,
     pub unrecoverable: Option<bool>,
     pub image_ocr_text: Option<String>,
+    #[serde(default)]
+    pub reasons: Vec<Reason>,
+    ///Every category scored in the description and OCR text, with every match behind them. Unset for images stored before scores were recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scores: Vec<Score>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<Reason>,
+    ///Every frame scanned from an animated GIF or video, whether or not text was found. `image_ocr_text` holds the text of every frame merged.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<FrameText>,
+    ///Every word of a still image kept after discarding those below `ocr.min_confidence`, which `image_ocr_text` is made from. Frames of animations and videos list their own words.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>,
 }
 ///Post struct models how posts are stored in the database.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Post {
     pub id: String,
     pub images: Vec<Image>,
@@ -32,57 +110,253 @@ pub struct Post {
     pub unrecoverable: Option<bool>,
     pub description: Option<String>,
     pub title: Option<String>,
+    ///Matches found in the title and description. Matches in images are listed on each image.
+    #[serde(default)]
+    pub reasons: Vec<Reason>,
+    ///Every category scored in the title and description, with every match behind them. Unset for posts stored before scores were recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scores: Vec<Score>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<Reason>,
+    ///Version of the filter rules the verdict was computed with. Unset for posts stored before versions were recorded.
+    #[serde(default)]
+    pub filter_version: Option<String>,
+    ///The account which uploaded the post, if imgur reported one.
+    #[serde(default)]
+    pub account: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    ///Set when the verdict came from the allowlist or denylist rather than the filter.
+    #[serde(default)]
+    pub listed: Option<ListAction>,
+    ///The requesting user's corrections, attached when the post is returned to them. They are stored separately, never with the post.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feedback: Vec<Feedback>,
+}
+
+///The bands of a hash tagged with their position, so one indexed array can be searched for any of them.
+fn band_keys(hash: u64) -> Vec<i32> {
+    scan_cache::bands(hash).iter().enumerate().map(|(i, band)| (i as i32) << 16 | *band as i32).collect()
+}
+
+impl Post {
+    ///The post as every store keeps it. Corrections are attached to a post when it is returned to a user, and are never stored with it.
+    pub fn stored(self) -> Post {
+        Post { feedback: vec![], ..self }
+    }
+}
+
+#[cfg(test)]
+impl Image {
+    ///An image whose OCR read `ocr_text`, for tests.
+    pub fn scanned(id: &str, ocr_text: &str, unrecoverable: Option<bool>) -> Image {
+        Image {
+            id: id.to_owned(),
+            url: format!("https://i.imgur.com/{}.png", id),
+            unrecoverable,
+            image_ocr_text: Some(ocr_text.to_owned()),
+            ..Image::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Post {
+    ///A gallery post which hasn't been judged yet, for tests.
+    pub fn titled(id: &str, title: &str, images: Vec<Image>) -> Post {
+        Post {
+            id: id.to_owned(),
+            images,
+            post_url: format!("https://imgur.com/gallery/{}", id),
+            title: Some(title.to_owned()),
+            ..Post::default()
+        }
+    }
+}
+
+///Converts a post into the document stored for it.
+fn post_document(post: &Post) -> Result<bson::Document> {
+    let post = post.clone().stored();
+    match bson::to_bson(&post)? {
+        Bson::Document(document) => Ok(document),
+        _ => bail!("Post {} did not serialize to a document", post.id),
+    }
 }
 
 impl Database {
     ///Creates a new database instance.
-    pub async fn new(server_ip: &str) -> Result<Database, anyhow::Error> {
-        let client_options = ClientOptions::parse(server_ip).await?;
+    pub async fn new(config: &DatabaseConfig) -> Result<Database, anyhow::Error> {
+        let client_options = ClientOptions::parse(&config.uri).await?;
         let client = Client::with_options(client_options)?;
-        let db = client.database("imgur_scraper");
+        let db = client.database(&config.name);
+        //Make sure each post can only be stored once.
+        let index = doc!{
+            "createIndexes": "posts",
+            "indexes": [{"key": {"id": 1}, "name": "id_unique", "unique": true}],
+        };
+        if let Err(e) = db.run_command(index, None).await {
+            println!("Failed to create unique index on posts, duplicate posts may already exist: {}", e);
+        }
+        let index = doc!{
+            "createIndexes": "feedback",
+            "indexes": [{"key": {"post_id": 1, "user": 1, "image_id": 1}, "name": "feedback_unique", "unique": true}],
+        };
+        if let Err(e) = db.run_command(index, None).await {
+            println!("Failed to create unique index on feedback: {}", e);
+        }
+        let index = doc!{
+            "createIndexes": "scans",
+            "indexes": [
+                {"key": {"hash": 1, "ocr_version": 1}, "name": "scans_unique", "unique": true},
+                {"key": {"bands": 1}, "name": "scans_bands"},
+            ],
+        };
+        if let Err(e) = db.run_command(index, None).await {
+            println!("Failed to create indexes on scans: {}", e);
+        }
         Ok(Database {
-            server_ip: server_ip.to_owned(),
-            admin: db.collection("admin"),
             posts: db.collection("posts"),
-            db: db,
+            feedback: db.collection("feedback"),
+            scans: db.collection("scans"),
         })
     }
-    ///Uploads a single new post instance to the mongodb database.
-    pub async fn upload_post(&self, post: Post) -> Result<mongodb::results::InsertOneResult, anyhow::Error> {
-        let mut images: Vec<mongodb::bson::Document> = vec![];
-        for image in post.images {
-            let new_image = doc!{
-                "id": image.id,
-                "description": image.description,
-                "url": image.url,
-                "unrecoverable": image.unrecoverable.unwrap_or(true),
-                "image_ocr_text" : image.image_ocr_text.unwrap_or("".to_owned()),
-            };
-            images.push(new_image);
-        }
-        let new_post = doc!{
-            "id": post.id,
-            "images": images,
-            "post_url": post.post_url,
-            "datetime": post.datetime,
-            "unrecoverable": post.unrecoverable.unwrap_or(true),
-            "description": post.description.unwrap_or("".to_owned()),
-            "title": post.title.unwrap_or("".to_owned()),
+}
+
+#[async_trait]
+impl PostStore for Database {
+    ///Uploads a single post instance to the mongodb database, replacing any previous version of it.
+    async fn upload_post(&self, post: Post) -> Result<(), anyhow::Error> {
+        let document = post_document(&post)?;
+        let options = ReplaceOptions::builder().upsert(Some(true)).build();
+        self.posts.replace_one(doc!{"id": post.id}, document, options).await?;
+        Ok(())
+    }
+    ///Replaces a post only if the stored copy still has the same download time and filter version, in a single conditional write.
+    async fn replace_post(&self, post: Post, filter_version: Option<String>) -> Result<bool, anyhow::Error> {
+        let document = post_document(&post)?;
+        let filter = doc!{
+            "id": post.id.as_str(),
+            "datetime": post.datetime.as_str(),
+            "filter_version": bson::to_bson(&filter_version)?,
         };
-        
-        let result = self.posts.insert_one(new_post, None).await?;
-        Ok(result)
+        let result = self.posts.replace_one(filter, document, None).await?;
+        Ok(result.matched_count > 0)
     }
-    ///Searches for a post in the database, if it can't find the post then t returns an error.
-    pub async fn get_post(&self, id: &str) -> Result<Post, anyhow::Error>{
+    ///Searches for a post in the database, returning `None` if it has not been stored.
+    async fn get_post(&self, id: &str) -> Result<Option<Post>, anyhow::Error> {
         let filter = doc!{"id": id};
         let cursor = self.posts.find_one(filter, None).await?;
         match cursor {
             Some (doc) => {
                 let data: Post = bson::from_bson(Bson::Document(doc))?;
-                Ok(data)
+                Ok(Some(data))
+            },
+            None => Ok(None),
+        }
+    }
+    ///Deletes a post from the database.
+    async fn delete(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let result = self.posts.delete_one(doc!{"id": id}, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+    ///Lists posts from the database ordered by id, through the unique index on their ids.
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Vec<Post>, anyhow::Error> {
+        let options = FindOptions::builder()
+            .sort(Some(doc!{"id": 1}))
+            .limit(Some(limit as i64))
+            .build();
+        let filter = after.map(|after| doc!{"id": {"$gt": after}});
+        let mut cursor = self.posts.find(filter, options).await?;
+        let mut posts = vec![];
+        while let Some(doc) = cursor.next().await {
+            posts.push(bson::from_bson(Bson::Document(doc?))?);
+        }
+        Ok(posts)
+    }
+    ///Counts the posts and images in the database.
+    async fn stats(&self) -> Result<StoreStats, anyhow::Error> {
+        let posts = self.posts.count_documents(None, None).await?;
+        let unrecoverable_posts = self.posts.count_documents(doc!{"unrecoverable": true}, None).await?;
+        let pipeline = vec![doc!{"$group": {"_id": Bson::Null, "images": {"$sum": {"$size": "$images"}}}}];
+        let mut cursor = self.posts.aggregate(pipeline, None).await?;
+        let images = match cursor.next().await {
+            Some(doc) => match doc?.get("images") {
+                Some(Bson::Int32(n)) => *n as u64,
+                Some(Bson::Int64(n)) => *n as u64,
+                _ => 0,
             },
-            None => Err(anyhow::anyhow!("Failed to find document")),
+            None => 0,
+        };
+        Ok(StoreStats {
+            posts: posts as u64,
+            unrecoverable_posts: unrecoverable_posts as u64,
+            images,
+        })
+    }
+    ///Stores a correction, replacing the user's earlier correction for the same post and image.
+    async fn add_feedback(&self, feedback: Feedback) -> Result<(), anyhow::Error> {
+        let filter = doc!{
+            "post_id": feedback.post_id.clone(),
+            "user": feedback.user.clone(),
+            "image_id": bson::to_bson(&feedback.image_id)?,
+        };
+        let mut document = match bson::to_bson(&feedback)? {
+            Bson::Document(document) => document,
+            _ => bail!("Feedback for post {} did not serialize to a document", feedback.post_id),
+        };
+        //Store a null image id for corrections to the whole post, so the unique index treats them as one value.
+        document.insert("image_id", bson::to_bson(&feedback.image_id)?);
+        let options = ReplaceOptions::builder().upsert(Some(true)).build();
+        self.feedback.replace_one(filter, document, options).await?;
+        Ok(())
+    }
+    ///Finds every correction a user has made to a post.
+    async fn user_feedback(&self, post_id: &str, user: &str) -> Result<Vec<Feedback>, anyhow::Error> {
+        let mut cursor = self.feedback.find(doc!{"post_id": post_id, "user": user}, None).await?;
+        let mut feedback = vec![];
+        while let Some(doc) = cursor.next().await {
+            feedback.push(bson::from_bson(Bson::Document(doc?))?);
+        }
+        Ok(feedback)
+    }
+    ///Finds cached scans sharing a band of the hash, through the index on their bands.
+    async fn similar_scans(&self, hash: u64, ocr_version: &str) -> Result<Vec<CachedScan>, anyhow::Error> {
+        let filter = doc!{"ocr_version": ocr_version, "bands": {"$in": band_keys(hash)}};
+        let mut cursor = self.scans.find(filter, None).await?;
+        let mut scans = vec![];
+        while let Some(doc) = cursor.next().await {
+            scans.push(bson::from_bson(Bson::Document(doc?))?);
         }
+        Ok(scans)
+    }
+    ///Caches a scan, replacing any cached scan with the same hash and OCR settings.
+    async fn cache_scan(&self, scan: CachedScan) -> Result<(), anyhow::Error> {
+        let hash = match scan_cache::parse_hash(&scan.hash) {
+            Some(hash) => hash,
+            None => bail!("Malformed image hash {}", scan.hash),
+        };
+        let filter = doc!{"hash": scan.hash.clone(), "ocr_version": scan.ocr_version.clone()};
+        let mut document = match bson::to_bson(&scan)? {
+            Bson::Document(document) => document,
+            _ => bail!("The scan of image {} did not serialize to a document", scan.image_id),
+        };
+        document.insert("bands", band_keys(hash));
+        let options = ReplaceOptions::builder().upsert(Some(true)).build();
+        self.scans.replace_one(filter, document, options).await?;
+        Ok(())
     }
-}
\ No newline at end of file
+    ///Lists corrections from the database ordered by post id.
+    async fn list_feedback(&self, skip: usize, limit: usize) -> Result<Vec<Feedback>, anyhow::Error> {
+        let options = FindOptions::builder()
+            .sort(Some(doc!{"post_id": 1, "user": 1, "image_id": 1}))
+            .skip(Some(skip as i64))
+            .limit(Some(limit as i64))
+            .build();
+        let mut cursor = self.feedback.find(None, options).await?;
+        let mut feedback = vec![];
+        while let Some(doc) = cursor.next().await {
+            feedback.push(bson::from_bson(Bson::Document(doc?))?);
+        }
+        Ok(feedback)
+    }
+}
@@ -0,0 +1,279 @@
+//!This module runs OCR on a fixed pool of threads, each holding a tesseract engine which is loaded once at startup and reused for every image.
+//!Tesseract blocks for as long as it reads an image, so it is kept off the tokio executor which serves requests.
+
+//Imports
+use std::{
+    ffi::CStr,
+    fs,
+    io::{BufReader, Cursor, Read},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Semaphore};
+use anyhow::{Result, anyhow, bail};
+use leptess::{
+    capi,
+    leptonica::{self, Pix},
+    tesseract::{TessApi, MAX_CREDIBLE_RESOLUTION, MIN_CREDIBLE_RESOLUTION},
+};
+use crate::config::OcrConfig;
+use crate::frames::{self, TimedFrame};
+use crate::mongo_db_interface::{FrameText, Word};
+use crate::ocr_words;
+use crate::preprocess::{self, Transform};
+
+///A downloaded image or video.
+pub enum Media {
+    ///An image held in memory.
+    Memory(bytes::Bytes),
+    ///An image which was too large to hold in memory.
+    Spooled(SpoolFile),
+    ///A video, which is always spooled as ffmpeg reads it from a file.
+    Video(SpoolFile),
+}
+
+///The text found in an image or video.
+#[derive(Clone, Debug, Default)]
+pub struct Scanned {
+    ///All of the text, merged from every frame of an animation or video.
+    pub text: String,
+    ///The text of each scanned frame of an animation or video, empty for still images.
+    pub frames: Vec<FrameText>,
+    ///The words of a still image kept after discarding those below `ocr.min_confidence`. Frames list their own words.
+    pub words: Vec<Word>,
+}
+
+///Counts spool files created by this process, so concurrent downloads never share a file.
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+///A file an oversized image is spooled to. The file is deleted when this is dropped, even if scanning panics.
+pub struct SpoolFile {
+    pub path: PathBuf,
+}
+
+impl SpoolFile {
+    ///Picks a new file name in the spool directory. The file is created by the caller.
+    pub fn new(dir: &Path) -> Self {
+        let count = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        SpoolFile {
+            path: dir.join(format!("scraper-spool-{}-{}", std::process::id(), count)),
+        }
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                println!("Failed to remove spooled image {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+///An image waiting for an OCR thread.
+struct Job {
+    media: Media,
+    ///Jobs still queued at their deadline are dropped without being scanned, nobody is waiting for them any more.
+    deadline: Instant,
+    reply: oneshot::Sender<Result<Scanned>>,
+}
+
+///A handle to the OCR threads. Cloning it is cheap, every clone submits to the same threads.
+#[derive(Clone)]
+pub struct OcrPool {
+    sender: Arc<Mutex<mpsc::Sender<Job>>>,
+    ///One permit for every image which may be scanning or queued. Submitting waits for a permit, and the thread hands it back once the image is done.
+    slots: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl OcrPool {
+    ///Starts the OCR threads, waiting until every one has loaded its engine so a missing language model stops the server at startup.
+    pub fn start(config: &OcrConfig) -> Result<OcrPool> {
+        if config.video.enabled {
+            frames::check_video_tools(&config.video)?;
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let slots = Arc::new(Semaphore::new(config.workers + config.queue_size));
+        let (ready, started) = mpsc::channel();
+        for i in 0..config.workers {
+            let receiver = receiver.clone();
+            let slots = slots.clone();
+            let ready = ready.clone();
+            let config = config.clone();
+            thread::Builder::new()
+                .name(format!("ocr-{}", i))
+                .spawn(move || {
+                    let engine = match TessApi::new(config.tessdata(), &config.languages) {
+                        Ok(engine) => engine,
+                        Err(e) => {
+                            let _ = ready.send(Err(anyhow!("{}", e)));
+                            return;
+                        }
+                    };
+                    let _ = ready.send(Ok(()));
+                    worker(engine, &config, &receiver, &slots);
+                })?;
+        }
+        for _ in 0..config.workers {
+            match started.recv() {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => bail!("Failed to load the OCR engine: {}", e),
+                Err(_) => bail!("An OCR thread stopped while loading its engine"),
+            }
+        }
+        Ok(OcrPool {
+            sender: Arc::new(Mutex::new(sender)),
+            slots,
+            timeout: Duration::from_secs(config.timeout_secs),
+        })
+    }
+    ///Scans an image or video on the pool, returning any text it finds.
+    ///Waits for room in the queue when it is full. Fails if it isn't scanned within `ocr.timeout_secs`, counting the time spent waiting.
+    pub async fn scan(&self, media: Media) -> Result<Scanned> {
+        let deadline = Instant::now() + self.timeout;
+        let permit = tokio::time::timeout_at(deadline.into(), self.slots.acquire()).await
+            .map_err(|_| anyhow!("Timed out waiting for room in the OCR queue"))?;
+        //The thread which scans the image returns the permit, so a timed out image still holds its slot until the thread is free again.
+        permit.forget();
+        let (reply, result) = oneshot::channel();
+        let job = Job { media, deadline, reply };
+        if self.sender.lock().unwrap().send(job).is_err() {
+            self.slots.add_permits(1);
+            bail!("The OCR threads have stopped");
+        }
+        match tokio::time::timeout_at(deadline.into(), result).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(_)) => bail!("The OCR thread stopped before scanning the image"),
+            Err(_) => bail!("OCR timed out after {}s", self.timeout.as_secs()),
+        }
+    }
+}
+
+///A single OCR thread, which scans images with its engine until the pool is dropped.
+fn worker(mut engine: TessApi, config: &OcrConfig, receiver: &Mutex<mpsc::Receiver<Job>>, slots: &Semaphore) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let Job { media, deadline, reply } = job;
+        let result = if Instant::now() >= deadline {
+            Err(anyhow!("Timed out waiting for an OCR thread"))
+        } else {
+            match panic::catch_unwind(AssertUnwindSafe(|| scan(&mut engine, media, config, deadline))) {
+                Ok(result) => result,
+                Err(_) => {
+                    //The engine may have been left halfway through an image, so it is replaced.
+                    println!("OCR panicked, reloading the engine of {}", thread::current().name().unwrap_or("an OCR thread"));
+                    match TessApi::new(config.tessdata(), &config.languages) {
+                        Ok(fresh) => engine = fresh,
+                        Err(e) => println!("Failed to reload the OCR engine, keeping the old one: {}", e),
+                    }
+                    Err(anyhow!("OCR panicked"))
+                }
+            }
+        };
+        //The submitter has gone if it timed out, the result is simply dropped.
+        let _ = reply.send(result);
+        slots.add_permits(1);
+    }
+}
+
+///Returns true if a downloaded image is a GIF, reading the start of a spooled file.
+fn is_gif(media: &Media) -> Result<bool> {
+    match media {
+        Media::Memory(bytes) => Ok(frames::is_gif(bytes)),
+        Media::Spooled(spool_file) => {
+            let mut header = vec![];
+            fs::File::open(&spool_file.path)?.take(6).read_to_end(&mut header)?;
+            Ok(frames::is_gif(&header))
+        },
+        Media::Video(_) => Ok(false),
+    }
+}
+
+///Scans an image with tesseract, returning the text and words it is confident of. Positions are mapped back through the preprocessing transform.
+fn recognise(engine: &mut TessApi, image: &Pix, transform: &Transform, config: &OcrConfig) -> Result<(String, Vec<Word>)> {
+    engine.set_image(image);
+    //Images without a believable resolution are read as 70 dpi.
+    let resolution = engine.get_source_y_resolution();
+    if !(MIN_CREDIBLE_RESOLUTION..=MAX_CREDIBLE_RESOLUTION).contains(&resolution) {
+        engine.set_source_resolution(70);
+    }
+    //Safety: the handle is owned by the engine, and tesseract hands over the text, which is freed below.
+    let tsv = unsafe {
+        let raw = capi::TessBaseAPIGetTsvText(engine.raw, 0);
+        if raw.is_null() {
+            bail!("Tesseract could not read the image");
+        }
+        let tsv = CStr::from_ptr(raw).to_str().map(str::to_owned);
+        capi::TessDeleteText(raw);
+        tsv?
+    };
+    Ok(ocr_words::read(&tsv, config.min_confidence, transform))
+}
+
+///Scans each chosen frame of an animation or video, keeping the text of every frame as well as all of it merged.
+fn scan_frames(engine: &mut TessApi, frames: Vec<TimedFrame>, config: &OcrConfig) -> Result<Scanned> {
+    let mut scanned = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let (prepared, transform) = preprocess::prepare_image(frame.image, &config.preprocess)?;
+        let pix = leptonica::pix_read_mem(&prepared).map_err(|_| anyhow!("Tesseract could not read a frame"))?;
+        let (text, words) = recognise(engine, &pix, &transform, config)?;
+        scanned.push(FrameText { time: frame.time, text, words });
+    }
+    Ok(Scanned {
+        text: frames::merge_text(scanned.iter().map(|frame| frame.text.as_str())),
+        frames: scanned,
+        words: vec![],
+    })
+}
+
+///Prepares an image with the configured preprocessing and scans it with tesseract OCR, returns any text it finds. Animations and videos have a few representative frames scanned.
+fn scan(engine: &mut TessApi, media: Media, config: &OcrConfig, deadline: Instant) -> Result<Scanned> {
+    if is_gif(&media)? {
+        let frames = match &media {
+            Media::Memory(bytes) => frames::gif_frames(|| Ok(Cursor::new(bytes.clone())), config.gif_frames)?,
+            Media::Spooled(spool_file) | Media::Video(spool_file) => frames::gif_frames(|| Ok(BufReader::new(fs::File::open(&spool_file.path)?)), config.gif_frames)?,
+        };
+        drop(media);
+        return scan_frames(engine, frames, config);
+    }
+    //A spooled image is never read back into memory, so tesseract reads the file as downloaded without preprocessing.
+    let (bytes, spooled) = match media {
+        Media::Memory(bytes) => (Some(bytes), None),
+        Media::Spooled(spool_file) => (None, Some(spool_file)),
+        Media::Video(spool_file) => {
+            let frames = frames::video_frames(&spool_file.path, &config.video, config.video.frames, deadline)?;
+            drop(spool_file);
+            return scan_frames(engine, frames, config);
+        },
+    };
+    let (bytes, transform): (Option<bytes::Bytes>, Transform) = match bytes.as_deref().map(|bytes| preprocess::prepare(bytes, &config.preprocess)) {
+        Some(Ok(Some((prepared, transform)))) => (Some(prepared.into()), transform),
+        Some(Err(e)) => {
+            //Tesseract reads some formats the image crate can't, so those are scanned as downloaded.
+            println!("Failed to preprocess image, scanning it as downloaded: {:#}", e);
+            (bytes, Transform::identity())
+        },
+        _ => (bytes, Transform::identity()),
+    };
+
+    let pix = match (&bytes, &spooled) {
+        (Some(bytes), _) => leptonica::pix_read_mem(bytes),
+        (None, Some(spool_file)) => leptonica::pix_read(&spool_file.path),
+        (None, None) => unreachable!("an image is either in memory or spooled"),
+    };
+    let pix = pix.map_err(|_| anyhow!("Tesseract could not read the image"))?;
+    let (text, words) = recognise(engine, &pix, &transform, config)?;
+    Ok(Scanned { text, frames: vec![], words })
+}
@@ -0,0 +1,108 @@
+//!This module reads the TSV output of tesseract, which lists every word found with its confidence and bounding box, and rebuilds the text from the words worth keeping.
+
+//Imports
+use crate::mongo_db_interface::Word;
+use crate::preprocess::Transform;
+
+///The level tesseract gives rows describing a single word. Lower levels describe pages, blocks, paragraphs and lines.
+const WORD_LEVEL: u32 = 5;
+
+///A word and the line it was read on.
+struct Located {
+    ///The block, paragraph and line numbers, which together identify a line of text.
+    line: (u32, u32, u32),
+    word: Word,
+}
+
+///Parses the word rows of tesseract's TSV output. Each row holds the level, page, block, paragraph, line and word numbers, the box, the confidence and the text.
+fn parse(tsv: &str) -> Vec<Located> {
+    tsv.lines()
+        .filter_map(|row| {
+            let fields: Vec<&str> = row.splitn(12, '\t').collect();
+            if fields.len() < 12 || fields[0].parse::<u32>().ok()? != WORD_LEVEL {
+                return None;
+            }
+            let number = |i: usize| fields[i].parse::<u32>().ok();
+            let text = fields[11].trim();
+            if text.is_empty() {
+                return None;
+            }
+            Some(Located {
+                line: (number(2)?, number(3)?, number(4)?),
+                word: Word {
+                    text: text.to_owned(),
+                    confidence: fields[10].parse().ok()?,
+                    left: number(6)?,
+                    top: number(7)?,
+                    width: number(8)?,
+                    height: number(9)?,
+                },
+            })
+        })
+        .collect()
+}
+
+///Reads tesseract's TSV output, discarding words below `min_confidence`. Returns the text of the kept words, a line of text per line tesseract found, and the kept words with their boxes mapped onto the original image.
+pub fn read(tsv: &str, min_confidence: f32, transform: &Transform) -> (String, Vec<Word>) {
+    let mut text = String::new();
+    let mut words = vec![];
+    let mut current_line = None;
+    for Located { line, mut word } in parse(tsv) {
+        if word.confidence < min_confidence {
+            continue;
+        }
+        if current_line.is_some() {
+            text.push(if current_line == Some(line) { ' ' } else { '\n' });
+        }
+        current_line = Some(line);
+        text.push_str(&word.text);
+        let (left, top, width, height) = transform.to_original(word.left, word.top, word.width, word.height);
+        word.left = left;
+        word.top = top;
+        word.width = width;
+        word.height = height;
+        words.push(word);
+    }
+    (text, words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TSV: &str = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext
+1\t1\t0\t0\t0\t0\t0\t0\t200\t100\t-1\t
+4\t1\t1\t1\t1\t0\t10\t10\t150\t20\t-1\t
+5\t1\t1\t1\t1\t1\t10\t10\t60\t20\t96.5\tVote
+5\t1\t1\t1\t1\t2\t80\t10\t30\t20\t12.0\t~~
+5\t1\t1\t1\t1\t3\t120\t10\t40\t20\t88.0\ttoday
+5\t1\t1\t1\t2\t1\t10\t40\t50\t20\t91.0\tpolls
+5\t1\t1\t1\t2\t2\t70\t40\t20\t20\t90.0\t 
+5\t1\t2\t1\t1\t1\t10\t70\t80\t20\t75.0\topen
+";
+
+    #[test]
+    fn read_keeps_confident_words_by_line() {
+        let (text, words) = read(TSV, 50.0, &Transform::identity());
+        assert_eq!(text, "Vote today\npolls\nopen");
+        assert_eq!(words.iter().map(|word| word.text.as_str()).collect::<Vec<&str>>(), vec!["Vote", "today", "polls", "open"]);
+        assert_eq!(words[0], Word { text: "Vote".to_owned(), confidence: 96.5, left: 10, top: 10, width: 60, height: 20 });
+    }
+
+    #[test]
+    fn read_discards_words_below_the_confidence() {
+        let (text, words) = read(TSV, 0.0, &Transform::identity());
+        assert_eq!(text, "Vote ~~ today\npolls\nopen");
+        assert_eq!(words.len(), 5);
+        let (text, words) = read(TSV, 95.0, &Transform::identity());
+        assert_eq!(text, "Vote");
+        assert_eq!(words.len(), 1);
+    }
+
+    #[test]
+    fn read_ignores_malformed_rows() {
+        let (text, words) = read("5\t1\t1\t1\t1\t1\tten\t10\t60\t20\t96\tVote\n5\t1\t1\t1\n", 0.0, &Transform::identity());
+        assert_eq!(text, "");
+        assert!(words.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//!This module defines the storage interface for processed posts, and selects the backend named in the config.
+
+//Imports
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::Serialize;
+use anyhow::Result;
+use crate::config::{DatabaseConfig, StorageBackend};
+use crate::mongo_db_interface::{Database, Post};
+use crate::sqlite_db_interface::SqliteStore;
+use crate::memory_db_interface::MemoryStore;
+use crate::feedback::Feedback;
+use crate::scan_cache::CachedScan;
+
+///A shared handle to whichever storage backend is in use.
+pub type Store = Arc<dyn PostStore>;
+
+///Summary counts of the posts held by a store.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct StoreStats {
+    pub posts: u64,
+    ///Posts judged unrecoverable. Posts without a verdict are not counted.
+    pub unrecoverable_posts: u64,
+    pub images: u64,
+}
+
+///The operations every storage backend must provide.
+#[async_trait]
+pub trait PostStore: Send + Sync {
+    ///Searches for a post by id, returning `None` if it has not been stored.
+    async fn get_post(&self, id: &str) -> Result<Option<Post>>;
+    ///Stores a processed post, replacing any existing post with the same id.
+    async fn upload_post(&self, post: Post) -> Result<()>;
+    ///Replaces a stored post only if it still has the download time of `post` and was judged by `filter_version`, so a post processed again since it was read is not overwritten. Returns whether it was replaced.
+    async fn replace_post(&self, post: Post, filter_version: Option<String>) -> Result<bool>;
+    ///Removes a post, returning whether it existed.
+    async fn delete(&self, id: &str) -> Result<bool>;
+    ///Lists stored posts ordered by id, starting after the id `after` and returning at most `limit`. Paging by id never skips or repeats a post when others are added or removed between pages.
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Vec<Post>>;
+    ///Counts the posts and images held by the store.
+    async fn stats(&self) -> Result<StoreStats>;
+    ///Stores a user's correction, replacing any earlier correction from the same user for the same post and image.
+    async fn add_feedback(&self, feedback: Feedback) -> Result<()>;
+    ///Returns every correction a user has made to a post and its images.
+    async fn user_feedback(&self, post_id: &str, user: &str) -> Result<Vec<Feedback>>;
+    ///Lists corrections ordered by post id, skipping the first `skip` and returning at most `limit`.
+    async fn list_feedback(&self, skip: usize, limit: usize) -> Result<Vec<Feedback>>;
+    ///Returns the cached scans made with the given OCR settings whose hash shares at least one band with `hash`. The caller picks those close enough.
+    async fn similar_scans(&self, hash: u64, ocr_version: &str) -> Result<Vec<CachedScan>>;
+    ///Caches the scan of an image, replacing any cached scan with the same hash and OCR settings.
+    async fn cache_scan(&self, scan: CachedScan) -> Result<()>;
+}
+
+///Opens the storage backend selected in the config.
+pub async fn open(config: &DatabaseConfig) -> Result<Store> {
+    let store: Store = match config.backend {
+        StorageBackend::Mongo => Arc::new(Database::new(config).await?),
+        StorageBackend::Sqlite => Arc::new(SqliteStore::new(&config.sqlite_path)?),
+        StorageBackend::Memory => Arc::new(MemoryStore::new()),
+    };
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongo_db_interface::Image;
+    use crate::feedback::Label;
+
+    fn post(id: &str, unrecoverable: Option<bool>, images: usize) -> Post {
+        let images = (0..images).map(|i| Image::scanned(&i.to_string(), "", unrecoverable)).collect();
+        Post { unrecoverable, ..Post::titled(id, &format!("Post {}", id), images) }
+    }
+
+    fn feedback(post_id: &str, user: &str, image_id: Option<&str>, label: Label) -> Feedback {
+        Feedback {
+            post_id: post_id.to_owned(),
+            image_id: image_id.map(str::to_owned),
+            user: user.to_owned(),
+            label,
+            comment: None,
+            created: "0".to_owned(),
+        }
+    }
+
+    ///Checks the behaviour every backend must share, starting from an empty store.
+    async fn check_store(store: Store) {
+        assert_eq!(store.stats().await.unwrap(), StoreStats::default());
+        store.upload_post(post("b", Some(true), 2)).await.unwrap();
+        store.upload_post(post("a", Some(false), 1)).await.unwrap();
+        store.upload_post(post("c", None, 0)).await.unwrap();
+        //Posts without a verdict aren't counted as unrecoverable.
+        assert_eq!(store.stats().await.unwrap(), StoreStats { posts: 3, unrecoverable_posts: 1, images: 3 });
+
+        let ids = |posts: Vec<Post>| posts.into_iter().map(|post| post.id).collect::<Vec<String>>();
+        assert_eq!(ids(store.list(None, 10).await.unwrap()), vec!["a", "b", "c"]);
+        assert_eq!(ids(store.list(Some("a"), 1).await.unwrap()), vec!["b"]);
+        assert_eq!(ids(store.list(Some("aa"), 10).await.unwrap()), vec!["b", "c"]);
+        assert!(store.list(Some("c"), 10).await.unwrap().is_empty());
+
+        //A post is only replaced if it hasn't been processed again since it was read.
+        let rescored = Post { filter_version: Some("new".to_owned()), ..post("a", Some(true), 1) };
+        assert!(!store.replace_post(rescored.clone(), Some("old".to_owned())).await.unwrap());
+        assert!(!store.replace_post(Post { id: "d".to_owned(), ..rescored.clone() }, None).await.unwrap());
+        assert!(store.get_post("d").await.unwrap().is_none());
+        assert!(!store.replace_post(Post { datetime: "1".to_owned(), ..rescored.clone() }, None).await.unwrap());
+        assert!(store.replace_post(rescored, None).await.unwrap());
+        let stored = store.get_post("a").await.unwrap().unwrap();
+        assert_eq!((stored.unrecoverable, stored.filter_version.as_deref()), (Some(true), Some("new")));
+        store.upload_post(post("a", Some(false), 1)).await.unwrap();
+
+        //Uploading the same id replaces the post.
+        store.upload_post(post("b", Some(false), 1)).await.unwrap();
+        let stored = store.get_post("b").await.unwrap().unwrap();
+        assert_eq!((stored.unrecoverable, stored.images.len()), (Some(false), 1));
+        assert_eq!(store.stats().await.unwrap(), StoreStats { posts: 3, unrecoverable_posts: 0, images: 2 });
+
+        assert!(store.delete("b").await.unwrap());
+        assert!(!store.delete("b").await.unwrap());
+        assert!(store.get_post("b").await.unwrap().is_none());
+
+        store.add_feedback(feedback("a", "user", None, Label::Unsafe)).await.unwrap();
+        store.add_feedback(feedback("a", "user", Some("0"), Label::Safe)).await.unwrap();
+        store.add_feedback(feedback("a", "other", None, Label::Safe)).await.unwrap();
+        //A later correction of the same post and image by the same user replaces the first.
+        store.add_feedback(feedback("a", "user", None, Label::Safe)).await.unwrap();
+        let mine = store.user_feedback("a", "user").await.unwrap();
+        assert_eq!(mine.len(), 2);
+        assert!(mine.iter().all(|f| f.label == Label::Safe));
+        assert!(store.user_feedback("c", "user").await.unwrap().is_empty());
+        assert_eq!(store.list_feedback(0, 10).await.unwrap().len(), 3);
+        assert_eq!(store.list_feedback(2, 10).await.unwrap().len(), 1);
+        //Corrections attached to a post are never stored with it.
+        store.upload_post(Post { feedback: mine, ..post("a", Some(false), 1) }).await.unwrap();
+        assert!(store.get_post("a").await.unwrap().unwrap().feedback.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        check_store(Arc::new(MemoryStore::new())).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store() {
+        check_store(Arc::new(SqliteStore::new(":memory:").unwrap())).await;
+    }
+}
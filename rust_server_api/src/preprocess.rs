@@ -0,0 +1,316 @@
+//!This module prepares downloaded images for OCR. Tesseract reads dark, straight, reasonably large text on a plain light background best, so images are converted towards that before they are scanned.
+
+//Imports
+use std::io::Cursor;
+use image::{DynamicImage, GenericImageView, GrayImage, ImageOutputFormat, Luma, imageops::{self, FilterType}, io::Reader};
+use anyhow::{Context, Result};
+use crate::config::PreprocessConfig;
+
+///Pixels darker than this count as ink when looking for dark images and skewed lines.
+const INK: u8 = 128;
+///The angle between the rotations tried when deskewing, in degrees.
+const SKEW_STEP: f32 = 0.5;
+///Skew is measured on a copy scaled down to at most this many pixels on its longer side, as the angle doesn't need full resolution.
+const SKEW_SAMPLE_SIZE: u32 = 600;
+
+///How a preprocessed image relates to the image it was made from, so positions tesseract finds can be mapped back onto the downloaded image.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    ///How many times larger the image was made, horizontally and vertically.
+    scale: (f32, f32),
+    ///The rotation applied when deskewing, in degrees, about the centre of the upscaled image.
+    angle: f32,
+    centre: (f32, f32),
+    ///The size of the original image. Unknown when the image wasn't decoded, then positions are only kept positive.
+    original: Option<(u32, u32)>,
+}
+
+impl Transform {
+    ///The transform of an image scanned as it was.
+    pub fn identity() -> Self {
+        Transform { scale: (1.0, 1.0), angle: 0.0, centre: (0.0, 0.0), original: None }
+    }
+    ///Maps a box found in the preprocessed image onto the original image, returning the box around its rotated corners as `(left, top, width, height)`.
+    pub fn to_original(self, left: u32, top: u32, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (cx, cy) = self.centre;
+        let corners = [
+            (left as f32, top as f32),
+            ((left + width) as f32, top as f32),
+            (left as f32, (top + height) as f32),
+            ((left + width) as f32, (top + height) as f32),
+        ];
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for (x, y) in corners.iter() {
+            //The same mapping `rotate` uses to find where each pixel came from, then the upscaling undone.
+            let (dx, dy) = (x - cx, y - cy);
+            let x = (dx * cos - dy * sin + cx) / self.scale.0;
+            let y = (dx * sin + dy * cos + cy) / self.scale.1;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        let (limit_x, limit_y) = self.original.map_or((f32::MAX, f32::MAX), |(w, h)| (w as f32, h as f32));
+        let clamp = |v: f32, limit: f32| v.round().max(0.0).min(limit) as u32;
+        let (left, top) = (clamp(min_x, limit_x), clamp(min_y, limit_y));
+        (left, top, clamp(max_x, limit_x) - left, clamp(max_y, limit_y) - top)
+    }
+}
+
+///Scales an image up so its shorter side is at least `min_side` pixels, by no more than `max_upscale` times.
+fn upscale(image: DynamicImage, config: &PreprocessConfig) -> DynamicImage {
+    let shorter = image.width().min(image.height()).max(1);
+    let factor = (config.min_side as f32 / shorter as f32).min(config.max_upscale);
+    if factor <= 1.0 {
+        return image;
+    }
+    let width = (image.width() as f32 * factor).round() as u32;
+    let height = (image.height() as f32 * factor).round() as u32;
+    image.resize_exact(width, height, FilterType::CatmullRom)
+}
+
+///Binarises an image by comparing each pixel with the mean of the window around it (Bradley and Roth's method), so text survives gradients and busy backgrounds.
+///A pixel becomes ink if it is `offset` percent darker than its neighbourhood. A window of 0 uses an eighth of the shorter side.
+fn adaptive_threshold(gray: &GrayImage, window: u32, offset: u8) -> GrayImage {
+    let (width, height) = gray.dimensions();
+    let window = if window == 0 { (width.min(height) / 8).max(3) } else { window };
+    let half = (window / 2) as i64;
+    //Summed area table with a leading row and column of zeros, so any window sum is four lookups.
+    let stride = width as usize + 1;
+    let mut integral = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        let mut row = 0u64;
+        for x in 0..width as usize {
+            row += gray.get_pixel(x as u32, y as u32)[0] as u64;
+            integral[(y + 1) * stride + x + 1] = integral[y * stride + x + 1] + row;
+        }
+    }
+    let clamp = |v: i64, max: u32| v.max(0).min(max as i64) as usize;
+    GrayImage::from_fn(width, height, |x, y| {
+        let (x0, x1) = (clamp(x as i64 - half, width), clamp(x as i64 + half + 1, width));
+        let (y0, y1) = (clamp(y as i64 - half, height), clamp(y as i64 + half + 1, height));
+        let count = ((x1 - x0) * (y1 - y0)) as u64;
+        let sum = integral[y1 * stride + x1] + integral[y0 * stride + x0] - integral[y0 * stride + x1] - integral[y1 * stride + x0];
+        let value = gray.get_pixel(x, y)[0] as u64;
+        if value * count * 100 <= sum * (100 - offset.min(100) as u64) {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    })
+}
+
+///Returns true if most of the image is dark, which usually means light text on a dark background.
+fn mostly_dark(gray: &GrayImage) -> bool {
+    let dark = gray.pixels().filter(|p| p[0] < INK).count();
+    dark * 2 > (gray.width() * gray.height()) as usize
+}
+
+///Finds the angle of the text lines, in degrees, by trying rotations and keeping the one whose rows of ink are sharpest.
+///Straight lines of text put all their ink in a few rows, which maximises the sum of squared row counts.
+fn skew_angle(gray: &GrayImage, max_skew: f32) -> f32 {
+    let scale = (SKEW_SAMPLE_SIZE as f32 / gray.width().max(gray.height()) as f32).min(1.0);
+    let sample = if scale < 1.0 {
+        let width = ((gray.width() as f32 * scale) as u32).max(1);
+        let height = ((gray.height() as f32 * scale) as u32).max(1);
+        imageops::resize(gray, width, height, FilterType::Triangle)
+    } else {
+        gray.clone()
+    };
+    let (width, height) = sample.dimensions();
+    let ink: Vec<(f32, f32)> = sample.enumerate_pixels()
+        .filter(|(_, _, p)| p[0] < INK)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    //Blank or solid images have no lines to measure.
+    if ink.len() < 50 || ink.len() * 2 > (width * height) as usize {
+        return 0.0;
+    }
+    let steps = (max_skew / SKEW_STEP).round() as i32;
+    let offset = width as f32;
+    let mut rows = vec![0u64; (height + 2 * width) as usize + 1];
+    let mut best: (f32, u64) = (0.0, 0);
+    for step in -steps..=steps {
+        let angle = step as f32 * SKEW_STEP;
+        let (sin, cos) = angle.to_radians().sin_cos();
+        rows.iter_mut().for_each(|count| *count = 0);
+        for &(x, y) in &ink {
+            rows[(y * cos - x * sin + offset) as usize] += 1;
+        }
+        let score = rows.iter().map(|count| count * count).sum();
+        //Prefer the smallest rotation when scores tie, so straight images stay untouched.
+        if score > best.1 || (score == best.1 && angle.abs() < best.0.abs()) {
+            best = (angle, score);
+        }
+    }
+    best.0
+}
+
+///Rotates an image so lines at the given angle become horizontal. Corners uncovered by the rotation are filled with white.
+fn rotate(gray: &GrayImage, angle: f32) -> GrayImage {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (cx, cy) = (gray.width() as f32 / 2.0, gray.height() as f32 / 2.0);
+    GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let sx = (dx * cos - dy * sin + cx).round();
+        let sy = (dx * sin + dy * cos + cy).round();
+        if sx < 0.0 || sy < 0.0 || sx >= gray.width() as f32 || sy >= gray.height() as f32 {
+            Luma([255])
+        } else {
+            *gray.get_pixel(sx as u32, sy as u32)
+        }
+    })
+}
+
+///Runs every enabled stage over an image, returning it with the transform back to the original. Thresholding, inversion and deskewing work on grayscale, so they convert the image even if `grayscale` is off.
+pub fn preprocess(image: DynamicImage, config: &PreprocessConfig) -> (DynamicImage, Transform) {
+    let original = (image.width(), image.height());
+    let needs_gray = config.grayscale || config.threshold || config.invert || config.deskew;
+    let mut image = if needs_gray { DynamicImage::ImageLuma8(image.to_luma8()) } else { image };
+    if config.upscale {
+        image = upscale(image, config);
+    }
+    let mut transform = Transform {
+        scale: (image.width() as f32 / original.0.max(1) as f32, image.height() as f32 / original.1.max(1) as f32),
+        angle: 0.0,
+        centre: (image.width() as f32 / 2.0, image.height() as f32 / 2.0),
+        original: Some(original),
+    };
+    if !needs_gray {
+        return (image, transform);
+    }
+    let mut gray = image.into_luma8();
+    //Thresholding keeps whatever is darker than its surroundings, so light text must be made dark first.
+    if config.invert && mostly_dark(&gray) {
+        imageops::invert(&mut gray);
+    }
+    if config.threshold {
+        gray = adaptive_threshold(&gray, config.threshold_window, config.threshold_offset);
+    }
+    if config.deskew {
+        let angle = skew_angle(&gray, config.max_skew);
+        if angle != 0.0 {
+            gray = rotate(&gray, angle);
+            transform.angle = angle;
+        }
+    }
+    (DynamicImage::ImageLuma8(gray), transform)
+}
+
+///Decodes a downloaded image, preprocesses it and encodes the result as a PNG for tesseract. Returns None when every stage is switched off, or the image has more than `max_pixels` pixels, so the image can be scanned as downloaded.
+pub fn prepare(bytes: &[u8], config: &PreprocessConfig) -> Result<Option<(Vec<u8>, Transform)>> {
+    if !config.is_enabled() {
+        return Ok(None);
+    }
+    let reader = || Reader::new(Cursor::new(bytes)).with_guessed_format();
+    let (width, height) = reader()?.into_dimensions().context("Failed to read image size")?;
+    if width as u64 * height as u64 > config.max_pixels {
+        println!("Image of {}x{} pixels is too large to preprocess, scanning it as downloaded", width, height);
+        return Ok(None);
+    }
+    let image = reader()?.decode().context("Failed to decode image")?;
+    prepare_image(image, config).map(Some)
+}
+
+///Preprocesses an already decoded image, such as a frame of an animation, and encodes it as a PNG for tesseract. The image is only encoded when every stage is switched off.
+pub fn prepare_image(image: DynamicImage, config: &PreprocessConfig) -> Result<(Vec<u8>, Transform)> {
+    let (image, transform) = if config.is_enabled() { preprocess(image, config) } else { (image, Transform::identity()) };
+    let mut encoded = vec![];
+    image.write_to(&mut encoded, ImageOutputFormat::Png)
+        .context("Failed to encode preprocessed image")?;
+    Ok((encoded, transform))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Draws lines of text-like ink two pixels thick, rising by `angle` degrees from left to right in image coordinates.
+    fn lines(width: u32, height: u32, angle: f32) -> GrayImage {
+        let slope = angle.to_radians().tan();
+        let mut image = GrayImage::from_pixel(width, height, Luma([255]));
+        for start in (40..height - 40).step_by(30) {
+            for x in 20..width - 20 {
+                let y = (start as f32 + x as f32 * slope).round() as u32;
+                for y in y..(y + 2).min(height) {
+                    image.put_pixel(x, y, Luma([0]));
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn skew_angle_finds_the_angle_of_the_lines() {
+        assert_eq!(skew_angle(&lines(400, 300, 0.0), 10.0), 0.0);
+        assert_eq!(skew_angle(&lines(400, 300, 3.0), 10.0), 3.0);
+        assert_eq!(skew_angle(&lines(400, 300, -4.5), 10.0), -4.5);
+        //Lines steeper than the limit are left for tesseract to cope with.
+        assert!(skew_angle(&lines(400, 300, 6.0), 2.0).abs() <= 2.0);
+        assert_eq!(skew_angle(&GrayImage::from_pixel(100, 100, Luma([255])), 10.0), 0.0);
+    }
+
+    #[test]
+    fn rotate_straightens_skewed_lines() {
+        let straightened = rotate(&lines(400, 300, 3.0), 3.0);
+        assert_eq!(skew_angle(&straightened, 10.0), 0.0);
+    }
+
+    #[test]
+    fn adaptive_threshold_keeps_text_on_a_gradient() {
+        //The background darkens from left to right, past where a global threshold would turn it into ink.
+        let mut gray = GrayImage::from_fn(200, 100, |x, _| Luma([250 - (x / 2) as u8 * 2]));
+        for x in 10..190 {
+            for y in 48..52 {
+                let background = gray.get_pixel(x, y)[0];
+                gray.put_pixel(x, y, Luma([background / 2]));
+            }
+        }
+        let binary = adaptive_threshold(&gray, 0, 15);
+        assert!(binary.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        assert_eq!(binary.get_pixel(20, 50)[0], 0);
+        assert_eq!(binary.get_pixel(180, 50)[0], 0);
+        assert_eq!(binary.get_pixel(20, 10)[0], 255);
+        assert_eq!(binary.get_pixel(180, 90)[0], 255);
+    }
+
+    #[test]
+    fn boxes_are_mapped_back_through_upscaling() {
+        let transform = Transform { scale: (2.0, 2.0), angle: 0.0, centre: (100.0, 50.0), original: Some((100, 50)) };
+        assert_eq!(transform.to_original(20, 40, 10, 10), (10, 20, 5, 5));
+        //Boxes reaching past the edge are cut off at the edge of the original image.
+        assert_eq!(transform.to_original(180, 90, 40, 20), (90, 45, 10, 5));
+        assert_eq!(Transform::identity().to_original(3, 4, 5, 6), (3, 4, 5, 6));
+    }
+
+    #[test]
+    fn boxes_are_mapped_back_through_deskewing() {
+        let transform = Transform { scale: (1.0, 1.0), angle: 90.0, centre: (50.0, 50.0), original: Some((100, 100)) };
+        assert_eq!(transform.to_original(60, 40, 10, 10), (50, 60, 10, 10));
+        //A small rotation widens the box to hold the rotated corners.
+        let transform = Transform { angle: 10.0, ..transform };
+        let (left, top, width, height) = transform.to_original(40, 45, 20, 10);
+        assert!(left < 40 && top < 45 && width > 20 && height > 10);
+    }
+
+    #[test]
+    fn dark_images_are_inverted_and_small_ones_upscaled() {
+        let config = PreprocessConfig { threshold: false, deskew: false, ..PreprocessConfig::default() };
+        let dark = DynamicImage::ImageLuma8(GrayImage::from_pixel(100, 50, Luma([20])));
+        let (prepared, _) = preprocess(dark, &config);
+        //Upscaling stops at three times, short of the 800 pixel minimum.
+        assert_eq!(prepared.dimensions(), (300, 150));
+        assert!(prepared.to_luma8().pixels().all(|p| p[0] > INK));
+    }
+
+    #[test]
+    fn images_over_the_pixel_limit_are_scanned_as_downloaded() {
+        let mut png = vec![];
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(100, 50, Luma([255]))).write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        let config = PreprocessConfig { max_pixels: 5000, ..PreprocessConfig::default() };
+        assert!(prepare(&png, &config).unwrap().is_some());
+        let config = PreprocessConfig { max_pixels: 4999, ..PreprocessConfig::default() };
+        assert!(prepare(&png, &config).unwrap().is_none());
+    }
+}
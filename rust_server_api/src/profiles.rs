@@ -0,0 +1,54 @@
+//!This module holds per-user filter profiles. Posts are scored once and stored for everyone, and each user's profile is applied to the stored scores when the post is returned to them.
+
+//Imports
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::mongo_db_interface::Post;
+use crate::verdict;
+
+///A user's choice of which categories hide posts for them, and how strictly. Profiles are identified by the api key the extension sends.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    ///Used in logs, never shown to users.
+    pub name: String,
+    pub api_key: String,
+    ///The categories which hide posts for this user, including `classifier` when a classifier is used. Every category applies when empty.
+    #[serde(default)]
+    pub categories: Vec<String>,
+    ///Score thresholds replacing those in the rule file, by category.
+    #[serde(default)]
+    pub thresholds: HashMap<String, f32>,
+    ///Replaces `filter.unrecoverable_threshold` for this user.
+    #[serde(default)]
+    pub unrecoverable_threshold: Option<f32>,
+}
+
+impl Profile {
+    ///Returns true if matches of the category can hide posts for this user.
+    pub fn includes(&self, category: &str) -> bool {
+        self.categories.is_empty() || self.categories.iter().any(|c| c == category)
+    }
+    ///Returns the score a category must reach for this user, falling back to the threshold it was scored with.
+    pub fn threshold(&self, category: &str, default: f32) -> f32 {
+        self.thresholds.get(category).copied().unwrap_or(default)
+    }
+}
+
+///Finds the profile an api key belongs to.
+pub fn find<'a>(profiles: &'a [Profile], api_key: &str) -> Option<&'a Profile> {
+    profiles.iter().find(|profile| profile.api_key == api_key)
+}
+
+///Returns the fraction of unsafe images which hides a whole post for a user.
+pub fn unrecoverable_threshold(profile: Option<&Profile>, default: f32) -> f32 {
+    profile.and_then(|profile| profile.unrecoverable_threshold).unwrap_or(default)
+}
+
+///Judges a stored post again by a user's profile. Posts on the allowlist or denylist are left alone, as their verdict doesn't come from the filter.
+pub fn apply(post: &mut Post, profile: &Profile, default_threshold: f32) {
+    if post.listed.is_some() {
+        return;
+    }
+    verdict::judge(post, Some(profile), unrecoverable_threshold(Some(profile), default_threshold));
+}
@@ -0,0 +1,126 @@
+//!This module re-checks stored posts against the current filter rules, using their stored titles, descriptions and OCR text rather than downloading them again.
+
+//Imports
+use serde::Serialize;
+use anyhow::Result;
+use crate::config::Config;
+use crate::filter::{Filter, SharedFilter};
+use crate::post_store::Store;
+use crate::verdict::{self, Evaluation};
+
+///How many posts are read from the database at a time.
+const BATCH_SIZE: usize = 100;
+
+///Counts of what happened during a rescore.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RescoreStats {
+    ///Posts already judged by the current rules, which were skipped.
+    pub current: u64,
+    ///Posts decided by the allowlist or denylist, which the rules do not apply to.
+    pub listed: u64,
+    ///Posts re-checked with the current rules.
+    pub rescored: u64,
+    ///Re-checked posts whose verdict changed.
+    pub changed: u64,
+    ///Posts processed again while they were being re-checked. The newer copy is kept.
+    pub superseded: u64,
+    ///Posts removed from the database because their images were never downloaded. They are processed again the next time they are requested.
+    pub evicted: u64,
+}
+
+///Re-checks every stored post which was judged by a different version of the rules, or every post if `all` is set.
+///A post is only written back if it hasn't been processed again since it was read, so a fresh upload from a worker is never replaced with stale data.
+pub async fn rescore(db: &Store, filter: &Filter, config: &Config, all: bool) -> Result<RescoreStats> {
+    let mut stats = RescoreStats::default();
+    let mut after: Option<String> = None;
+    loop {
+        let posts = db.list(after.as_deref(), BATCH_SIZE).await?;
+        after = match posts.last() {
+            Some(last) => Some(last.id.clone()),
+            None => break,
+        };
+        for mut post in posts {
+            if post.listed.is_some() {
+                stats.listed += 1;
+                continue;
+            }
+            if !all && post.filter_version.as_deref() == Some(filter.version()) {
+                stats.current += 1;
+                continue;
+            }
+            let before = post.unrecoverable;
+            let read_version = post.filter_version.clone();
+            match verdict::evaluate(filter, &mut post, config.filter.unrecoverable_threshold, &config.profiles) {
+                Evaluation::Evaluated => {
+                    let changed = post.unrecoverable != before;
+                    if !db.replace_post(post, read_version).await? {
+                        stats.superseded += 1;
+                        continue;
+                    }
+                    stats.rescored += 1;
+                    if changed {
+                        stats.changed += 1;
+                    }
+                },
+                //Pages start after the last id read, so deleting while paging never skips a post.
+                Evaluation::NeedsImages => if db.delete(&post.id).await? {
+                    stats.evicted += 1;
+                },
+            }
+        }
+    }
+    Ok(stats)
+}
+
+///Spawns a task which rescores stale posts at startup, and again every time the filter rules are reloaded.
+pub fn spawn_background(db: Store, filter: SharedFilter, config: &Config) {
+    let config = config.clone();
+    let mut changes = filter.subscribe();
+    tokio::spawn(async move {
+        while changes.recv().await.is_some() {
+            let current = filter.current();
+            match rescore(&db, &current, &config, false).await {
+                Ok(stats) => println!("Rescored posts for filter version {}: {:?}", current.version(), stats),
+                Err(e) => println!("Failed to rescore posts: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::access_lists::ListAction;
+    use crate::filter_rules::RuleSet;
+    use crate::memory_db_interface::MemoryStore;
+    use crate::mongo_db_interface::{Image, Post};
+
+    fn judged(post: Post, unrecoverable: bool, filter_version: &str) -> Post {
+        Post { unrecoverable: Some(unrecoverable), filter_version: Some(filter_version.to_owned()), ..post }
+    }
+
+    #[tokio::test]
+    async fn rescore_skips_current_and_listed_posts_and_evicts_posts_without_images() {
+        let filter = Filter::compile(RuleSet::parse("[politics]\nvote\n").unwrap());
+        let db: Store = Arc::new(MemoryStore::new());
+        let version = filter.version().to_owned();
+        db.upload_post(judged(Post::titled("current", "vote", vec![]), true, &version)).await.unwrap();
+        db.upload_post(Post { listed: Some(ListAction::Allow), ..judged(Post::titled("listed", "vote", vec![]), false, "old") }).await.unwrap();
+        db.upload_post(judged(Post::titled("changed", "a cat", vec![Image::scanned("0", "go and vote", None)]), false, "old")).await.unwrap();
+        db.upload_post(judged(Post::titled("unchanged", "a cat", vec![Image::scanned("0", "a dog", None)]), false, "old")).await.unwrap();
+        //Posts stored without images need downloading again, unless their title hides them.
+        db.upload_post(judged(Post::titled("no_images", "a cat", vec![]), false, "old")).await.unwrap();
+        db.upload_post(judged(Post::titled("title_only", "vote", vec![]), false, "old")).await.unwrap();
+
+        let stats = rescore(&db, &filter, &Config::default(), false).await.unwrap();
+        assert_eq!((stats.current, stats.listed, stats.rescored, stats.changed, stats.evicted), (1, 1, 3, 2, 1));
+        assert!(db.get_post("no_images").await.unwrap().is_none());
+        let changed = db.get_post("changed").await.unwrap().unwrap();
+        assert_eq!((changed.unrecoverable, changed.filter_version), (Some(true), Some(version)));
+
+        //Rescoring everything includes posts already judged by the current rules.
+        let stats = rescore(&db, &filter, &Config::default(), true).await.unwrap();
+        assert_eq!((stats.current, stats.listed, stats.rescored, stats.changed, stats.evicted), (0, 1, 4, 0, 0));
+    }
+}
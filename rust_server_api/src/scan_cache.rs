@@ -0,0 +1,325 @@
+//!This module remembers the OCR of images already scanned, so the same meme reposted under another post id is not read by tesseract again.
+//!Images are compared by a difference hash, which survives the resizing and recompression reposts go through. Images whose hashes differ in few enough bits are candidates, and are only taken to be the same picture if a finer hash of the two, which sees the text on them, is almost the same too.
+
+//Imports
+use std::io::Cursor;
+use image::{DynamicImage, GenericImageView, ImageFormat, imageops::FilterType, io::Reader};
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use crate::config::CacheConfig;
+use crate::mongo_db_interface::{Reason, Word};
+use crate::ocr_pool::Media;
+use crate::post_store::Store;
+
+///How many parts the hash is split into for lookups. Hashes within `BANDS - 1` bits of each other always share at least one part exactly, so stores only need to search for equal parts.
+pub const BANDS: usize = 4;
+///The largest `ocr.cache.max_distance` lookups can find every match for.
+pub const MAX_DISTANCE: u32 = BANDS as u32 - 1;
+///The side of the grid the detail hash compares, giving `DETAIL_SIDE * DETAIL_SIDE` bits.
+const DETAIL_SIDE: u32 = 32;
+///How many bits of the detail hash a match may differ in. A 64 bit hash barely sees a caption, so the same template with another caption is often within a few bits, while resizing and recompressing an image moves a few of the 1024 bits of the detail hash and another caption dozens.
+pub const DETAIL_MAX_DISTANCE: u32 = 8;
+
+///The OCR of an image as cached, with the verdict the image was given when it was scanned.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CachedScan {
+    ///The difference hash of the image, as 16 hex digits.
+    pub hash: String,
+    ///The OCR settings the image was scanned with. Scans made with other settings are never reused.
+    pub ocr_version: String,
+    ///The detail hash of the image, as 256 hex digits. Scans cached without one are never reused.
+    #[serde(default)]
+    pub detail: String,
+    ///The size of the image in pixels, which the boxes of its words are in.
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>,
+    ///The verdict of the image when it was scanned. Images reusing the scan are judged again with the current rules.
+    pub unrecoverable: bool,
+    #[serde(default)]
+    pub reasons: Vec<Reason>,
+    pub filter_version: Option<String>,
+    ///The image the scan was read from.
+    pub post_id: String,
+    pub image_id: String,
+    ///When the image was scanned, in milliseconds since the unix epoch.
+    pub created: String,
+}
+
+///What a still image is recognised by.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    ///The difference hash stores look the image up by.
+    pub hash: u64,
+    ///The difference hash of a finer grid, confirming a match.
+    pub detail: Vec<u64>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Fingerprint {
+    pub fn of(image: &DynamicImage) -> Fingerprint {
+        Fingerprint {
+            hash: dhash(image, 8)[0],
+            detail: dhash(image, DETAIL_SIDE),
+            width: image.width(),
+            height: image.height(),
+        }
+    }
+}
+
+///Computes the difference hash of an image: one bit for each pair of neighbouring pixels of a `side + 1` by `side` grayscale thumbnail, set when the left pixel is brighter. The bits are packed 64 to a word.
+pub fn dhash(image: &DynamicImage, side: u32) -> Vec<u64> {
+    let thumbnail = image.resize_exact(side + 1, side, FilterType::Triangle).to_luma8();
+    let mut hash = vec![0u64; (side * side) as usize / 64];
+    for y in 0..side {
+        for x in 0..side {
+            let bit = (y * side + x) as usize;
+            let word = &mut hash[bit / 64];
+            *word <<= 1;
+            if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+                *word |= 1;
+            }
+        }
+    }
+    hash
+}
+
+///The number of bits two hashes differ in.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+///The number of bits two detail hashes differ in.
+fn detail_distance(a: &[u64], b: &[u64]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| distance(*a, *b)).sum()
+}
+
+///Splits a hash into the parts stores look up by.
+pub fn bands(hash: u64) -> [u16; BANDS] {
+    let mut bands = [0; BANDS];
+    for (i, band) in bands.iter_mut().enumerate() {
+        *band = (hash >> (i * 16)) as u16;
+    }
+    bands
+}
+
+///Formats a hash as it is stored.
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+///Reads a stored hash, returning None if it is malformed.
+pub fn parse_hash(hash: &str) -> Option<u64> {
+    u64::from_str_radix(hash, 16).ok()
+}
+
+///Formats a detail hash as it is stored.
+pub fn format_detail(detail: &[u64]) -> String {
+    detail.iter().map(|word| format_hash(*word)).collect()
+}
+
+///Reads a stored detail hash, returning None if it is malformed or missing.
+fn parse_detail(detail: &str) -> Option<Vec<u64>> {
+    if detail.len() != (DETAIL_SIDE * DETAIL_SIDE) as usize / 4 || !detail.is_ascii() {
+        return None;
+    }
+    (0..detail.len()).step_by(16).map(|i| parse_hash(&detail[i..i + 16])).collect()
+}
+
+///Moves the boxes of the words of a cached scan into the pixels of an image of another size. The words are dropped if the size of the cached image isn't known.
+pub fn rescale_words(scan: &CachedScan, fingerprint: &Fingerprint) -> Vec<Word> {
+    if scan.width == 0 || scan.height == 0 {
+        return vec![];
+    }
+    let x = fingerprint.width as f64 / scan.width as f64;
+    let y = fingerprint.height as f64 / scan.height as f64;
+    let scale = |value: u32, by: f64| (value as f64 * by).round() as u32;
+    scan.words.iter().map(|word| Word {
+        left: scale(word.left, x),
+        top: scale(word.top, y),
+        width: scale(word.width, x),
+        height: scale(word.height, y),
+        ..word.clone()
+    }).collect()
+}
+
+///Hashes a downloaded still image on a blocking thread. Animations and videos aren't hashed, as their first frame says little about the rest, nor are images the image crate can't decode.
+///Spooled images aren't hashed either. They were too large to hold in memory, so decoding them outside the OCR pool could exhaust it, and they are scanned afresh instead. The same goes for images of more than `max_pixels` pixels.
+pub async fn fingerprint(media: &Media, max_pixels: u64) -> Option<Fingerprint> {
+    let bytes = match media {
+        Media::Memory(bytes) => bytes.clone(),
+        Media::Spooled(_) | Media::Video(_) => return None,
+    };
+    let decoded = tokio::task::spawn_blocking(move || -> Result<Option<DynamicImage>> {
+        let reader = || Reader::new(Cursor::new(&bytes)).with_guessed_format();
+        let (width, height) = reader()?.into_dimensions()?;
+        if width as u64 * height as u64 > max_pixels {
+            return Ok(None);
+        }
+        decode(reader()?)
+    }).await;
+    match decoded {
+        Ok(Ok(Some(image))) => Some(Fingerprint::of(&image)),
+        _ => None,
+    }
+}
+
+///Decodes an image unless it is a GIF, which may be animated.
+fn decode<R: std::io::BufRead + std::io::Seek>(reader: Reader<R>) -> Result<Option<DynamicImage>> {
+    if reader.format() == Some(ImageFormat::Gif) {
+        return Ok(None);
+    }
+    Ok(Some(reader.decode()?))
+}
+
+///Finds the cached scan closest to an image, if any has a hash within `max_distance` bits and a detail hash within `DETAIL_MAX_DISTANCE` bits, and was made with the same OCR settings.
+pub async fn find(db: &Store, fingerprint: &Fingerprint, ocr_version: &str, config: &CacheConfig) -> Result<Option<CachedScan>> {
+    let candidates = db.similar_scans(fingerprint.hash, ocr_version).await?;
+    Ok(closest(fingerprint, candidates, config))
+}
+
+///Picks the candidate closest to an image by its detail hash, among those close enough by both hashes.
+fn closest(fingerprint: &Fingerprint, candidates: Vec<CachedScan>, config: &CacheConfig) -> Option<CachedScan> {
+    candidates.into_iter()
+        .filter(|scan| parse_hash(&scan.hash).is_some_and(|hash| distance(fingerprint.hash, hash) <= config.max_distance))
+        .filter_map(|scan| Some((detail_distance(&fingerprint.detail, &parse_detail(&scan.detail)?), scan)))
+        .filter(|(distance, _)| *distance <= DETAIL_MAX_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, scan)| scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use image::{Rgb, RgbImage};
+    use crate::memory_db_interface::MemoryStore;
+
+    ///A 600 by 500 picture with a white caption bar on top, holding lines of glyph-like strokes drawn from a seed.
+    fn meme(caption: u64) -> DynamicImage {
+        let mut image = RgbImage::from_fn(600, 500, |x, y| {
+            let v = ((x as f32 / 40.0).sin() * 60.0 + (y as f32 / 55.0).cos() * 60.0 + 120.0) as u8;
+            if y < 90 { Rgb([255, 255, 255]) } else { Rgb([v, v / 2, 255 - v]) }
+        });
+        let mut state = caption;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for line in 0..2 {
+            let mut x = 20;
+            while x < 560 {
+                let width = 8 + (next() % 14) as u32;
+                if next() % 6 != 0 {
+                    let top = 15 + line * 38;
+                    for y in top..top + 28 {
+                        for x in x..x + width {
+                            if next() % 3 != 0 {
+                                image.put_pixel(x, y, Rgb([0, 0, 0]));
+                            }
+                        }
+                    }
+                }
+                x += width + 4;
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    ///Shrinks an image and saves it as a JPEG, as a repost would be.
+    fn repost(image: &DynamicImage) -> DynamicImage {
+        let mut jpeg = vec![];
+        image::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 70).encode_image(&image.resize(480, 400, FilterType::CatmullRom)).unwrap();
+        image::load_from_memory(&jpeg).unwrap()
+    }
+
+    fn cached(fingerprint: &Fingerprint, text: &str) -> CachedScan {
+        CachedScan {
+            hash: format_hash(fingerprint.hash),
+            ocr_version: "v".to_owned(),
+            detail: format_detail(&fingerprint.detail),
+            width: fingerprint.width,
+            height: fingerprint.height,
+            text: text.to_owned(),
+            words: vec![Word { text: text.to_owned(), confidence: 90.0, left: 100, top: 50, width: 200, height: 30 }],
+            unrecoverable: false,
+            reasons: vec![],
+            filter_version: None,
+            post_id: "post".to_owned(),
+            image_id: text.to_owned(),
+            created: "0".to_owned(),
+        }
+    }
+
+    #[test]
+    fn bands_split_the_hash_low_bits_first() {
+        assert_eq!(bands(0x0123_4567_89ab_cdef), [0xcdef, 0x89ab, 0x4567, 0x0123]);
+        //Hashes within MAX_DISTANCE bits share a band however the bits are spread.
+        let hash = 0x0123_4567_89ab_cdef;
+        let other = hash ^ (1 << 3) ^ (1 << 20) ^ (1 << 40);
+        assert!(bands(hash).iter().zip(&bands(other)).any(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn hashes_round_trip() {
+        let fingerprint = Fingerprint::of(&meme(1));
+        assert_eq!(parse_hash(&format_hash(fingerprint.hash)), Some(fingerprint.hash));
+        assert_eq!(parse_detail(&format_detail(&fingerprint.detail)), Some(fingerprint.detail));
+        assert_eq!(parse_detail(""), None);
+        assert_eq!(parse_hash("not a hash"), None);
+    }
+
+    #[test]
+    fn another_caption_is_told_apart_by_the_detail_hash() {
+        let (first, second) = (Fingerprint::of(&meme(22)), Fingerprint::of(&meme(33)));
+        assert!(distance(first.hash, second.hash) <= MAX_DISTANCE);
+        assert!(detail_distance(&first.detail, &second.detail) > DETAIL_MAX_DISTANCE * 10);
+        let config = CacheConfig { enabled: true, max_distance: MAX_DISTANCE };
+        assert_eq!(closest(&second, vec![cached(&first, "first")], &config), None);
+    }
+
+    #[test]
+    fn a_repost_matches_the_cached_scan() {
+        let original = Fingerprint::of(&meme(11));
+        let reposted = Fingerprint::of(&repost(&meme(11)));
+        let config = CacheConfig { enabled: true, max_distance: 0 };
+        let candidates = vec![cached(&Fingerprint::of(&meme(22)), "other"), cached(&original, "original")];
+        assert_eq!(closest(&reposted, candidates, &config).map(|scan| scan.text), Some("original".to_owned()));
+    }
+
+    #[test]
+    fn scans_without_a_detail_hash_are_not_reused() {
+        let fingerprint = Fingerprint::of(&meme(11));
+        let scan = CachedScan { detail: String::new(), ..cached(&fingerprint, "old") };
+        let config = CacheConfig { enabled: true, max_distance: MAX_DISTANCE };
+        assert_eq!(closest(&fingerprint, vec![scan], &config), None);
+    }
+
+    #[test]
+    fn words_are_rescaled_to_the_new_image() {
+        let scan = cached(&Fingerprint::of(&meme(11)), "word");
+        let reposted = Fingerprint::of(&repost(&meme(11)));
+        let word = &rescale_words(&scan, &reposted)[0];
+        assert_eq!((word.left, word.top, word.width, word.height), (80, 40, 160, 24));
+        let without_size = CachedScan { width: 0, height: 0, ..scan };
+        assert!(rescale_words(&without_size, &reposted).is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_looks_up_scans_with_the_same_ocr_settings() {
+        let db: Store = Arc::new(MemoryStore::new());
+        let fingerprint = Fingerprint::of(&meme(11));
+        db.cache_scan(cached(&fingerprint, "cached")).await.unwrap();
+        let config = CacheConfig { enabled: true, max_distance: 0 };
+        let found = find(&db, &fingerprint, "v", &config).await.unwrap();
+        assert_eq!(found.map(|scan| scan.text), Some("cached".to_owned()));
+        assert_eq!(find(&db, &fingerprint, "other", &config).await.unwrap(), None);
+    }
+}
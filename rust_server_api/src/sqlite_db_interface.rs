@@ -0,0 +1,214 @@
+//!This module stores posts in an embedded SQLite database, for single-box deployments without a mongo server.
+
+//Imports
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use rusqlite::{Connection, OptionalExtension, params};
+use async_trait::async_trait;
+use anyhow::{Result, anyhow, bail};
+use crate::mongo_db_interface::Post;
+use crate::post_store::{PostStore, StoreStats};
+use crate::feedback::Feedback;
+use crate::scan_cache::{self, CachedScan};
+
+///A post store backed by a single SQLite file. Each post and correction is kept as a JSON document alongside the columns needed for queries.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    ///Opens (or creates) the database file and ensures the schema exists.
+    pub fn new(path: impl AsRef<Path>) -> Result<SqliteStore> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS posts (
+                id TEXT PRIMARY KEY,
+                unrecoverable INTEGER NOT NULL,
+                image_count INTEGER NOT NULL,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS feedback (
+                post_id TEXT NOT NULL,
+                user TEXT NOT NULL,
+                image_id TEXT NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (post_id, user, image_id)
+            );
+            CREATE TABLE IF NOT EXISTS scans (
+                hash TEXT NOT NULL,
+                ocr_version TEXT NOT NULL,
+                band0 INTEGER NOT NULL,
+                band1 INTEGER NOT NULL,
+                band2 INTEGER NOT NULL,
+                band3 INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (hash, ocr_version)
+            );
+            CREATE INDEX IF NOT EXISTS scans_band0 ON scans (band0);
+            CREATE INDEX IF NOT EXISTS scans_band1 ON scans (band1);
+            CREATE INDEX IF NOT EXISTS scans_band2 ON scans (band2);
+            CREATE INDEX IF NOT EXISTS scans_band3 ON scans (band3);"
+        )?;
+        Ok(SqliteStore { conn: Arc::new(Mutex::new(conn)) })
+    }
+    ///Runs a query on a blocking thread so SQLite never stalls the async executor.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().map_err(|_| anyhow!("SQLite connection lock was poisoned"))?;
+            f(&conn)
+        }).await?
+    }
+}
+
+#[async_trait]
+impl PostStore for SqliteStore {
+    async fn get_post(&self, id: &str) -> Result<Option<Post>> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| {
+            let data: Option<String> = conn
+                .query_row("SELECT data FROM posts WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?;
+            match data {
+                Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+                None => Ok(None),
+            }
+        }).await
+    }
+    async fn upload_post(&self, post: Post) -> Result<()> {
+        let post = post.stored();
+        self.with_conn(move |conn| {
+            let data = serde_json::to_string(&post)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO posts (id, unrecoverable, image_count, data) VALUES (?1, ?2, ?3, ?4)",
+                params![post.id, post.unrecoverable == Some(true), post.images.len() as i64, data],
+            )?;
+            Ok(())
+        }).await
+    }
+    async fn replace_post(&self, post: Post, filter_version: Option<String>) -> Result<bool> {
+        let post = post.stored();
+        self.with_conn(move |conn| {
+            //The connection is held throughout, so no other write can land between the check and the update.
+            let stored: Option<String> = conn
+                .query_row("SELECT data FROM posts WHERE id = ?1", params![post.id], |row| row.get(0))
+                .optional()?;
+            let stored: Post = match stored {
+                Some(data) => serde_json::from_str(&data)?,
+                None => return Ok(false),
+            };
+            if stored.datetime != post.datetime || stored.filter_version != filter_version {
+                return Ok(false);
+            }
+            let data = serde_json::to_string(&post)?;
+            conn.execute(
+                "UPDATE posts SET unrecoverable = ?2, image_count = ?3, data = ?4 WHERE id = ?1",
+                params![post.id, post.unrecoverable == Some(true), post.images.len() as i64, data],
+            )?;
+            Ok(true)
+        }).await
+    }
+    async fn delete(&self, id: &str) -> Result<bool> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM posts WHERE id = ?1", params![id])?;
+            Ok(deleted > 0)
+        }).await
+    }
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Vec<Post>> {
+        let after = after.map(str::to_owned);
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM posts WHERE ?1 IS NULL OR id > ?1 ORDER BY id LIMIT ?2")?;
+            let rows = statement.query_map(params![after, limit as i64], |row| row.get::<_, String>(0))?;
+            let mut posts = vec![];
+            for data in rows {
+                posts.push(serde_json::from_str(&data?)?);
+            }
+            Ok(posts)
+        }).await
+    }
+    async fn stats(&self) -> Result<StoreStats> {
+        self.with_conn(|conn| {
+            let (posts, unrecoverable_posts, images): (i64, i64, i64) = conn.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(unrecoverable), 0), COALESCE(SUM(image_count), 0) FROM posts",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
+            Ok(StoreStats {
+                posts: posts as u64,
+                unrecoverable_posts: unrecoverable_posts as u64,
+                images: images as u64,
+            })
+        }).await
+    }
+    async fn add_feedback(&self, feedback: Feedback) -> Result<()> {
+        self.with_conn(move |conn| {
+            let data = serde_json::to_string(&feedback)?;
+            //Corrections for the whole post are keyed with an empty image id, as NULLs are never equal in a primary key.
+            conn.execute(
+                "INSERT OR REPLACE INTO feedback (post_id, user, image_id, data) VALUES (?1, ?2, ?3, ?4)",
+                params![feedback.post_id, feedback.user, feedback.image_id.unwrap_or_default(), data],
+            )?;
+            Ok(())
+        }).await
+    }
+    async fn user_feedback(&self, post_id: &str, user: &str) -> Result<Vec<Feedback>> {
+        let (post_id, user) = (post_id.to_owned(), user.to_owned());
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM feedback WHERE post_id = ?1 AND user = ?2")?;
+            let rows = statement.query_map(params![post_id, user], |row| row.get::<_, String>(0))?;
+            let mut feedback = vec![];
+            for data in rows {
+                feedback.push(serde_json::from_str(&data?)?);
+            }
+            Ok(feedback)
+        }).await
+    }
+    async fn list_feedback(&self, skip: usize, limit: usize) -> Result<Vec<Feedback>> {
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM feedback ORDER BY post_id, user, image_id LIMIT ?1 OFFSET ?2")?;
+            let rows = statement.query_map(params![limit as i64, skip as i64], |row| row.get::<_, String>(0))?;
+            let mut feedback = vec![];
+            for data in rows {
+                feedback.push(serde_json::from_str(&data?)?);
+            }
+            Ok(feedback)
+        }).await
+    }
+    async fn similar_scans(&self, hash: u64, ocr_version: &str) -> Result<Vec<CachedScan>> {
+        let ocr_version = ocr_version.to_owned();
+        let [b0, b1, b2, b3] = scan_cache::bands(hash);
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT data FROM scans WHERE ocr_version = ?1 AND (band0 = ?2 OR band1 = ?3 OR band2 = ?4 OR band3 = ?5)"
+            )?;
+            let rows = statement.query_map(params![ocr_version, b0, b1, b2, b3], |row| row.get::<_, String>(0))?;
+            let mut scans = vec![];
+            for data in rows {
+                scans.push(serde_json::from_str(&data?)?);
+            }
+            Ok(scans)
+        }).await
+    }
+    async fn cache_scan(&self, scan: CachedScan) -> Result<()> {
+        let [b0, b1, b2, b3] = match scan_cache::parse_hash(&scan.hash) {
+            Some(hash) => scan_cache::bands(hash),
+            None => bail!("Malformed image hash {}", scan.hash),
+        };
+        self.with_conn(move |conn| {
+            let data = serde_json::to_string(&scan)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO scans (hash, ocr_version, band0, band1, band2, band3, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![scan.hash, scan.ocr_version, b0, b1, b2, b3, data],
+            )?;
+            Ok(())
+        }).await
+    }
+}
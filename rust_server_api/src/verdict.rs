@@ -0,0 +1,314 @@
+//!This module turns filter results into the verdict of a post, so freshly downloaded posts and posts re-checked from the database are judged the same way.
+//!Every category score is kept with the post, so the verdict can be judged again for a user's profile without checking the text again.
+
+//Imports
+use crate::config::ClassifierMode;
+use crate::filter::{AttachedClassifier, Filter};
+use crate::mongo_db_interface::{Image, Post, Reason, ReasonSource, Score};
+use crate::profiles::Profile;
+
+///The outcome of evaluating a post.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Evaluation {
+    ///The verdict was computed from the stored text.
+    Evaluated,
+    ///The images of the post were never downloaded, because its title or description was unsafe when it was processed. They must be downloaded before the post can be judged.
+    NeedsImages,
+}
+
+///The category named in the reasons given by the classifier.
+const CLASSIFIER_CATEGORY: &str = "classifier";
+
+///The scores and matches found in the pieces of text of a post or image.
+#[derive(Default)]
+struct Scored {
+    scores: Vec<Score>,
+    matches: Vec<Reason>,
+}
+
+impl Scored {
+    ///Records the score of every category the rules found in a piece of text, with every match behind it.
+    fn keywords(&mut self, filter: &Filter, text: &str, source: ReasonSource) {
+        let result = filter.check(text);
+        self.scores.extend(result.categories.into_iter().map(|c| Score {
+            source,
+            category: c.category,
+            score: c.score,
+            threshold: c.threshold,
+        }));
+        self.matches.extend(result.matches.into_iter().map(|m| Reason {
+            source,
+            category: m.category,
+            term: m.term,
+            start: m.start,
+            end: m.end,
+        }));
+    }
+    ///Records the classifier's probability for a piece of text, with a match naming the words which pointed most strongly towards political.
+    ///Text the model knows none of the words of isn't scored.
+    fn classifier(&mut self, attached: &AttachedClassifier, text: &str, source: ReasonSource) {
+        let classification = match attached.model.classify(text) {
+            Some(classification) => classification,
+            None => return,
+        };
+        let term = if classification.terms.is_empty() {
+            format!("{:.0}%", classification.probability * 100.0)
+        } else {
+            classification.terms.join(" ")
+        };
+        self.scores.push(Score {
+            source,
+            category: CLASSIFIER_CATEGORY.to_owned(),
+            score: classification.probability,
+            threshold: attached.threshold,
+        });
+        self.matches.push(Reason {
+            source,
+            category: CLASSIFIER_CATEGORY.to_owned(),
+            term,
+            start: 0,
+            end: text.chars().count(),
+        });
+    }
+    ///Scores one piece of text, combining the rules and any classifier as the classifier mode says.
+    fn text(&mut self, filter: &Filter, text: &str, source: ReasonSource) {
+        let attached = match filter.classifier() {
+            Some(attached) => attached,
+            None => return self.keywords(filter, text, source),
+        };
+        match attached.mode {
+            ClassifierMode::Keywords => self.keywords(filter, text, source),
+            ClassifierMode::Classifier => self.classifier(attached, text, source),
+            ClassifierMode::Either => {
+                self.keywords(filter, text, source);
+                self.classifier(attached, text, source);
+            },
+            ClassifierMode::Both => {
+                //The rules only count where the classifier agrees, so the classifier only needs asking when they matched.
+                let mut found = Scored::default();
+                found.keywords(filter, text, source);
+                if !found.scores.is_empty() && attached.model.classify(text).is_some_and(|c| c.probability >= attached.threshold) {
+                    self.scores.extend(found.scores);
+                    self.matches.extend(found.matches);
+                }
+            },
+        }
+    }
+}
+
+///Returns true if a score hides text for a profile, or by the default thresholds when there is no profile.
+fn reaches(score: &Score, profile: Option<&Profile>) -> bool {
+    match profile {
+        Some(profile) => profile.includes(&score.category) && score.score >= profile.threshold(&score.category, score.threshold),
+        None => score.score >= score.threshold,
+    }
+}
+
+///Returns the matches of the categories which reached their threshold, these are the reasons the text is unsafe.
+///Text stored before scores were recorded only has its reasons, so those are narrowed to the profile's categories instead.
+fn blocking(scores: &[Score], matches: &[Reason], reasons: &[Reason], profile: Option<&Profile>) -> Vec<Reason> {
+    if scores.is_empty() {
+        return reasons.iter()
+            .filter(|reason| profile.is_none_or(|profile| profile.includes(&reason.category)))
+            .cloned()
+            .collect();
+    }
+    let blocked: Vec<&Score> = scores.iter().filter(|score| reaches(score, profile)).collect();
+    matches.iter()
+        .filter(|m| blocked.iter().any(|score| score.source == m.source && score.category == m.category))
+        .cloned()
+        .collect()
+}
+
+///Returns true if the url points at a video. Animated GIFs are scanned frame by frame, so they count as images.
+pub fn is_video(url: &str) -> bool {
+    let extension = url.rsplit('.').next().unwrap_or("");
+    extension == "mp4" || extension == "gifv"
+}
+
+///Returns true if the url points at a GIF or a video, which are scanned frame by frame.
+fn has_frames(url: &str) -> bool {
+    is_video(url) || url.rsplit('.').next() == Some("gif")
+}
+
+///Returns true if the text of an image was read, so it counts towards the post threshold. Images whose download or OCR failed have no text. GIFs and videos record every frame scanned, so those without frames weren't scanned: videos are skipped unless video scanning is enabled, and GIFs stored before they were scanned frame by frame have empty text and no frames.
+pub fn is_scanned(image: &Image) -> bool {
+    image.image_ocr_text.is_some() && (!has_frames(&image.url) || !image.frames.is_empty())
+}
+
+///Returns true if a stored post has images which could be scanned but weren't, because downloading or OCR failed, so the post should be processed again.
+pub fn has_unscanned_images(post: &Post, video_enabled: bool) -> bool {
+    post.images.iter().any(|image| !is_scanned(image) && (video_enabled || !is_video(&image.url)))
+}
+
+///Scores the title and description of a post, and sets the reasons they are unsafe by the default thresholds.
+pub fn check_text(filter: &Filter, post: &mut Post) {
+    let mut scored = Scored::default();
+    scored.text(filter, post.title.as_deref().unwrap_or(""), ReasonSource::Title);
+    scored.text(filter, post.description.as_deref().unwrap_or(""), ReasonSource::Description);
+    post.scores = scored.scores;
+    post.matches = scored.matches;
+    post.reasons = blocking(&post.scores, &post.matches, &[], None);
+}
+
+///Returns true if the title or description hides the post by the default thresholds and for every profile, so its images don't need downloading.
+pub fn text_unsafe_for_all(post: &Post, profiles: &[Profile]) -> bool {
+    !blocking(&post.scores, &post.matches, &post.reasons, None).is_empty()
+        && profiles.iter().all(|profile| !blocking(&post.scores, &post.matches, &post.reasons, Some(profile)).is_empty())
+}
+
+///Judges an image from its scores, setting its reasons and whether it is unrecoverable.
+fn judge_image(image: &mut Image, profile: Option<&Profile>) {
+    image.reasons = blocking(&image.scores, &image.matches, &image.reasons, profile);
+    image.unrecoverable = Some(!image.reasons.is_empty());
+}
+
+///Scores the description and OCR text of an image, setting its reasons and whether it is unrecoverable by the default thresholds.
+pub fn check_image(filter: &Filter, image: &mut Image) {
+    let mut scored = Scored::default();
+    scored.text(filter, &image.description, ReasonSource::ImageDescription);
+    scored.text(filter, image.image_ocr_text.as_deref().unwrap_or(""), ReasonSource::OcrText);
+    image.scores = scored.scores;
+    image.matches = scored.matches;
+    image.reasons = vec![];
+    judge_image(image, None);
+}
+
+///Judges a post and its images from their stored scores, for a profile or by the default thresholds, updating the verdict and reasons in place.
+///A post is unrecoverable if its title or description is unsafe, or if the fraction of unsafe scanned images reaches the threshold.
+pub fn judge(post: &mut Post, profile: Option<&Profile>, unrecoverable_threshold: f32) {
+    post.reasons = blocking(&post.scores, &post.matches, &post.reasons, profile);
+    for image in post.images.iter_mut() {
+        judge_image(image, profile);
+    }
+    post.unrecoverable = Some(!post.reasons.is_empty() || images_unsafe(&post.images, unrecoverable_threshold));
+}
+
+///Evaluates a post and all of its images with the filter, updating the scores, verdict and reasons in place.
+///A post without images can only be judged if its title or description hides it for every profile.
+pub fn evaluate(filter: &Filter, post: &mut Post, unrecoverable_threshold: f32, profiles: &[Profile]) -> Evaluation {
+    check_text(filter, post);
+    if post.images.is_empty() && !text_unsafe_for_all(post, profiles) {
+        return Evaluation::NeedsImages;
+    }
+    post.filter_version = Some(filter.version().to_owned());
+
+    for image in post.images.iter_mut() {
+        check_image(filter, image);
+    }
+    judge(post, None, unrecoverable_threshold);
+    Evaluation::Evaluated
+}
+
+///Returns true if the fraction of scanned images marked as unrecoverable reaches the threshold. Images which weren't scanned don't count either way, so a post with none scanned isn't hidden by its images.
+pub fn images_unsafe(images: &[Image], unrecoverable_threshold: f32) -> bool {
+    let scanned: Vec<&Image> = images.iter().filter(|image| is_scanned(image)).collect();
+    if scanned.is_empty() {
+        return false;
+    }
+    let num_unrecoverable = scanned.iter().filter(|image| image.unrecoverable == Some(true)).count();
+    num_unrecoverable as f32 / scanned.len() as f32 >= unrecoverable_threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::filter_rules::RuleSet;
+
+    fn profile(categories: &[&str], thresholds: &[(&str, f32)]) -> Profile {
+        Profile {
+            name: "test".to_owned(),
+            api_key: "key".to_owned(),
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            thresholds: thresholds.iter().map(|(c, t)| (c.to_string(), *t)).collect::<HashMap<String, f32>>(),
+            unrecoverable_threshold: None,
+        }
+    }
+
+    fn score(source: ReasonSource, category: &str, score: f32) -> Score {
+        Score { source, category: category.to_owned(), score, threshold: 2.0 }
+    }
+
+    fn reason(source: ReasonSource, category: &str, term: &str) -> Reason {
+        Reason { source, category: category.to_owned(), term: term.to_owned(), start: 0, end: term.len() }
+    }
+
+    #[test]
+    fn reaches_uses_the_profile_threshold_and_categories() {
+        let politics = score(ReasonSource::Title, "politics", 1.5);
+        assert!(!reaches(&politics, None));
+        assert!(reaches(&politics, Some(&profile(&[], &[("politics", 1.0)]))));
+        //A threshold for another category doesn't change this one.
+        assert!(!reaches(&politics, Some(&profile(&[], &[("sport", 1.0)]))));
+        let strong = score(ReasonSource::Title, "politics", 3.0);
+        assert!(reaches(&strong, None));
+        assert!(reaches(&strong, Some(&profile(&["politics"], &[]))));
+        assert!(!reaches(&strong, Some(&profile(&["sport"], &[]))));
+        assert!(!reaches(&strong, Some(&profile(&[], &[("politics", 5.0)]))));
+    }
+
+    #[test]
+    fn blocking_keeps_matches_of_reached_scores() {
+        let scores = vec![
+            score(ReasonSource::Title, "politics", 3.0),
+            score(ReasonSource::Description, "politics", 1.0),
+            score(ReasonSource::Title, "sport", 1.0),
+        ];
+        let matches = vec![
+            reason(ReasonSource::Title, "politics", "election"),
+            reason(ReasonSource::Description, "politics", "vote"),
+            reason(ReasonSource::Title, "sport", "goal"),
+        ];
+        assert_eq!(blocking(&scores, &matches, &[], None), vec![matches[0].clone()]);
+        //A lower threshold for sport blocks its match, but only in the text it was scored in.
+        let lenient = profile(&[], &[("sport", 1.0)]);
+        assert_eq!(blocking(&scores, &matches, &[], Some(&lenient)), vec![matches[0].clone(), matches[2].clone()]);
+        assert!(blocking(&scores, &matches, &[], Some(&profile(&["sport"], &[]))).is_empty());
+    }
+
+    #[test]
+    fn blocking_narrows_stored_reasons_without_scores() {
+        let reasons = vec![
+            reason(ReasonSource::Title, "politics", "election"),
+            reason(ReasonSource::OcrText, "sport", "goal"),
+        ];
+        assert_eq!(blocking(&[], &[], &reasons, None), reasons);
+        assert_eq!(blocking(&[], &[], &reasons, Some(&profile(&["sport"], &[]))), vec![reasons[1].clone()]);
+        //Thresholds can't apply without scores, so every reason in an included category stays.
+        assert_eq!(blocking(&[], &[], &reasons, Some(&profile(&[], &[("politics", 9.0)]))), reasons);
+    }
+
+    #[test]
+    fn images_unsafe_counts_only_scanned_images() {
+        let unsafe_image = Image::scanned("a", "vote", Some(true));
+        let safe_image = Image::scanned("b", "", Some(false));
+        //A video which wasn't scanned, though its description was judged unsafe.
+        let video = Image { url: "https://i.imgur.com/c.mp4".to_owned(), image_ocr_text: None, ..Image::scanned("c", "", Some(true)) };
+        assert!(!images_unsafe(&[], 0.5));
+        assert!(!images_unsafe(std::slice::from_ref(&video), 0.5));
+        assert!(images_unsafe(&[unsafe_image.clone(), safe_image.clone()], 0.5));
+        assert!(!images_unsafe(&[unsafe_image.clone(), safe_image.clone(), safe_image.clone()], 0.5));
+        assert!(!images_unsafe(&[unsafe_image, safe_image.clone(), safe_image, video], 0.5));
+    }
+
+    #[test]
+    fn evaluate_needs_images_unless_the_text_hides_the_post_for_everyone() {
+        let filter = Filter::compile(RuleSet::parse("[politics]\nvote\n").unwrap());
+        let mut harmless = Post::titled("a", "a cat", vec![]);
+        assert_eq!(evaluate(&filter, &mut harmless, 0.5, &[]), Evaluation::NeedsImages);
+        assert_eq!((harmless.unrecoverable, harmless.filter_version), (None, None));
+
+        let mut political = Post::titled("b", "go and vote", vec![]);
+        assert_eq!(evaluate(&filter, &mut political, 0.5, &[]), Evaluation::Evaluated);
+        assert_eq!(political.unrecoverable, Some(true));
+        //A profile which ignores the category still needs the images to judge the post.
+        let mut political = Post::titled("b", "go and vote", vec![]);
+        assert_eq!(evaluate(&filter, &mut political, 0.5, &[profile(&["sport"], &[])]), Evaluation::NeedsImages);
+
+        let mut with_images = Post::titled("c", "a cat", vec![Image::scanned("0", "go and vote", None), Image::scanned("1", "", None)]);
+        assert_eq!(evaluate(&filter, &mut with_images, 0.5, &[]), Evaluation::Evaluated);
+        assert_eq!(with_images.unrecoverable, Some(true));
+        assert_eq!(with_images.images[0].reasons[0].term, "vote");
+    }
+}